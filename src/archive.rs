@@ -0,0 +1,1680 @@
+//! Star Force archive header: a list of `(offset, size)` pairs ending right
+//! before the data of the first subfile. Bit 31 of the size marks a subfile
+//! compressed with LZ10/LZ11, in which case the size is the decompressed one.
+
+use std::{
+    collections::HashMap,
+    error::Error,
+    fmt::{Debug, Display},
+    io::{Cursor, Read, Seek, SeekFrom, Write},
+    path::{Path, PathBuf},
+};
+
+use byteorder::*;
+
+use crate::{
+    glob,
+    graphics::{self, Kind},
+    hook::Hook,
+    lzss,
+    manifest::{Compression, EntryManifest, Manifest},
+    nds, nlz,
+    record::{self, Schema, TableFormat},
+    sprite,
+    text::{self, CharTable},
+    translation::{self, Translations},
+};
+
+use itertools::Itertools;
+
+/// Directory in an unpacked archive holding the original data of the
+/// subfiles converted to other formats, which is needed to convert them back.
+pub const ORIGINAL_DIR: &str = ".original";
+
+/// Directory in an unpacked archive holding the bytes which aren't part of
+/// any subfile, so packing reproduces the layout of the archive.
+pub const LAYOUT_DIR: &str = ".layout";
+
+#[derive(Debug)]
+pub struct SubFile {
+    pub offset: u32,
+    pub size: u32,
+    pub compressed: bool,
+}
+
+pub struct InvalidHeaderError;
+
+impl Debug for InvalidHeaderError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str("InvalidHeaderError")
+    }
+}
+
+impl Display for InvalidHeaderError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str("Invalid archive file header")
+    }
+}
+
+impl Error for InvalidHeaderError {}
+
+/// Reads all header entries, including the trailing entry pointing at the end
+/// of the archive. `file_size` is the size of the whole archive.
+pub fn read_header(inp: &mut impl Read, file_size: usize) -> Result<Vec<SubFile>, Box<dyn Error>> {
+    let mut subfiles = Vec::new();
+    let mut header_end = file_size;
+    let mut pos = 0;
+    while pos < header_end {
+        let offset = inp.read_u32::<LE>().map_err(|_| InvalidHeaderError)?;
+        let size = inp.read_u32::<LE>().map_err(|_| InvalidHeaderError)?;
+        pos += 8;
+        subfiles.push(SubFile {
+            offset,
+            size: size & 0x7FFFFFFF,
+            compressed: (size & 0x80000000) != 0,
+        });
+        header_end = (offset as usize).min(header_end);
+    }
+    if pos != header_end
+        || subfiles.is_empty()
+        || subfiles.iter().any(|x| x.offset as usize > file_size)
+    {
+        return Err(InvalidHeaderError.into());
+    }
+    Ok(subfiles)
+}
+
+#[derive(Debug, Default, Clone)]
+pub struct UnpackOptions {
+    /// The archive has an EOF subfile entry, which isn't written.
+    pub eof: bool,
+    pub ignore_zero: bool,
+    /// Keep compressed subfiles compressed as "name_XXX.bin.lz".
+    pub raw: bool,
+    pub verbose: bool,
+    /// Decode message archives to "name_XXX.txt" with this table.
+    pub table: Option<CharTable>,
+    /// Convert Nitro graphics to "name_XXX.ncgr.png" and the like.
+    pub images: bool,
+    /// Headerless graphics and record table entries to convert, added to
+    /// the manifest.
+    pub entries: Vec<EntryManifest>,
+    /// External converters, after the ones of the manifest.
+    pub hooks: Vec<Hook>,
+}
+
+/// How many bytes automatic compression must save to be used.
+#[derive(Debug, Clone, Copy)]
+pub enum MinSavings {
+    Bytes(usize),
+    Percent(usize),
+}
+
+impl MinSavings {
+    pub fn parse(value: &str) -> Option<Self> {
+        if let Some(percent) = value.strip_suffix('%') {
+            percent.parse().ok().map(Self::Percent)
+        } else {
+            value.parse().ok().map(Self::Bytes)
+        }
+    }
+
+    fn threshold(&self, size: usize) -> usize {
+        let threshold = match self {
+            Self::Bytes(bytes) => *bytes,
+            Self::Percent(percent) => size * percent / 100,
+        };
+        threshold.max(1)
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct PackOptions {
+    /// Write an EOF subfile entry before the end entry.
+    pub eof: bool,
+    /// Compress subfiles without another policy if they get smaller.
+    pub compress: bool,
+    pub ignore_zero: bool,
+    /// Store identical subfiles once, sharing their offset.
+    pub dedupe: bool,
+    /// Alignment of the subfile offsets, over the one of the manifest or the
+    /// template. 1 for none by default.
+    pub align: Option<usize>,
+    /// Byte filling the space left by the alignment, 0xFF by default.
+    pub fill: Option<u8>,
+    /// Archive giving the subfiles missing from the input, and the
+    /// compression and layout of the others. It can be a file inside a ROM.
+    pub template: Option<String>,
+    /// Check that every compressed subfile decompresses back to its input.
+    pub verify: bool,
+    pub verbose: bool,
+    pub slience: bool,
+    pub compress_options: lzss::CompressOptions,
+    /// File name patterns with their policy, the last matching one is used.
+    pub patterns: Vec<(String, Compression)>,
+    pub min_savings: MinSavings,
+    /// Table used to encode ".txt" subfiles back to message archives.
+    pub table: Option<CharTable>,
+    /// Strings replacing the scripts of ".txt" subfiles.
+    pub translations: Option<Translations>,
+    /// Directory the archive part of the translation keys is relative to,
+    /// else it is the path of the input directory.
+    pub text_root: Option<PathBuf>,
+    /// Warn about translated strings longer than this many bytes.
+    pub max_length: Option<usize>,
+    /// Headerless graphics and record table entries, overriding the manifest.
+    pub entries: Vec<EntryManifest>,
+    /// External converters, after the ones of the manifest.
+    pub hooks: Vec<Hook>,
+}
+
+impl Default for PackOptions {
+    fn default() -> Self {
+        Self {
+            eof: false,
+            compress: false,
+            ignore_zero: false,
+            dedupe: false,
+            align: None,
+            fill: None,
+            template: None,
+            verify: cfg!(debug_assertions),
+            verbose: false,
+            slience: false,
+            compress_options: Default::default(),
+            patterns: vec![],
+            min_savings: MinSavings::Bytes(1),
+            table: None,
+            translations: None,
+            text_root: None,
+            max_length: None,
+            entries: vec![],
+            hooks: vec![],
+        }
+    }
+}
+
+impl PackOptions {
+    /// Describes the options affecting the packed data, ignoring the ones
+    /// only changing what gets printed.
+    pub fn fingerprint(&self) -> String {
+        let mut options = self.clone();
+        options.verbose = false;
+        options.slience = false;
+        format!("{:?}", options)
+    }
+}
+
+/// Unpacks every subfile of an archive into the output directory as
+/// "basename_XXX.bin".
+pub fn unpack(
+    data: &[u8],
+    basename: &str,
+    output: &Path,
+    options: &UnpackOptions,
+) -> Result<(), Box<dyn Error>> {
+    let verbose = options.verbose;
+    let mut file = Cursor::new(data);
+    let file_size = data.len();
+    let mut subfiles = read_header(&mut file, file_size)?;
+    if verbose {
+        print_subfiles(&subfiles);
+    }
+    if options.eof && (subfiles.last().expect("").size > 0 || subfiles.last().expect("").compressed)
+    {
+        return Err(InvalidHeaderError.into());
+    }
+    if options.eof {
+        subfiles.pop();
+    }
+    if subfiles.is_empty() {
+        return Err(InvalidHeaderError.into());
+    }
+    let subfile_len = subfiles.len() - 1;
+    let zero_subfile_amounts = subfiles.iter().filter(|x| x.size == 0).count();
+    let max_size = subfiles.iter().map(|x| x.size).max().unwrap_or_default();
+    if verbose {
+        println!("Largest entry size: 0x{:08x}", max_size);
+    }
+    let padding = subfile_len.to_string().len();
+    let to_padded_string = |num: u32| -> String {
+        let num = num.to_string();
+        let mut padding = "0".to_string().repeat(padding - num.len());
+        padding.push_str(num.as_str());
+        padding
+    };
+    let offsets = subfiles.iter().map(|x| x.offset).collect::<Vec<_>>();
+    let header_end = offsets.iter().copied().min().unwrap_or_default() as usize;
+    // Where the data of a subfile must end at the latest.
+    let next_offset = |offset: u32| -> usize {
+        offsets
+            .iter()
+            .copied()
+            .filter(|x| *x > offset)
+            .min()
+            .unwrap_or(file_size as _)
+            .min(file_size as _) as usize
+    };
+    std::fs::create_dir_all(output).map_err(|e| format!("Can't create output directory: {}", e))?;
+    let mut entries = Vec::new();
+    // Offset and end of the data of every subfile.
+    let mut extents = Vec::new();
+    let mut end_offset = None;
+    for (i, subfile) in subfiles.into_iter().enumerate() {
+        if i == subfile_len
+            && subfile.offset as usize <= file_size
+            && subfile.size == 0xFFFF
+            && !subfile.compressed
+        {
+            end_offset = Some(subfile.offset as usize);
+            continue;
+        }
+        if options.ignore_zero && subfile.size == 0 {
+            // The layout still needs it for the bytes following it.
+            extents.push((i, subfile.offset as usize, subfile.offset as usize));
+            println!("Warning: Entry {} is empty, skipped.", i);
+            if i + 1 == subfile_len {
+                println!("Tip: It seems like there is a zero-sized subfile at the end of the archive, maybe it is a end-of-file mark.");
+                println!("     If you think that so, you can use -eof option to skip writing this subfile.");
+            }
+            continue;
+        }
+        file.seek(SeekFrom::Start(subfile.offset as _))?;
+        let data = if subfile.compressed && options.raw {
+            // The stored size is unknown, so read up to the next entry and
+            // keep what the decoder consumed plus its 4 byte padding.
+            let end = next_offset(subfile.offset);
+            let stored = &data[subfile.offset as usize..end];
+            let mut cursor = Cursor::new(stored);
+            nlz::decompress(&mut cursor)
+                .map_err(|e| format!("Can't decompress entry {}: {}", i, e))?;
+            let stored_size = ((cursor.position() as usize + 3) & !3).min(stored.len());
+            if verbose {
+                println!(
+                    "Unpacking compressed entry {} with stored size {}",
+                    i, stored_size
+                );
+            }
+            extents.push((
+                i,
+                subfile.offset as usize,
+                subfile.offset as usize + stored_size,
+            ));
+            stored[..stored_size].to_vec()
+        } else if subfile.compressed {
+            if verbose {
+                println!("Decompressing entry {}", i);
+            }
+            let decompressed = nlz::decompress(&mut file)
+                .map_err(|e| format!("Can't decompress entry {}: {}", i, e))?;
+            let offset = subfile.offset as usize;
+            let end = offset + ((file.position() as usize - offset + 3) & !3);
+            extents.push((i, offset, end.min(next_offset(subfile.offset))));
+            decompressed
+        } else {
+            if verbose {
+                println!("Unpacking entry {} with size {}", i, subfile.size);
+            }
+            let offset = subfile.offset as usize;
+            extents.push((i, offset, offset + subfile.size as usize));
+            data.get(subfile.offset as usize..subfile.offset as usize + subfile.size as usize)
+                .ok_or_else(|| format!("Entry {} is out of the archive", i))?
+                .to_vec()
+        };
+        entries.push((i, options.raw && subfile.compressed, data));
+    }
+    let layout = Layout::capture(data, header_end, &extents, end_offset);
+    if verbose {
+        layout.print();
+    }
+    layout
+        .save(output, |i| {
+            format!("{}_{}", basename, to_padded_string(i as _))
+        })
+        .map_err(|e| format!("Can't write layout: {}", e))?;
+    let mut manifest = Manifest::load(output).map_err(|e| format!("Can't read manifest: {}", e))?;
+    let had_layout =
+        manifest.align.is_some() || manifest.entries.iter().any(|x| x.shared.is_some());
+    if !options.entries.is_empty()
+        || had_layout
+        || !layout.shared.is_empty()
+        || layout.align.is_some()
+    {
+        // Keep the declarations and the layout for packing.
+        manifest.align = layout.align.map(|x| x.0);
+        manifest.fill = layout.align.map(|x| x.1);
+        manifest.entries.iter_mut().for_each(|x| x.shared = None);
+        manifest.merge(&options.entries);
+        manifest.merge(
+            &layout
+                .shared
+                .iter()
+                .map(|&(index, shared)| EntryManifest {
+                    index,
+                    shared: Some(shared),
+                    ..Default::default()
+                })
+                .collect::<Vec<_>>(),
+        );
+        manifest
+            .save(output)
+            .map_err(|e| format!("Can't write manifest: {}", e))?;
+    }
+    let kinds: Vec<(usize, Kind)> = entries
+        .iter()
+        .filter(|x| !x.1)
+        .filter_map(|(i, _, data)| {
+            let declared = manifest.entry(*i).and_then(|x| x.format);
+            let kind = match declared {
+                Some(format) => Kind::of_format(format),
+                None => Kind::detect(data).filter(|_| options.images)?,
+            };
+            Some((*i, kind))
+        })
+        .collect();
+    let data_of = |index: usize| {
+        entries
+            .iter()
+            .find(|x| x.0 == index)
+            .map(|x| x.2.as_slice())
+    };
+    let reference = |index: usize, kind: Kind, explicit: Option<usize>| {
+        let candidates = kinds
+            .iter()
+            .filter(|x| x.1 == kind || (kind.is_palette() && x.1.is_palette()))
+            .map(|x| x.0);
+        explicit
+            .or_else(|| graphics::nearest(index, candidates))
+            .and_then(data_of)
+    };
+    let write_original = |name: &str, data: &[u8]| -> Result<(), Box<dyn Error>> {
+        let originals = output.join(ORIGINAL_DIR);
+        std::fs::create_dir_all(&originals)
+            .and_then(|_| std::fs::write(originals.join(format!("{}.bin", name)), data))
+            .map_err(|e| format!("Can't write original subfile: {}", e).into())
+    };
+    for (i, stored, data) in &entries {
+        let name = format!("{}_{}", basename, to_padded_string(*i as _));
+        let mut extension = if *stored { "bin.lz" } else { "bin" };
+        let mut converted = None;
+        let hook = manifest
+            .hooks
+            .iter()
+            .chain(&options.hooks)
+            .filter(|_| !stored)
+            .find(|x| x.unpack.is_some() && x.matches(&format!("{}.{}", name, extension), data));
+        if let Some(hook) = hook {
+            let path = output.join(format!("{}.{}", name, hook.extension));
+            hook.unpack(*i, data, &path)
+                .map_err(|e| format!("Can't convert entry {}: {}", i, e))?;
+            write_original(&name, data)?;
+            continue;
+        }
+        let schema = manifest
+            .entry(*i)
+            .and_then(|x| x.schema.as_ref())
+            .filter(|_| !stored);
+        if let Some(schema) = schema {
+            let schema = Schema::load(&output.join(schema))
+                .map_err(|e| format!("Can't read schema of entry {}: {}", i, e))?;
+            // Only keep tables which convert back to the same data.
+            let result = record::export(&schema, data).and_then(|table| {
+                if record::import(&schema, &table, data)? != *data {
+                    return Err("Table doesn't convert back to the same data".into());
+                }
+                Ok(table)
+            });
+            match result {
+                Ok(table) => {
+                    write_original(&name, data)?;
+                    extension = schema.format.extension();
+                    converted = Some(table);
+                }
+                Err(err) if verbose => println!("Entry {} isn't converted to a table: {}", i, err),
+                Err(_) => {}
+            }
+        } else if let Some((_, kind)) = kinds.iter().find(|x| x.0 == *i) {
+            let entry = manifest.entry(*i);
+            let palette = reference(*i, Kind::Palette, entry.and_then(|x| x.palette));
+            let tiles = reference(*i, Kind::Tiles, entry.and_then(|x| x.tiles));
+            // Only keep images which convert back to the same data.
+            let width = entry.and_then(|x| x.width);
+            let result = graphics::export(*kind, data, palette, tiles, width).and_then(|png| {
+                if graphics::import(*kind, &png, data, palette, tiles)? != *data {
+                    return Err("Image doesn't convert back to the same data".into());
+                }
+                Ok(png)
+            });
+            match result {
+                Ok(png) => {
+                    write_original(&name, data)?;
+                    extension = kind.extension();
+                    converted = Some(png);
+                    let cells = reference(*i, Kind::Cells, entry.and_then(|x| x.cells));
+                    let renders = match kind {
+                        Kind::Cells => sprite::render_cells(data, tiles, palette),
+                        Kind::Animation => sprite::render_animations(data, cells, tiles, palette),
+                        _ => Ok(vec![]),
+                    };
+                    match renders {
+                        Ok(renders) => write_renders(output, &name, *kind, renders)?,
+                        Err(err) if verbose => println!("Entry {} isn't rendered: {}", i, err),
+                        Err(_) => {}
+                    }
+                }
+                Err(err) if verbose => println!(
+                    "Entry {} isn't converted to {}: {}",
+                    i,
+                    kind.extension(),
+                    err
+                ),
+                Err(_) => {}
+            }
+        } else if let Some(table) = options
+            .table
+            .as_ref()
+            .filter(|_| !stored && !data.is_empty())
+        {
+            match text::decode_archive(data, table) {
+                Ok(text) => {
+                    extension = "txt";
+                    converted = Some(text.into_bytes());
+                }
+                Err(err) if verbose => println!("Entry {} isn't decoded as text: {}", i, err),
+                Err(_) => {}
+            }
+        }
+        let output_name = format!("{}.{}", name, extension);
+        std::fs::write(
+            output.join(output_name),
+            converted.as_deref().unwrap_or(data),
+        )
+        .map_err(|e| format!("Can't write subfile: {}", e))?;
+    }
+    if zero_subfile_amounts > 0 {
+        println!(
+            "Tip: There {} zero-sized subfile{} in the archive.",
+            if zero_subfile_amounts > 1 {
+                "are some"
+            } else {
+                "is a"
+            },
+            if zero_subfile_amounts > 1 { "s" } else { "" }
+        );
+        println!("     If you are using tools like TextPet, it may come to an error when reading text archive.");
+        println!("     To ignore zero file writing, use --ignore-zero option to skip writing zero files.");
+    }
+    Ok(())
+}
+
+/// Lists the subfiles in a directory with their index, sorted by index.
+/// Missing indices are filled with an empty path when `ignore_zero` is set,
+/// and two files with the same index are an error.
+pub fn collect_subfiles(
+    input: &Path,
+    ignore_zero: bool,
+    slience: bool,
+) -> Result<Vec<(usize, PathBuf)>, Box<dyn Error>> {
+    let mut input_dir =
+        std::fs::read_dir(input).map_err(|e| format!("Can't read input directory: {}", e))?;
+    let mut files = Vec::new();
+    while let Some(Ok(entry)) = input_dir.next() {
+        if entry.path().is_file() {
+            let file_name = entry.file_name();
+            if let Some(index) = parse_subfile_index(&file_name.to_string_lossy()) {
+                files.push((index, entry.path()))
+            }
+        }
+    }
+    files.sort();
+    if let Some(pair) = files.windows(2).find(|x| x[0].0 == x[1].0) {
+        let name = |x: &PathBuf| {
+            x.file_name()
+                .unwrap_or_default()
+                .to_string_lossy()
+                .into_owned()
+        };
+        return Err(format!(
+            "Sub files {} and {} have the same index {}",
+            name(&pair[0].1),
+            name(&pair[1].1),
+            pair[0].0
+        )
+        .into());
+    }
+    if !files.is_empty() {
+        let max_index = files.last().map(|x| x.0).unwrap();
+        let mut i = 0;
+        if files.len() == max_index + 1 || ignore_zero {
+            while files.len() < max_index + 1 {
+                let file = &files[i];
+                if i != file.0 {
+                    files.insert(i, (i, PathBuf::default()));
+                    if !slience {
+                        println!("Warning: Missing file {}, using zero size file", i);
+                    }
+                }
+                i += 1;
+            }
+        } else {
+            println!(
+                "Incorrect subfile amount, expecting {} files but got {} subfiles",
+                max_index + 1,
+                files.len()
+            );
+            println!("List of missing sub files:");
+            for i in 0..max_index + 1 {
+                if !files.iter().any(|x| x.0 == i) {
+                    println!("Missing sub file {}", i);
+                }
+            }
+            println!("Tip: If it's not an error, use --ignore-zero to ignore missing files and write zero size sub file.");
+            return Err("Incorrect subfile amount".into());
+        }
+    }
+    Ok(files)
+}
+
+/// Packs the subfiles in the input directory into an archive.
+pub fn pack(input: &Path, options: &PackOptions) -> Result<Vec<u8>, Box<dyn Error>> {
+    let verbose = options.verbose;
+    let mut template = options
+        .template
+        .as_deref()
+        .map(Template::read)
+        .transpose()
+        .map_err(|e| format!("Can't read template: {}", e))?;
+    let files = match &template {
+        // Only the replaced subfiles are needed, the others are copied from
+        // the template.
+        Some(template) => {
+            let found = collect_subfiles(input, true, true)?;
+            let count = found.last().map_or(0, |x| x.0 + 1);
+            let mut files = (0..count.max(template.entries.len()))
+                .map(|i| (i, PathBuf::default()))
+                .collect::<Vec<_>>();
+            for (i, path) in found {
+                files[i].1 = path;
+            }
+            files
+        }
+        None => collect_subfiles(input, options.ignore_zero, options.slience)?,
+    };
+    // The EOF entry of a template is one of its entries.
+    let eof = options.eof && template.is_none();
+    let end_entry = template.as_ref().is_none_or(|x| x.end_entry);
+    let header_size = (files.len() + eof as usize + end_entry as usize) * 8;
+    let layout = match template.as_mut() {
+        Some(template) => std::mem::take(&mut template.layout),
+        None => Layout::load(input).map_err(|e| format!("Can't read layout: {}", e))?,
+    };
+    let mut offset = header_size as u32;
+    let mut file = Cursor::new(Vec::new());
+    for _ in 0..header_size / 8 {
+        file.write_u64::<LE>(0)?;
+    }
+    let mut manifest = Manifest::load(input).map_err(|e| format!("Can't read manifest: {}", e))?;
+    manifest.merge(&options.entries);
+    let template_entry = |index: usize| template.as_ref().and_then(|x| x.entries.get(index));
+    // The suffix on the file itself wins over the manifest, which wins over
+    // the template and then the patterns given on the command line.
+    let compression_of = |index: usize, path: &Path| -> Compression {
+        let name = path.file_name().unwrap_or_default().to_string_lossy();
+        if name.ends_with(".nocompress") {
+            Compression::Never
+        } else if name.ends_with(".compress") {
+            Compression::Always
+        } else if let Some(policy) = manifest.entry(index).and_then(|x| x.compression) {
+            policy
+        } else if let Some(entry) = template_entry(index) {
+            if entry.compressed {
+                Compression::Always
+            } else {
+                Compression::Never
+            }
+        } else if let Some((_, policy)) = options
+            .patterns
+            .iter()
+            .rev()
+            .find(|(pattern, _)| glob::matches(pattern, &name))
+        {
+            *policy
+        } else if options.compress {
+            Compression::Auto
+        } else {
+            Compression::Never
+        }
+    };
+    let verified =
+        |index: usize, original: &[u8], compressed: Vec<u8>| -> Result<Vec<u8>, Box<dyn Error>> {
+            if options.verify {
+                if verbose {
+                    println!("Verifying entry {}", index);
+                }
+                check_compressed(index, &compressed, original)?;
+            }
+            Ok(compressed)
+        };
+    let options_of = |index: usize| -> lzss::CompressOptions {
+        let mut compress_options = options.compress_options;
+        if let Some(vram_safe) = manifest.entry(index).and_then(|x| x.vram_safe) {
+            compress_options.vram_safe = vram_safe;
+        }
+        if let Some(codec) = template_entry(index).and_then(|x| x.codec()) {
+            compress_options.codec = codec;
+        }
+        compress_options
+    };
+    let subfiles = Subfiles::new(input, &files, &manifest, options);
+    let mut entries = Vec::with_capacity(files.len());
+    for (i, entry) in files.iter().cloned() {
+        if entry == PathBuf::default() {
+            // Subfiles missing from the input are copied from the template.
+            entries.push(match template_entry(i) {
+                Some(x) => (x.compressed, x.data.clone(), x.size as usize),
+                None => (false, vec![], 0),
+            });
+            continue;
+        }
+        let buf = subfiles.read(i)?;
+        let uncompressed_size = buf.len();
+        let packed = if entry.extension().is_some_and(|x| x == "lz") {
+            let uncompressed_size = nlz::decompressed_size(&mut Cursor::new(&buf))
+                .map_err(|e| format!("Invalid pre-compressed subfile {}: {}", i, e))?;
+            (true, buf, uncompressed_size)
+        } else {
+            match compression_of(i, &entry) {
+                Compression::Never => (false, buf, uncompressed_size),
+                Compression::Always => {
+                    let compressed_data = lzss::compress_with(&buf, options_of(i))?;
+                    (true, verified(i, &buf, compressed_data)?, uncompressed_size)
+                }
+                Compression::Auto => match lzss::compress_with(&buf, options_of(i)) {
+                    Ok(compressed_data)
+                        if compressed_data.len()
+                            + options.min_savings.threshold(uncompressed_size)
+                            <= uncompressed_size =>
+                    {
+                        (true, verified(i, &buf, compressed_data)?, uncompressed_size)
+                    }
+                    _ => (false, buf, uncompressed_size),
+                },
+            }
+        };
+        entries.push(packed);
+    }
+    let files = entries;
+    // The data is written in the order of the template, else of the header.
+    let order = match &template {
+        Some(template) => (0..files.len())
+            .sorted_by_key(|&i| (template.entries.get(i).map_or(u32::MAX, |x| x.offset), i))
+            .collect(),
+        None => (0..files.len()).collect::<Vec<_>>(),
+    };
+    // Identical stored data is written once and shared by the entries when
+    // deduplicating, the header allows several entries at the same offset.
+    let mut offsets = vec![0; files.len()];
+    let mut placed = vec![false; files.len()];
+    // The padding before the data of each subfile, none if it isn't written.
+    let mut written = vec![None; files.len()];
+    let mut shared = HashMap::new();
+    // An alignment given on the command line or in the project wins over the
+    // one found by unpacking.
+    let template_align = template.as_ref().and_then(|x| x.align);
+    let align = options
+        .align
+        .or(manifest.align)
+        .or(template_align.map(|x| x.0))
+        .unwrap_or(1)
+        .max(1);
+    let fill = options
+        .fill
+        .or(manifest.fill)
+        .or(template_align.map(|x| x.1))
+        .unwrap_or(0xFF);
+    let mut started = false;
+    let (mut duplicates, mut saved) = (0, 0);
+    for &i in &order {
+        let data = &files[i].1;
+        let gap = layout.gap(i);
+        placed[i] = true;
+        // Subfiles sharing their data in the unpacked archive keep sharing
+        // it while it still holds theirs.
+        let owner = manifest
+            .entry(i)
+            .and_then(|x| x.shared)
+            .or_else(|| layout.shared.iter().find(|x| x.0 == i).map(|x| x.1));
+        if let Some(owner) = owner.filter(|x| placed.get(*x) == Some(&true) && *x != i) {
+            if files[owner].1.starts_with(data) {
+                offsets[i] = offsets[owner];
+                continue;
+            }
+            if verbose {
+                println!("Entry {} no longer shares the data of entry {}", i, owner);
+            }
+        }
+        if options.dedupe && !data.is_empty() && gap.is_empty() {
+            if let Some(&(first, shared_offset)) = shared.get(data.as_slice()) {
+                if verbose {
+                    println!("Entry {} has the same data as entry {}", i, first);
+                }
+                offsets[i] = shared_offset;
+                duplicates += 1;
+                saved += data.len();
+                continue;
+            }
+        }
+        // The first subfile with data isn't aligned, and empty ones have no
+        // data to align.
+        let padding = if started && !data.is_empty() {
+            (align - offset as usize % align) % align
+        } else {
+            0
+        };
+        offset += padding as u32;
+        if options.dedupe && !data.is_empty() && gap.is_empty() {
+            shared.insert(data.as_slice(), (i, offset));
+        }
+        offsets[i] = offset;
+        written[i] = Some(padding);
+        started |= !data.is_empty();
+        offset += (data.len() + gap.len()) as u32;
+    }
+    if duplicates > 0 && !options.slience {
+        println!(
+            "Shared the data of {} duplicate entr{}, saving {} bytes.",
+            duplicates,
+            if duplicates == 1 { "y" } else { "ies" },
+            saved
+        );
+    }
+    let file_size = offset as usize;
+    file.seek(SeekFrom::Start(0))?;
+    for ((compressed, data, uncompressed_size), offset) in files.iter().zip(&offsets) {
+        if verbose {
+            println!(
+                "Subfile {} bytes -> {} bytes",
+                uncompressed_size,
+                data.len()
+            );
+        }
+        file.write_u32::<LE>(*offset)?;
+        file.write_u32::<LE>(
+            (*uncompressed_size as u32 & 0x7FFFFFFF) | if *compressed { 0x80000000 } else { 0 },
+        )?;
+    }
+    if eof {
+        file.write_u32::<LE>(file_size as _)?;
+        file.write_u32::<LE>(0)?;
+    }
+    if end_entry {
+        file.write_u32::<LE>(file_size as _)?;
+        file.write_u32::<LE>(0xFFFF)?;
+    }
+    for &i in &order {
+        if let Some(padding) = written[i] {
+            file.write_all(&vec![fill; padding])?;
+            file.write_all(&files[i].1)?;
+            file.write_all(layout.gap(i))?;
+        }
+    }
+    file.write_all(&layout.trailer)?;
+    Ok(file.into_inner())
+}
+
+/// An archive packed again with some subfiles replaced.
+struct Template {
+    entries: Vec<TemplateEntry>,
+    /// Whether the header ends with the 0xFFFF end entry.
+    end_entry: bool,
+    layout: Layout,
+    align: Option<(usize, u8)>,
+}
+
+struct TemplateEntry {
+    offset: u32,
+    /// The size in the header, decompressed for compressed subfiles.
+    size: u32,
+    compressed: bool,
+    /// The data as stored, compressed data with its padding.
+    data: Vec<u8>,
+}
+
+impl TemplateEntry {
+    /// The format the subfile is compressed with.
+    fn codec(&self) -> Option<lzss::Codec> {
+        match self.data.first().filter(|_| self.compressed) {
+            Some(0x10) => Some(lzss::Codec::LZ10),
+            Some(0x11) => Some(lzss::Codec::LZ11),
+            _ => None,
+        }
+    }
+}
+
+impl Template {
+    fn read(path: &str) -> Result<Self, Box<dyn Error>> {
+        let data = nds::read_archive(path)?;
+        let file_size = data.len();
+        let subfiles = read_header(&mut Cursor::new(&data), file_size)?;
+        let end_entry = subfiles
+            .last()
+            .is_some_and(|x| x.size == 0xFFFF && !x.compressed);
+        let count = subfiles.len() - end_entry as usize;
+        let header_end = subfiles.iter().map(|x| x.offset).min().unwrap_or_default() as usize;
+        let next_offset = |offset: u32| -> usize {
+            subfiles
+                .iter()
+                .map(|x| x.offset)
+                .filter(|x| *x > offset)
+                .min()
+                .map_or(file_size, |x| x as usize)
+                .min(file_size)
+        };
+        let mut entries = Vec::with_capacity(count);
+        let mut extents = Vec::with_capacity(count);
+        for (i, subfile) in subfiles[..count].iter().enumerate() {
+            let offset = subfile.offset as usize;
+            let end = if subfile.compressed {
+                let stored = &data[offset..next_offset(subfile.offset).max(offset)];
+                let mut cursor = Cursor::new(stored);
+                nlz::decompress(&mut cursor)
+                    .map_err(|e| format!("Can't decompress entry {}: {}", i, e))?;
+                offset + ((cursor.position() as usize + 3) & !3).min(stored.len())
+            } else {
+                offset + subfile.size as usize
+            };
+            let stored = data
+                .get(offset..end)
+                .ok_or_else(|| format!("Entry {} is out of the archive", i))?;
+            extents.push((i, offset, end));
+            entries.push(TemplateEntry {
+                offset: subfile.offset,
+                size: subfile.size,
+                compressed: subfile.compressed,
+                data: stored.to_vec(),
+            });
+        }
+        let end_offset = end_entry.then(|| subfiles[count].offset as usize);
+        let layout = Layout::capture(&data, header_end, &extents, end_offset);
+        Ok(Self {
+            entries,
+            end_entry,
+            align: layout.align,
+            layout,
+        })
+    }
+}
+
+/// The bytes of an archive outside of its subfiles, and the subfiles sharing
+/// the data of another one, which packing the subfiles back to back loses.
+#[derive(Debug, Default)]
+struct Layout {
+    /// Bytes following the data of a subfile, before the next one.
+    gaps: Vec<(usize, Vec<u8>)>,
+    /// Bytes after the offset of the end entry.
+    trailer: Vec<u8>,
+    /// Subfiles at the offset of an earlier one, with that one.
+    shared: Vec<(usize, usize)>,
+    /// Alignment of the subfile offsets and the byte filling the space left.
+    align: Option<(usize, u8)>,
+}
+
+impl Layout {
+    const TRAILER: &'static str = "trailer.bin";
+
+    /// Finds the bytes around the subfiles given as `(index, offset, end)`.
+    /// `end_offset` is the offset of the end entry, if there is one.
+    fn capture(
+        data: &[u8],
+        header_end: usize,
+        extents: &[(usize, usize, usize)],
+        end_offset: Option<usize>,
+    ) -> Self {
+        let mut layout = Self::default();
+        let mut owners: Vec<(usize, usize, usize)> = vec![];
+        for &(index, offset, end) in extents {
+            match owners.iter().find(|x| x.1 == offset) {
+                Some(owner) => layout.shared.push((index, owner.0)),
+                // Empty subfiles are placed right after the previous one.
+                None if end > offset => owners.push((index, offset, end)),
+                None => {}
+            }
+        }
+        owners.sort_by_key(|x| x.1);
+        let data_end = owners.iter().map(|x| x.2).max().unwrap_or(header_end);
+        let end_offset = end_offset.unwrap_or(data_end).max(data_end).min(data.len());
+        let first = owners.first().map_or(end_offset, |x| x.1);
+        // The header ends at the lowest offset, so bytes before the first
+        // data follow the empty subfiles at that offset.
+        let leading = extents
+            .iter()
+            .filter(|x| x.1 == header_end)
+            .max_by_key(|x| x.0);
+        if let Some(&(index, ..)) = leading.filter(|_| header_end < first) {
+            layout.gaps.push((index, data[header_end..first].to_vec()));
+        }
+        layout.align = Self::detect_alignment(data, &owners);
+        for (i, &(index, _, end)) in owners.iter().enumerate() {
+            let next = owners.get(i + 1).map_or(end_offset, |x| x.1);
+            if end >= next {
+                continue;
+            }
+            // Padding is written again by the alignment, but the end of the
+            // last subfile isn't aligned.
+            let gap = &data[end..next];
+            let padding = layout.align.is_some_and(|(align, fill)| {
+                i + 1 < owners.len()
+                    && next == end.next_multiple_of(align)
+                    && gap.iter().all(|x| *x == fill)
+            });
+            if !padding {
+                layout.gaps.push((index, gap.to_vec()));
+            }
+        }
+        layout.trailer = data[end_offset..].to_vec();
+        layout
+    }
+
+    /// Finds the largest alignment, up to 2048 bytes, of the subfiles after
+    /// the first one, which some padding between them is needed for.
+    fn detect_alignment(data: &[u8], owners: &[(usize, usize, usize)]) -> Option<(usize, u8)> {
+        (1..=11).rev().map(|x| 1 << x).find_map(|align| {
+            if owners.iter().skip(1).any(|x| x.1 % align != 0) {
+                return None;
+            }
+            owners.windows(2).find_map(|pair| {
+                let (end, next) = (pair[0].2, pair[1].1);
+                let gap = &data[end.min(next)..next];
+                let fill = *gap.first()?;
+                (next == end.next_multiple_of(align) && gap.iter().all(|x| *x == fill))
+                    .then_some((align, fill))
+            })
+        })
+    }
+
+    fn print(&self) {
+        if let Some((align, fill)) = self.align {
+            println!("Entries aligned to {} bytes with 0x{:02X}", align, fill);
+        }
+        for (index, gap) in &self.gaps {
+            println!("{} bytes after entry {}", gap.len(), index);
+        }
+        if !self.trailer.is_empty() {
+            println!("{} bytes after the end entry", self.trailer.len());
+        }
+        for (index, shared) in &self.shared {
+            println!("Entry {} shares the data of entry {}", index, shared);
+        }
+    }
+
+    /// Writes the bytes to the layout directory, replacing the previous
+    /// ones. Gaps are named like the subfile they follow, with ".gap".
+    fn save(&self, dir: &Path, name_of: impl Fn(usize) -> String) -> Result<(), Box<dyn Error>> {
+        let dir = dir.join(LAYOUT_DIR);
+        if dir.is_dir() {
+            std::fs::remove_dir_all(&dir)?;
+        }
+        if self.gaps.is_empty() && self.trailer.is_empty() {
+            return Ok(());
+        }
+        std::fs::create_dir_all(&dir)?;
+        for (index, gap) in &self.gaps {
+            std::fs::write(dir.join(format!("{}.gap", name_of(*index))), gap)?;
+        }
+        if !self.trailer.is_empty() {
+            std::fs::write(dir.join(Self::TRAILER), &self.trailer)?;
+        }
+        Ok(())
+    }
+
+    /// Reads the bytes of the layout directory, the subfiles sharing data
+    /// are in the manifest.
+    fn load(dir: &Path) -> Result<Self, Box<dyn Error>> {
+        let dir = dir.join(LAYOUT_DIR);
+        let mut layout = Self::default();
+        if !dir.is_dir() {
+            return Ok(layout);
+        }
+        for entry in std::fs::read_dir(&dir)? {
+            let path = entry?.path();
+            let name = path.file_name().unwrap_or_default().to_string_lossy();
+            if name == Self::TRAILER {
+                layout.trailer = std::fs::read(&path)?;
+            } else if let Some(index) =
+                parse_subfile_index(&name).filter(|_| name.ends_with(".gap"))
+            {
+                layout.gaps.push((index, std::fs::read(&path)?));
+            }
+        }
+        Ok(layout)
+    }
+
+    fn gap(&self, index: usize) -> &[u8] {
+        self.gaps
+            .iter()
+            .find(|x| x.0 == index)
+            .map_or(&[], |x| x.1.as_slice())
+    }
+}
+
+/// Writes the PNGs rendered from cells or animations to a directory named
+/// like their subfile, which packing skips.
+fn write_renders(
+    output: &Path,
+    name: &str,
+    kind: Kind,
+    renders: sprite::Renders,
+) -> Result<(), Box<dyn Error>> {
+    if renders.is_empty() {
+        return Ok(());
+    }
+    let extension = kind.extension().trim_end_matches(".json");
+    let dir = output.join(format!("{}.{}", name, extension));
+    std::fs::create_dir_all(&dir)
+        .and_then(|_| {
+            renders
+                .into_iter()
+                .try_for_each(|(file, png)| std::fs::write(dir.join(file), png))
+        })
+        .map_err(|e| format!("Can't write rendered frames: {}", e).into())
+}
+
+/// The subfiles of an unpacked archive, read back to their stored data.
+struct Subfiles<'a> {
+    dir: &'a Path,
+    files: &'a [(usize, PathBuf)],
+    manifest: &'a Manifest,
+    options: &'a PackOptions,
+    /// Graphics entries, to find the ones referenced by others.
+    kinds: Vec<(usize, Kind)>,
+}
+
+impl<'a> Subfiles<'a> {
+    fn new(
+        dir: &'a Path,
+        files: &'a [(usize, PathBuf)],
+        manifest: &'a Manifest,
+        options: &'a PackOptions,
+    ) -> Self {
+        let kinds = files
+            .iter()
+            .filter(|x| x.1 != PathBuf::default())
+            .filter_map(|(i, path)| {
+                let declared = manifest.entry(*i).and_then(|x| x.format);
+                let kind = Kind::of_path(path).or_else(|| {
+                    if content_extension(path).as_deref() != Some("bin") {
+                        return None;
+                    }
+                    if let Some(format) = declared {
+                        return Some(Kind::of_format(format));
+                    }
+                    let mut magic = [0; 4];
+                    let mut file = std::fs::File::open(path).ok()?;
+                    file.read_exact(&mut magic).ok()?;
+                    Kind::detect(&magic)
+                })?;
+                Some((*i, kind))
+            })
+            .collect();
+        Self {
+            dir,
+            files,
+            manifest,
+            options,
+            kinds,
+        }
+    }
+
+    fn path(&self, index: usize) -> Option<&Path> {
+        self.files
+            .iter()
+            .find(|x| x.0 == index && x.1 != PathBuf::default())
+            .map(|x| x.1.as_path())
+    }
+
+    /// Reads a subfile, converting edited formats back to the stored data.
+    fn read(&self, index: usize) -> Result<Vec<u8>, Box<dyn Error>> {
+        let path = self
+            .path(index)
+            .ok_or_else(|| format!("Missing subfile {}", index))?;
+        if let Some(hook) = self.hook(index, path) {
+            return Ok(hook
+                .pack(index, path)
+                .map_err(|e| format!("Can't convert entry {}: {}", index, e))?);
+        }
+        let data = std::fs::read(path).map_err(|e| format!("Can't read subfile: {}", e))?;
+        if let Some(kind) = Kind::of_path(path) {
+            let original = self.original(index)?;
+            let references = |original: bool| -> Result<_, Box<dyn Error>> {
+                let entry = self.manifest.entry(index);
+                let palette = match kind {
+                    Kind::Palette | Kind::RawPalette | Kind::Cells | Kind::Animation => None,
+                    _ => self.reference(
+                        index,
+                        Kind::Palette,
+                        entry.and_then(|x| x.palette),
+                        original,
+                    )?,
+                };
+                let tiles = match kind {
+                    Kind::Screen => {
+                        self.reference(index, Kind::Tiles, entry.and_then(|x| x.tiles), original)?
+                    }
+                    _ => None,
+                };
+                Ok((palette, tiles))
+            };
+            // An image left as it was exported keeps its data, even if the
+            // entries it uses were edited.
+            let (unpacked_palette, tiles) = references(true)?;
+            let width = self.manifest.entry(index).and_then(|x| x.width);
+            if graphics::export(
+                kind,
+                &original,
+                unpacked_palette.as_deref(),
+                tiles.as_deref(),
+                width,
+            )
+            .is_ok_and(|x| x == data)
+            {
+                return Ok(original);
+            }
+            let (palette, tiles) = references(false)?;
+            // An image still drawn with the palette as it was unpacked keeps
+            // its indices, even if that palette was edited too.
+            let palette = match unpacked_palette {
+                Some(x) if graphics::uses_palette(&data, &x) => Some(x),
+                _ => palette,
+            };
+            return Ok(graphics::import(
+                kind,
+                &data,
+                &original,
+                palette.as_deref(),
+                tiles.as_deref(),
+            )
+            .map_err(|e| format!("Can't convert entry {}: {}", index, e))?);
+        }
+        match content_extension(path).as_deref() {
+            Some(extension @ ("csv" | "json")) => {
+                let schema = self
+                    .manifest
+                    .entry(index)
+                    .and_then(|x| x.schema.as_ref())
+                    .ok_or_else(|| {
+                        format!(
+                            "Entry {} is a {} table, a schema is needed in the manifest",
+                            index,
+                            extension.to_ascii_uppercase()
+                        )
+                    })?;
+                let mut schema = Schema::load(&self.dir.join(schema))
+                    .map_err(|e| format!("Can't read schema of entry {}: {}", index, e))?;
+                // The file tells the format, even if the schema changed since.
+                schema.format = match extension {
+                    "csv" => TableFormat::Csv,
+                    _ => TableFormat::Json,
+                };
+                Ok(record::import(&schema, &data, &self.original(index)?)
+                    .map_err(|e| format!("Can't convert entry {}: {}", index, e))?)
+            }
+            Some("txt") => {
+                let table = self.options.table.as_ref().ok_or_else(|| {
+                    format!(
+                        "Entry {} is a text script, a character table is needed",
+                        index
+                    )
+                })?;
+                let text = String::from_utf8(data)
+                    .map_err(|_| format!("Entry {} isn't valid UTF-8 text", index))?;
+                match &self.options.translations {
+                    Some(translations) => self.encode_translated(index, &text, table, translations),
+                    None => Ok(text::encode_archive(&text, table)
+                        .map_err(|e| format!("Can't encode entry {}: {}", index, e))?),
+                }
+            }
+            _ => Ok(data),
+        }
+    }
+
+    /// Finds the hook converting an editable file back: the first one with
+    /// its extension which applies to the subfile as it was unpacked.
+    fn hook(&self, index: usize, path: &Path) -> Option<&Hook> {
+        let name = path.file_name()?.to_string_lossy();
+        let name = name
+            .strip_suffix(".nocompress")
+            .or_else(|| name.strip_suffix(".compress"))
+            .unwrap_or(&name);
+        let unpacked = format!("{}.bin", name.split('.').next()?);
+        self.manifest
+            .hooks
+            .iter()
+            .chain(&self.options.hooks)
+            .filter(|x| x.pack.is_some())
+            .filter(|x| name.ends_with(&format!(".{}", x.extension)))
+            .find(|x| {
+                let original = match x.magic {
+                    Some(_) => self.original(index).unwrap_or_default(),
+                    None => vec![],
+                };
+                x.matches(&unpacked, &original)
+            })
+    }
+
+    /// Reads the entry of the given kind used by a subfile: the one set in
+    /// the manifest, else the closest one. With `original`, converted entries
+    /// are read as they were unpacked.
+    fn reference(
+        &self,
+        index: usize,
+        kind: Kind,
+        explicit: Option<usize>,
+        original: bool,
+    ) -> Result<Option<Vec<u8>>, Box<dyn Error>> {
+        let candidates = self
+            .kinds
+            .iter()
+            .filter(|x| x.1 == kind || (kind.is_palette() && x.1.is_palette()))
+            .map(|x| x.0);
+        let reference = explicit.or_else(|| graphics::nearest(index, candidates));
+        let converted = |x: usize| self.path(x).and_then(Kind::of_path).is_some();
+        reference
+            .map(|x| {
+                if original && converted(x) {
+                    self.original(x)
+                } else {
+                    self.read(x)
+                }
+            })
+            .transpose()
+    }
+
+    /// Reads the original data kept when the subfile was converted.
+    fn original(&self, index: usize) -> Result<Vec<u8>, Box<dyn Error>> {
+        let dir = self.dir.join(ORIGINAL_DIR);
+        let path = std::fs::read_dir(&dir)
+            .ok()
+            .into_iter()
+            .flatten()
+            .flatten()
+            .map(|x| x.path())
+            .find(|x| {
+                parse_subfile_index(&x.file_name().unwrap_or_default().to_string_lossy())
+                    == Some(index)
+            })
+            .ok_or_else(|| {
+                format!(
+                    "Missing original data of entry {} in {}",
+                    index, ORIGINAL_DIR
+                )
+            })?;
+        Ok(std::fs::read(path)?)
+    }
+
+    /// Encodes a message archive with its scripts replaced by their
+    /// translation, reporting the ones too long or using characters missing
+    /// from the table.
+    fn encode_translated(
+        &self,
+        index: usize,
+        text: &str,
+        table: &CharTable,
+        translations: &Translations,
+    ) -> Result<Vec<u8>, Box<dyn Error>> {
+        let options = self.options;
+        let archive = translation::archive_key(options.text_root.as_deref(), self.dir);
+        let translated_archive = translations.archive(&archive)?;
+        let warn = |message: String| {
+            if !options.slience {
+                println!("Warning: {}", message);
+            }
+        };
+        let mut scripts = Vec::new();
+        let mut unmapped = 0;
+        for (i, script) in text::parse_scripts(text)?.into_iter().enumerate() {
+            let key = translation::key(&archive, index, i);
+            let translated = translated_archive.and_then(|x| translations.get(x, index, i));
+            let (encoded, missing) = table
+                .encode_lossy(translated.unwrap_or(&script))
+                .map_err(|e| format!("Can't encode {}: {}", key, e))?;
+            if !missing.is_empty() {
+                unmapped += 1;
+                warn(format!(
+                    "{} uses characters missing from the table: {}",
+                    key,
+                    missing.iter().unique().join(" ")
+                ));
+            }
+            if let Some(max_length) = options.max_length {
+                if translated.is_some() && encoded.len() > max_length {
+                    warn(format!(
+                        "{} is {} bytes, longer than {}",
+                        key,
+                        encoded.len(),
+                        max_length
+                    ));
+                }
+            }
+            scripts.push(encoded);
+        }
+        if unmapped > 0 {
+            return Err(format!(
+                "Entry {} has {} string{} with characters missing from the table",
+                index,
+                unmapped,
+                if unmapped == 1 { "" } else { "s" }
+            )
+            .into());
+        }
+        text::write_scripts(&scripts)
+    }
+}
+
+/// The extension telling the format of a subfile, ignoring the compression
+/// policy suffix.
+pub fn content_extension(path: &Path) -> Option<String> {
+    let name = path.file_name()?.to_string_lossy();
+    let name = name
+        .strip_suffix(".nocompress")
+        .or_else(|| name.strip_suffix(".compress"))
+        .unwrap_or(&name);
+    Some(
+        Path::new(name)
+            .extension()?
+            .to_string_lossy()
+            .to_ascii_lowercase(),
+    )
+}
+
+pub fn print_subfiles(subfiles: &[SubFile]) {
+    for (i, subfile) in subfiles.iter().enumerate() {
+        if subfile.compressed {
+            println!(
+                "Entry {} at 0x{:08x}, size 0x{:08x}, compressed",
+                i, subfile.offset, subfile.size
+            );
+        } else {
+            println!(
+                "Entry {} at 0x{:08x}, size 0x{:08x}",
+                i, subfile.offset, subfile.size
+            );
+        }
+    }
+}
+
+/// Parses the subfile number out of "XXX.ext" or "name_XXX.ext".
+pub fn parse_subfile_index(file_name: &str) -> Option<usize> {
+    let start = file_name.rfind('_').map(|x| x + 1).unwrap_or_default();
+    let end = file_name[start..].find('.')? + start;
+    file_name[start..end].parse().ok()
+}
+
+/// Parses a byte as decimal or "0x" prefixed hex.
+pub fn parse_byte(value: &str) -> Option<u8> {
+    match value
+        .strip_prefix("0x")
+        .or_else(|| value.strip_prefix("0X"))
+    {
+        Some(hex) => u8::from_str_radix(hex, 16).ok(),
+        None => value.parse().ok(),
+    }
+}
+
+/// Checks that a compressed stream decodes back to exactly the original data.
+pub fn decompresses_to(compressed: &[u8], original: &[u8]) -> bool {
+    nlz::decompress(&mut Cursor::new(compressed)).is_ok_and(|x| x == original)
+}
+
+/// Fails with the entry index if its compressed data doesn't decode back to
+/// the original data.
+fn check_compressed(
+    index: usize,
+    compressed: &[u8],
+    original: &[u8],
+) -> Result<(), Box<dyn Error>> {
+    if !decompresses_to(compressed, original) {
+        return Err(format!(
+            "Compressed entry {} doesn't decompress back to its input",
+            index
+        )
+        .into());
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{manifest::MANIFEST_NAME, test_util::TempDir};
+
+    /// An empty entry followed by bytes before the data of the next one, a
+    /// byte of padding between the entries with data, and a trailer.
+    fn archive() -> Vec<u8> {
+        let mut archive = vec![];
+        for (offset, size) in [(32u32, 0u32), (36, 3), (40, 4), (44, 0xFFFF)] {
+            archive.extend(offset.to_le_bytes());
+            archive.extend(size.to_le_bytes());
+        }
+        archive.extend([0xDE, 0xAD, 0xBE, 0xEF]);
+        archive.extend(b"ABC\x99WXYZTAIL");
+        archive
+    }
+
+    /// An archive with the end entry and the data of each entry in turn.
+    fn archive_of(entries: &[&[u8]]) -> Vec<u8> {
+        let mut offset = (entries.len() + 1) as u32 * 8;
+        let mut archive = vec![];
+        for data in entries {
+            archive.extend(offset.to_le_bytes());
+            archive.extend((data.len() as u32).to_le_bytes());
+            offset += data.len() as u32;
+        }
+        archive.extend(offset.to_le_bytes());
+        archive.extend(0xFFFFu32.to_le_bytes());
+        archive.extend(entries.concat());
+        archive
+    }
+
+    #[test]
+    fn layout_round_trips() {
+        let dir = TempDir::new();
+        let data = archive();
+        unpack(&data, "arc", dir.path(), &UnpackOptions::default()).unwrap();
+        let layout = dir.path().join(LAYOUT_DIR);
+        let gap = layout.join("arc_0.gap");
+        assert_eq!(std::fs::read(&gap).unwrap(), [0xDE, 0xAD, 0xBE, 0xEF]);
+        assert_eq!(
+            std::fs::read(layout.join(Layout::TRAILER)).unwrap(),
+            b"TAIL"
+        );
+        assert_eq!(pack(dir.path(), &PackOptions::default()).unwrap(), data);
+        // The bytes after the empty entry move the entries after it.
+        std::fs::write(&gap, [0; 6]).unwrap();
+        let packed = pack(dir.path(), &PackOptions::default()).unwrap();
+        assert_eq!(
+            packed[..16],
+            [32, 0, 0, 0, 0, 0, 0, 0, 38, 0, 0, 0, 3, 0, 0, 0]
+        );
+        // The data was aligned to 8 bytes, which the padding keeps.
+        assert_eq!(packed[16..20], 48u32.to_le_bytes());
+        assert_eq!(packed[38..41], *b"ABC");
+        assert_eq!(packed[41..48], [0x99; 7]);
+        assert_eq!(packed[48..], *b"WXYZTAIL");
+    }
+
+    #[test]
+    fn layout_of_skipped_entries_round_trips() {
+        let dir = TempDir::new();
+        let data = archive();
+        let options = UnpackOptions {
+            ignore_zero: true,
+            ..Default::default()
+        };
+        unpack(&data, "arc", dir.path(), &options).unwrap();
+        assert!(!dir.path().join("arc_0.bin").exists());
+        let options = PackOptions {
+            ignore_zero: true,
+            ..Default::default()
+        };
+        assert_eq!(pack(dir.path(), &options).unwrap(), data);
+    }
+
+    #[test]
+    fn explicit_alignment_wins_over_the_manifest() {
+        let dir = TempDir::new();
+        unpack(&archive(), "arc", dir.path(), &UnpackOptions::default()).unwrap();
+        let options = PackOptions {
+            align: Some(16),
+            ..Default::default()
+        };
+        let packed = pack(dir.path(), &options).unwrap();
+        assert_eq!(packed[16..20], 48u32.to_le_bytes());
+        // The fill byte found by unpacking is still used.
+        assert_eq!(packed[39..48], [0x99; 9]);
+    }
+
+    #[test]
+    fn template_fills_missing_entries_by_index() {
+        let dir = TempDir::new();
+        let template = dir.write("c.bin", archive_of(&[b"AAAA", b"BBBB", b"CCCC"]));
+        dir.write("x/x_2.bin", b"ZZZZ");
+        let options = PackOptions {
+            template: Some(template.display().to_string()),
+            ..Default::default()
+        };
+        let input = dir.path().join("x");
+        assert_eq!(
+            pack(&input, &options).unwrap(),
+            archive_of(&[b"AAAA", b"BBBB", b"ZZZZ"])
+        );
+        // A second file for an index can't take the place of a missing one.
+        dir.write("x/x_1.bin", b"YYYY");
+        dir.write("x/x_1.txt", b"text");
+        assert_eq!(
+            pack(&input, &options).unwrap_err().to_string(),
+            "Sub files x_1.bin and x_1.txt have the same index 1"
+        );
+    }
+
+    #[test]
+    fn dedupe_shares_identical_entries() {
+        let dir = TempDir::new();
+        for (i, data) in [b"AAAA", b"BBBB", b"AAAA"].iter().enumerate() {
+            dir.write(&format!("x_{}.bin", i), data);
+        }
+        let options = PackOptions {
+            dedupe: true,
+            ..Default::default()
+        };
+        let packed = pack(dir.path(), &options).unwrap();
+        let offset = |i: usize| LE::read_u32(&packed[i * 8..]);
+        assert_eq!((offset(0), offset(1), offset(2)), (32, 36, 32));
+        assert_eq!(packed[32..], *b"AAAABBBB");
+    }
+
+    #[test]
+    fn edited_copies_stop_sharing() {
+        let dir = TempDir::new();
+        // Entries 0 and 2 share their data.
+        let mut data = vec![];
+        for (offset, size) in [(32u32, 4u32), (36, 4), (32, 4), (40, 0xFFFF)] {
+            data.extend(offset.to_le_bytes());
+            data.extend(size.to_le_bytes());
+        }
+        data.extend(b"AAAABBBB");
+        unpack(&data, "x", dir.path(), &UnpackOptions::default()).unwrap();
+        assert_eq!(pack(dir.path(), &PackOptions::default()).unwrap(), data);
+        dir.write("x_2.bin", b"CCCC");
+        let packed = pack(dir.path(), &PackOptions::default()).unwrap();
+        let offset = |i: usize| LE::read_u32(&packed[i * 8..]);
+        assert_eq!((offset(0), offset(1), offset(2)), (32, 36, 40));
+        assert_eq!(packed[32..], *b"AAAABBBBCCCC");
+    }
+
+    #[test]
+    fn raw_entries_round_trip() {
+        let dir = TempDir::new();
+        let input = b"hello hello hello hello".repeat(4);
+        let compressed = lzss::compress_with(&input, Default::default()).unwrap();
+        let mut data = archive_of(&[&compressed, b"ABCD"]);
+        data[4..8].copy_from_slice(&(input.len() as u32 | 0x80000000).to_le_bytes());
+        let options = UnpackOptions {
+            raw: true,
+            ..Default::default()
+        };
+        unpack(&data, "x", dir.path(), &options).unwrap();
+        assert_eq!(
+            std::fs::read(dir.path().join("x_0.bin.lz")).unwrap(),
+            compressed
+        );
+        assert!(!dir.path().join("x_0.bin").exists());
+        // The stored bytes are written back as they are, keeping the size
+        // field of the compressed entry.
+        assert_eq!(pack(dir.path(), &PackOptions::default()).unwrap(), data);
+    }
+
+    #[test]
+    fn compression_policy_by_suffix_manifest_and_pattern() {
+        let dir = TempDir::new();
+        let text = b"hello hello hello hello".repeat(4);
+        for name in [
+            "x_0.bin.nocompress",
+            "x_1.bin.compress",
+            "x_2.bin",
+            "x_3.bin",
+            "x_4.bin",
+        ] {
+            dir.write(name, &text);
+        }
+        dir.write("x_5.bin", b"ABCD");
+        dir.write(
+            MANIFEST_NAME,
+            b"[[entry]]\nindex = 0\ncompression = \"always\"\n\n\
+              [[entry]]\nindex = 2\ncompression = \"never\"\n",
+        );
+        let mut options = PackOptions {
+            compress: true,
+            patterns: vec![
+                ("x_2*".to_owned(), Compression::Always),
+                ("x_3*".to_owned(), Compression::Always),
+                ("x_3*".to_owned(), Compression::Never),
+            ],
+            ..Default::default()
+        };
+        let compressed = |options: &PackOptions| {
+            let packed = pack(dir.path(), options).unwrap();
+            (0..6)
+                .map(|i| LE::read_u32(&packed[i * 8 + 4..]) & 0x80000000 != 0)
+                .collect::<Vec<_>>()
+        };
+        // The suffix wins over the manifest, which wins over the patterns,
+        // the last matching one of them being used. Automatic compression
+        // leaves the data it doesn't shrink.
+        assert_eq!(
+            compressed(&options),
+            [false, true, false, false, true, false]
+        );
+        options.min_savings = MinSavings::Percent(90);
+        assert_eq!(
+            compressed(&options),
+            [false, true, false, false, false, false]
+        );
+    }
+
+    #[test]
+    fn verify_rejects_mismatched_compression() {
+        let input = b"hello hello hello hello".repeat(4);
+        let compressed = lzss::compress_with(&input, Default::default()).unwrap();
+        assert!(check_compressed(3, &compressed, &input).is_ok());
+        let mut corrupted = compressed.clone();
+        // The first literal, after the header and the first flag byte.
+        corrupted[5] ^= 1;
+        assert_eq!(
+            check_compressed(3, &corrupted, &input)
+                .unwrap_err()
+                .to_string(),
+            "Compressed entry 3 doesn't decompress back to its input"
+        );
+        assert!(check_compressed(3, &compressed[..compressed.len() / 2], &input).is_err());
+    }
+}
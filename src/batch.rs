@@ -0,0 +1,404 @@
+//! Unpacking and packing whole directory trees of archives.
+
+use std::{
+    error::Error,
+    io::Cursor,
+    path::{Path, PathBuf},
+};
+
+use crate::{
+    archive::{self, PackOptions, UnpackOptions},
+    glob,
+    incremental::{self, BuildCache},
+    manifest::Manifest,
+};
+
+/// Extension of the archives whose manifest doesn't give another one.
+const DEFAULT_EXTENSION: &str = "bin";
+
+/// Patterns matched against archive paths relative to the input directory,
+/// like "msg/mess_0001.bin".
+#[derive(Debug, Default, Clone)]
+pub struct Filters {
+    pub include: Vec<String>,
+    pub exclude: Vec<String>,
+}
+
+impl Filters {
+    pub fn accepts(&self, path: &str) -> bool {
+        (self.include.is_empty() || self.include.iter().any(|x| glob::matches(x, path)))
+            && !self.exclude.iter().any(|x| glob::matches(x, path))
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct Summary {
+    pub succeeded: Vec<String>,
+    /// Archives skipped by an incremental build as nothing changed.
+    pub skipped: Vec<String>,
+    /// Files left out of an unpack as they don't start with an archive header.
+    pub ignored: Vec<String>,
+    pub failed: Vec<(String, String)>,
+}
+
+impl Summary {
+    pub fn record(&mut self, path: String, result: Result<(), Box<dyn Error>>) {
+        match result {
+            Ok(()) => self.succeeded.push(path),
+            Err(err) => self.failed.push((path, err.to_string())),
+        }
+    }
+
+    /// Prints the failures, and the counts unless `slience` is set.
+    pub fn print(&self, slience: bool) {
+        for (path, err) in &self.failed {
+            println!("Failed: {}: {}", path, err);
+        }
+        if slience {
+            return;
+        }
+        let mut counts = vec![format!(
+            "{} archive{} succeeded",
+            self.succeeded.len(),
+            if self.succeeded.len() == 1 { "" } else { "s" }
+        )];
+        if !self.skipped.is_empty() {
+            counts.push(format!("{} up to date", self.skipped.len()));
+        }
+        if !self.ignored.is_empty() {
+            counts.push(format!("{} not archives", self.ignored.len()));
+        }
+        counts.push(format!("{} failed", self.failed.len()));
+        println!("{}.", counts.join(", "));
+    }
+}
+
+/// Unpacks every archive under the input directory, "dir/name.bin" goes to
+/// "dir/name/" under the output directory. Files without a valid archive
+/// header are left out, and other extensions than "bin" are kept in the
+/// manifest so packing gives the archive its name back.
+pub fn unpack_tree(
+    input: &Path,
+    output: &Path,
+    filters: &Filters,
+    options: &UnpackOptions,
+) -> Summary {
+    let mut summary = Summary::default();
+    let mut files = vec![];
+    if let Err(err) = walk(input, &mut |path| {
+        if path.is_file() {
+            files.push(path.to_owned());
+        }
+        true
+    }) {
+        summary
+            .failed
+            .push((input.display().to_string(), err.to_string()));
+    }
+    for file in files {
+        let relative = relative_path(input, &file);
+        if !filters.accepts(&relative) {
+            continue;
+        }
+        let data = match std::fs::read(&file) {
+            Ok(data) => data,
+            Err(err) => {
+                summary.record(relative, Err(err.into()));
+                continue;
+            }
+        };
+        if archive::read_header(&mut Cursor::new(&data), data.len()).is_err() {
+            if options.verbose {
+                println!("Skipping {}, not an archive", relative);
+            }
+            summary.ignored.push(relative);
+            continue;
+        }
+        if options.verbose {
+            println!("Unpacking {}", relative);
+        }
+        let basename = file
+            .file_stem()
+            .map(|x| x.to_string_lossy().into_owned())
+            .unwrap_or_default();
+        let extension = file
+            .extension()
+            .map(|x| x.to_string_lossy().into_owned())
+            .unwrap_or_default();
+        let output = output.join(&relative).with_extension("");
+        let result = archive::unpack(&data, &basename, &output, options).and_then(|_| {
+            let mut manifest = Manifest::load(&output)?;
+            let extension = (extension != DEFAULT_EXTENSION).then_some(extension);
+            if manifest.extension == extension {
+                return Ok(());
+            }
+            manifest.extension = extension;
+            manifest.save(&output)
+        });
+        summary.record(relative, result);
+    }
+    summary
+}
+
+/// Packs every directory holding subfiles under the input directory,
+/// "dir/name/" goes to "dir/name.bin" under the output directory, or to the
+/// extension given by its manifest. With a cache, archives whose inputs
+/// didn't change are skipped.
+pub fn pack_tree(
+    input: &Path,
+    output: &Path,
+    filters: &Filters,
+    options: &PackOptions,
+    mut cache: Option<&mut BuildCache>,
+) -> Summary {
+    let mut summary = Summary::default();
+    let options = &PackOptions {
+        text_root: Some(input.to_owned()),
+        ..options.clone()
+    };
+    let mut dirs = vec![];
+    if let Err(err) = walk(input, &mut |path| {
+        if !path.is_dir() || !is_unpacked_archive(path) {
+            return true;
+        }
+        dirs.push(path.to_owned());
+        false
+    }) {
+        summary
+            .failed
+            .push((input.display().to_string(), err.to_string()));
+    }
+    for dir in dirs {
+        // A broken manifest fails the pack itself.
+        let extension = Manifest::load(&dir)
+            .ok()
+            .and_then(|x| x.extension)
+            .unwrap_or_else(|| DEFAULT_EXTENSION.to_owned());
+        let relative = relative_path(input, &dir);
+        let relative = if extension.is_empty() {
+            relative
+        } else {
+            format!("{}.{}", relative, extension)
+        };
+        if !filters.accepts(&relative) {
+            continue;
+        }
+        if options.verbose {
+            println!("Packing {}", relative);
+        }
+        let output = output.join(&relative);
+        let result = pack_cached(&dir, &output, options, cache.as_deref_mut(), |data| {
+            if let Some(parent) = output.parent() {
+                std::fs::create_dir_all(parent)?;
+            }
+            Ok(std::fs::write(&output, data)?)
+        });
+        match result {
+            Ok(false) => summary.skipped.push(relative),
+            result => summary.record(relative, result.map(|_| ())),
+        }
+    }
+    summary
+}
+
+/// Packs the directory and writes the archive with `write`, unless the cache
+/// says the existing output was built from the same inputs. Returns whether
+/// the archive was packed.
+pub fn pack_cached(
+    input: &Path,
+    output: &Path,
+    options: &PackOptions,
+    cache: Option<&mut BuildCache>,
+    write: impl FnOnce(&[u8]) -> Result<(), Box<dyn Error>>,
+) -> Result<bool, Box<dyn Error>> {
+    let Some(cache) = cache else {
+        write(&archive::pack(input, options)?)?;
+        return Ok(true);
+    };
+    let key = output.display().to_string();
+    let mut stamp = cache.stamp(&key, input, options)?;
+    if cache.is_up_to_date(&key, &stamp) {
+        return Ok(false);
+    }
+    let data = archive::pack(input, options)?;
+    write(&data)?;
+    stamp.output = incremental::hash(&data);
+    cache.update(key, stamp);
+    Ok(true)
+}
+
+/// Whether the directory directly holds files named like subfiles.
+pub fn is_unpacked_archive(dir: &Path) -> bool {
+    std::fs::read_dir(dir).is_ok_and(|entries| {
+        entries.flatten().any(|entry| {
+            entry.path().is_file()
+                && archive::parse_subfile_index(&entry.file_name().to_string_lossy()).is_some()
+        })
+    })
+}
+
+/// Visits every path under the directory in a stable order. Directories are
+/// only entered when `visit` returns true for them.
+pub fn walk(dir: &Path, visit: &mut impl FnMut(&Path) -> bool) -> Result<(), Box<dyn Error>> {
+    let mut entries = std::fs::read_dir(dir)?
+        .map(|x| x.map(|x| x.path()))
+        .collect::<Result<Vec<PathBuf>, _>>()?;
+    entries.sort();
+    for path in entries {
+        if visit(&path) && path.is_dir() {
+            walk(&path, visit)?;
+        }
+    }
+    Ok(())
+}
+
+/// The path relative to the base directory, always separated by '/'.
+pub fn relative_path(base: &Path, path: &Path) -> String {
+    path.strip_prefix(base)
+        .unwrap_or(path)
+        .components()
+        .map(|x| x.as_os_str().to_string_lossy())
+        .collect::<Vec<_>>()
+        .join("/")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{manifest::MANIFEST_NAME, test_util::TempDir};
+
+    /// An archive with one entry and the end entry.
+    fn archive(size: u32, data: &[u8]) -> Vec<u8> {
+        let mut archive = vec![];
+        archive.extend(16u32.to_le_bytes());
+        archive.extend(size.to_le_bytes());
+        archive.extend((16 + data.len() as u32).to_le_bytes());
+        archive.extend(0xFFFFu32.to_le_bytes());
+        archive.extend(data);
+        archive
+    }
+
+    #[test]
+    fn unpack_tree_reports_malformed_archives() {
+        let dir = TempDir::new();
+        // A compressed entry starting with a back reference.
+        dir.write(
+            "in/a/bad.bin",
+            archive(0x80000008, &[0x10, 0x08, 0, 0, 0x80, 0x00, 0x05, 0]),
+        );
+        dir.write("in/b/good.bin", archive(4, b"ABCD"));
+        let summary = unpack_tree(
+            &dir.path().join("in"),
+            &dir.path().join("out"),
+            &Filters::default(),
+            &UnpackOptions::default(),
+        );
+        assert_eq!(summary.succeeded, ["b/good.bin"]);
+        assert_eq!(summary.failed.len(), 1);
+        assert_eq!(summary.failed[0].0, "a/bad.bin");
+        assert_eq!(
+            std::fs::read(dir.path().join("out/b/good/good_0.bin")).unwrap(),
+            b"ABCD"
+        );
+    }
+
+    #[test]
+    fn unpack_tree_skips_files_that_are_not_archives() {
+        let dir = TempDir::new();
+        dir.write("in/readme.txt", b"Not an archive");
+        dir.write("in/a.bin", archive(4, b"ABCD"));
+        let summary = unpack_tree(
+            &dir.path().join("in"),
+            &dir.path().join("out"),
+            &Filters::default(),
+            &UnpackOptions::default(),
+        );
+        assert_eq!(summary.succeeded, ["a.bin"]);
+        assert_eq!(summary.ignored, ["readme.txt"]);
+        assert!(summary.failed.is_empty());
+        assert!(!dir.path().join("out/readme").exists());
+    }
+
+    #[test]
+    fn unpack_tree_applies_filters() {
+        let dir = TempDir::new();
+        for name in ["in/msg/a.bin", "in/msg/b.bin", "in/face/c.bin"] {
+            dir.write(name, archive(4, b"ABCD"));
+        }
+        let filters = Filters {
+            include: vec!["msg/*".to_owned()],
+            exclude: vec!["*/b.bin".to_owned()],
+        };
+        let summary = unpack_tree(
+            &dir.path().join("in"),
+            &dir.path().join("out"),
+            &filters,
+            &UnpackOptions::default(),
+        );
+        assert_eq!(summary.succeeded, ["msg/a.bin"]);
+        assert!(!dir.path().join("out/msg/b").exists());
+        assert!(!dir.path().join("out/face").exists());
+    }
+
+    #[test]
+    fn pack_tree_keeps_archive_extensions() {
+        let dir = TempDir::new();
+        let files = [
+            ("a.arc", archive(4, b"ABCD")),
+            ("sub/b.bin", archive(4, b"EFGH")),
+            ("c", archive(4, b"IJKL")),
+        ];
+        for (name, data) in &files {
+            dir.write(&format!("in/{}", name), data);
+        }
+        let unpacked = unpack_tree(
+            &dir.path().join("in"),
+            &dir.path().join("out"),
+            &Filters::default(),
+            &UnpackOptions::default(),
+        );
+        assert_eq!(unpacked.succeeded.len(), 3);
+        // Only other extensions than "bin" need a manifest.
+        assert!(!dir.path().join("out/sub/b").join(MANIFEST_NAME).exists());
+        let packed = pack_tree(
+            &dir.path().join("out"),
+            &dir.path().join("re"),
+            &Filters::default(),
+            &PackOptions::default(),
+            None,
+        );
+        assert_eq!(packed.succeeded, ["a.arc", "c", "sub/b.bin"]);
+        for (name, data) in &files {
+            assert_eq!(
+                &std::fs::read(dir.path().join("re").join(name)).unwrap(),
+                data
+            );
+        }
+    }
+
+    #[test]
+    fn pack_tree_skips_unchanged_archives() {
+        let dir = TempDir::new();
+        dir.write("in/a/a_0.bin", b"ABCD");
+        dir.write("in/b/b_0.bin", b"EFGH");
+        let mut cache = BuildCache::default();
+        let mut pack = || {
+            pack_tree(
+                &dir.path().join("in"),
+                &dir.path().join("out"),
+                &Filters::default(),
+                &PackOptions::default(),
+                Some(&mut cache),
+            )
+        };
+        assert_eq!(pack().succeeded, ["a.bin", "b.bin"]);
+        dir.write("in/b/b_0.bin", b"IJKL");
+        let summary = pack();
+        assert_eq!(summary.skipped, ["a.bin"]);
+        assert_eq!(summary.succeeded, ["b.bin"]);
+        assert_eq!(
+            std::fs::read(dir.path().join("out/b.bin")).unwrap(),
+            archive(4, b"IJKL")
+        );
+    }
+}
@@ -0,0 +1,605 @@
+//! Nitro fonts (NFTR) as a PNG glyph sheet and a JSON file with the font
+//! settings and the code, widths and advance of every glyph. The font is
+//! rebuilt from both, so glyphs can be added by appending them to the JSON
+//! and drawing them in the next cells of the sheet.
+
+use std::{
+    collections::{BTreeMap, HashSet},
+    error::Error,
+    path::{Path, PathBuf},
+};
+
+use byteorder::*;
+use serde::{Deserialize, Serialize};
+
+use crate::{graphics, text::CharTable};
+
+/// Glyphs in each row of the sheet.
+const SHEET_COLUMNS: usize = 16;
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct FontJson {
+    /// 0x100, 0x101 or 0x102 for versions 1.0 to 1.2.
+    pub version: u16,
+    pub font_type: u8,
+    pub line_feed: u8,
+    /// Glyph drawn for codes without one.
+    pub error_glyph: u16,
+    pub default_left: i8,
+    pub default_width: u8,
+    pub default_advance: u8,
+    /// 0 for UTF-8, 1 for UTF-16, 2 for Shift JIS and 3 for CP1252.
+    pub encoding: u8,
+    /// Only in version 1.2 fonts.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub height: Option<u8>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub width: Option<u8>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub ascent: Option<u8>,
+    pub cell_width: u8,
+    pub cell_height: u8,
+    pub baseline: u8,
+    pub max_width: u8,
+    pub bpp: u8,
+    pub rotation: u8,
+    /// Code ranges of the character maps, in file order.
+    pub maps: Vec<CodeMap>,
+    pub glyphs: Vec<Glyph>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct CodeMap {
+    /// "direct", "table" or "scan".
+    #[serde(rename = "type")]
+    pub map_type: String,
+    pub first: u16,
+    pub last: u16,
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct Glyph {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub code: Option<u16>,
+    /// The character of the code, which sets the code if there is none.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub char: Option<String>,
+    /// Other codes drawn with the glyph.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub aliases: Vec<u16>,
+    pub left: i8,
+    pub width: u8,
+    pub advance: u8,
+}
+
+/// Character of a code in the encoding of the font, if it is known.
+fn code_char(encoding: u8, code: u16) -> Option<char> {
+    match encoding {
+        0 | 1 => char::from_u32(code as u32),
+        3 if !(0x80..0xA0).contains(&code) => char::from_u32(code as u32),
+        _ => None,
+    }
+}
+
+fn char_code(encoding: u8, c: char) -> Option<u16> {
+    let code = u16::try_from(c as u32).ok()?;
+    (code_char(encoding, code) == Some(c)).then_some(code)
+}
+
+impl Glyph {
+    fn codes(&self, encoding: u8) -> Result<Vec<u16>, Box<dyn Error>> {
+        let code = match (self.code, &self.char) {
+            (Some(code), _) => Some(code),
+            (None, Some(text)) => {
+                let mut chars = text.chars();
+                let (Some(c), None) = (chars.next(), chars.next()) else {
+                    return Err(format!("\"{}\" isn't a single character", text).into());
+                };
+                Some(char_code(encoding, c).ok_or_else(|| {
+                    format!("\"{}\" has no code in the font encoding, set its code", c)
+                })?)
+            }
+            (None, None) => None,
+        };
+        Ok(code
+            .into_iter()
+            .chain(self.aliases.iter().copied())
+            .collect())
+    }
+}
+
+/// Pixel values of each glyph, one byte per pixel.
+pub type Glyphs = Vec<Vec<u8>>;
+
+/// Reads a section of the font at the offset of its data, checking its magic.
+fn section<'a>(data: &'a [u8], offset: usize, magic: &[u8; 4]) -> Option<&'a [u8]> {
+    let start = offset.checked_sub(8)?;
+    if data.get(start..offset)?[..4] != *magic {
+        return None;
+    }
+    let size = LE::read_u32(&data[start + 4..]) as usize;
+    data.get(offset..start + size)
+}
+
+/// Splits a font into its settings and the pixel values of each glyph.
+pub fn export(data: &[u8]) -> Result<(FontJson, Glyphs), Box<dyn Error>> {
+    let invalid = || "Invalid font file";
+    if data.get(..4) != Some(b"RTFN") || data.len() < 16 {
+        return Err(invalid().into());
+    }
+    let version = LE::read_u16(&data[6..]);
+    let header_size = LE::read_u16(&data[12..]) as usize;
+    let info = section(data, header_size + 8, b"FNIF").ok_or_else(invalid)?;
+    if info.len() != 20 && info.len() != 24 {
+        return Err("Unsupported font information size".into());
+    }
+    let v12 = |i: usize| info.get(i).copied();
+    let offset = |i: usize| LE::read_u32(&info[i..]) as usize;
+    let glyph_data = section(data, offset(8), b"PLGC")
+        .filter(|x| x.len() >= 8)
+        .ok_or_else(invalid)?;
+    let widths = section(data, offset(12), b"HDWC").filter(|x| x.len() >= 8);
+    let (cell_width, cell_height) = (glyph_data[0] as usize, glyph_data[1] as usize);
+    let cell_size = LE::read_u16(&glyph_data[2..]) as usize;
+    let bpp = glyph_data[6] as usize;
+    if ![1, 2, 4, 8].contains(&bpp) || cell_size == 0 {
+        return Err("Unsupported glyph format".into());
+    }
+    let mut count = (glyph_data.len() - 8) / cell_size;
+    if let Some(widths) = widths {
+        // Padding of the section can look like more glyphs.
+        let last = LE::read_u16(&widths[2..]) as usize;
+        if last < count && (count - last - 1) * cell_size < 4 {
+            count = last + 1;
+        }
+    }
+    let mut glyphs = vec![
+        Glyph {
+            left: info[4] as i8,
+            width: info[5],
+            advance: info[6],
+            ..Default::default()
+        };
+        count
+    ];
+    if let Some(widths) = widths {
+        let first = LE::read_u16(widths) as usize;
+        for (i, entry) in widths[8..].chunks_exact(3).enumerate() {
+            if let Some(glyph) = glyphs.get_mut(first + i) {
+                (glyph.left, glyph.width, glyph.advance) = (entry[0] as i8, entry[1], entry[2]);
+            }
+        }
+    }
+    let encoding = info[7];
+    let mut maps = vec![];
+    let mut next = offset(16);
+    let mut visited = HashSet::new();
+    while next != 0 {
+        // Maps linking back to an earlier one would be read forever.
+        if !visited.insert(next) {
+            return Err(invalid().into());
+        }
+        let map = section(data, next, b"PAMC")
+            .filter(|x| x.len() >= 14)
+            .ok_or_else(invalid)?;
+        let (first, last) = (LE::read_u16(map), LE::read_u16(&map[2..]));
+        let map_type = LE::read_u16(&map[4..]);
+        let mut codes = vec![];
+        match map_type {
+            0 => {
+                let start = LE::read_u16(&map[12..]);
+                for code in first..=last {
+                    codes.push((code, start.wrapping_add(code - first)));
+                }
+            }
+            1 => {
+                for (code, glyph) in (first..=last).zip(map[12..].chunks_exact(2)) {
+                    codes.push((code, LE::read_u16(glyph)));
+                }
+            }
+            2 => {
+                let pairs = LE::read_u16(&map[12..]) as usize;
+                for pair in map[14..].chunks_exact(4).take(pairs) {
+                    codes.push((LE::read_u16(pair), LE::read_u16(&pair[2..])));
+                }
+            }
+            _ => return Err(format!("Unknown character map type {}", map_type).into()),
+        }
+        for (code, glyph) in codes {
+            let Some(glyph) = glyphs.get_mut(glyph as usize) else {
+                continue;
+            };
+            if glyph.code.is_none() {
+                glyph.code = Some(code);
+                glyph.char = code_char(encoding, code).map(String::from);
+            } else if glyph.code != Some(code) && !glyph.aliases.contains(&code) {
+                glyph.aliases.push(code);
+            }
+        }
+        maps.push(CodeMap {
+            map_type: ["direct", "table", "scan"][map_type as usize].to_owned(),
+            first,
+            last,
+        });
+        next = LE::read_u32(&map[8..]) as usize;
+    }
+    let pixels = (0..count)
+        .map(|i| {
+            let cell = &glyph_data[8 + i * cell_size..8 + (i + 1) * cell_size];
+            (0..cell_width * cell_height)
+                .map(|p| {
+                    let bit = p * bpp;
+                    let byte = cell.get(bit / 8).copied().unwrap_or(0);
+                    (byte >> (8 - bpp - bit % 8)) & ((1 << bpp) - 1) as u8
+                })
+                .collect()
+        })
+        .collect();
+    let json = FontJson {
+        version,
+        font_type: info[0],
+        line_feed: info[1],
+        error_glyph: LE::read_u16(&info[2..]),
+        default_left: info[4] as i8,
+        default_width: info[5],
+        default_advance: info[6],
+        encoding,
+        height: v12(20),
+        width: v12(21),
+        ascent: v12(22),
+        cell_width: glyph_data[0],
+        cell_height: glyph_data[1],
+        baseline: glyph_data[4],
+        max_width: glyph_data[5],
+        bpp: glyph_data[6],
+        rotation: glyph_data[7],
+        maps,
+        glyphs,
+    };
+    Ok((json, pixels))
+}
+
+/// Pads a section to 4 bytes and writes it with its header.
+fn write_section(font: &mut Vec<u8>, magic: &[u8; 4], mut data: Vec<u8>) {
+    data.resize(data.len().next_multiple_of(4), 0);
+    font.extend(magic);
+    font.write_u32::<LE>(data.len() as u32 + 8).unwrap();
+    font.extend(data);
+}
+
+/// Builds a font from its settings and the pixel values of each glyph.
+pub fn import(json: &FontJson, pixels: &[Vec<u8>]) -> Result<Vec<u8>, Box<dyn Error>> {
+    let bpp = json.bpp as usize;
+    if ![1, 2, 4, 8].contains(&bpp) {
+        return Err("Glyphs must have 1, 2, 4 or 8 bits per pixel".into());
+    }
+    let cell_pixels = json.cell_width as usize * json.cell_height as usize;
+    let cell_size = (cell_pixels * bpp).div_ceil(8);
+    // Code of each glyph, in the order maps were read.
+    let mut codes = BTreeMap::new();
+    for (i, glyph) in json.glyphs.iter().enumerate() {
+        for code in glyph.codes(json.encoding)? {
+            if let Some(other) = codes.insert(code, i as u16) {
+                if other != i as u16 {
+                    return Err(format!(
+                        "Glyphs {} and {} have the same code {:04X}",
+                        other, i, code
+                    )
+                    .into());
+                }
+            }
+        }
+    }
+    let mut maps = vec![];
+    let mut mapped = HashSet::new();
+    for map in &json.maps {
+        let range = map.first..=map.last;
+        let in_range: Vec<(u16, u16)> = codes
+            .range(range.clone())
+            .map(|(code, glyph)| (*code, *glyph))
+            .filter(|x| map.map_type != "scan" || !mapped.contains(&x.0))
+            .collect();
+        let direct = !in_range.is_empty()
+            && in_range.len() == range.clone().count()
+            && in_range.iter().all(|(code, glyph)| {
+                glyph.wrapping_sub(*code) == in_range[0].1.wrapping_sub(map.first)
+            });
+        let mut data = vec![];
+        data.write_u16::<LE>(map.first)?;
+        data.write_u16::<LE>(map.last)?;
+        let map_type = match map.map_type.as_str() {
+            "direct" if direct => 0,
+            // Direct maps whose glyphs aren't in order any more become tables.
+            "direct" | "table" => 1,
+            "scan" => 2,
+            other => return Err(format!("Unknown character map type \"{}\"", other).into()),
+        };
+        data.write_u16::<LE>(map_type)?;
+        data.write_u16::<LE>(0)?;
+        data.write_u32::<LE>(0)?;
+        match map_type {
+            0 => data.write_u16::<LE>(in_range[0].1)?,
+            1 => {
+                for code in range {
+                    data.write_u16::<LE>(*codes.get(&code).unwrap_or(&0xFFFF))?;
+                }
+            }
+            _ => {
+                data.write_u16::<LE>(in_range.len() as u16)?;
+                for (code, glyph) in &in_range {
+                    data.write_u16::<LE>(*code)?;
+                    data.write_u16::<LE>(*glyph)?;
+                }
+            }
+        }
+        mapped.extend(in_range.iter().map(|x| x.0));
+        maps.push(data);
+    }
+    // Codes outside of every map get one more.
+    let rest: Vec<_> = codes.iter().filter(|x| !mapped.contains(x.0)).collect();
+    if let (Some(first), Some(last)) = (rest.first(), rest.last()) {
+        let mut data = vec![];
+        for value in [*first.0, *last.0, 2, 0, 0, 0, rest.len() as u16] {
+            data.write_u16::<LE>(value)?;
+        }
+        for (code, glyph) in rest {
+            data.write_u16::<LE>(*code)?;
+            data.write_u16::<LE>(*glyph)?;
+        }
+        maps.push(data);
+    }
+    let mut glyph_data = vec![
+        json.cell_width,
+        json.cell_height,
+        0,
+        0,
+        json.baseline,
+        json.max_width,
+        json.bpp,
+        json.rotation,
+    ];
+    LE::write_u16(&mut glyph_data[2..], cell_size as u16);
+    for glyph in pixels {
+        let mut cell = vec![0; cell_size];
+        for (p, value) in glyph.iter().enumerate() {
+            let bit = p * bpp;
+            cell[bit / 8] |= value << (8 - bpp - bit % 8);
+        }
+        glyph_data.extend(cell);
+    }
+    let mut widths = vec![];
+    widths.write_u16::<LE>(0)?;
+    widths.write_u16::<LE>(json.glyphs.len().saturating_sub(1) as u16)?;
+    widths.write_u32::<LE>(0)?;
+    for glyph in &json.glyphs {
+        widths.extend([glyph.left as u8, glyph.width, glyph.advance]);
+    }
+    let mut info = vec![
+        json.font_type,
+        json.line_feed,
+        0,
+        0,
+        json.default_left as u8,
+        json.default_width,
+        json.default_advance,
+        json.encoding,
+    ];
+    LE::write_u16(&mut info[2..], json.error_glyph);
+    info.resize(20, 0);
+    if let (Some(height), Some(width), Some(ascent)) = (json.height, json.width, json.ascent) {
+        info.extend([height, width, ascent, 0]);
+    }
+    // Offsets point to the data of each section, after its header.
+    let padded = |x: &Vec<u8>| x.len().next_multiple_of(4) + 8;
+    let glyphs_offset = 16 + padded(&info);
+    let widths_offset = glyphs_offset + padded(&glyph_data);
+    let mut map_offset = widths_offset + padded(&widths);
+    LE::write_u32(&mut info[8..], glyphs_offset as u32 + 8);
+    LE::write_u32(&mut info[12..], widths_offset as u32 + 8);
+    if !maps.is_empty() {
+        LE::write_u32(&mut info[16..], map_offset as u32 + 8);
+    }
+    for i in 0..maps.len() {
+        map_offset += padded(&maps[i]);
+        if i + 1 < maps.len() {
+            LE::write_u32(&mut maps[i][8..], map_offset as u32 + 8);
+        }
+    }
+    let mut font = b"RTFN".to_vec();
+    font.write_u16::<LE>(0xFEFF)?;
+    font.write_u16::<LE>(json.version)?;
+    font.write_u32::<LE>(0)?;
+    font.write_u16::<LE>(16)?;
+    font.write_u16::<LE>(3 + maps.len() as u16)?;
+    write_section(&mut font, b"FNIF", info);
+    write_section(&mut font, b"PLGC", glyph_data);
+    write_section(&mut font, b"HDWC", widths);
+    for map in maps {
+        write_section(&mut font, b"PAMC", map);
+    }
+    let size = font.len() as u32;
+    LE::write_u32(&mut font[8..], size);
+    Ok(font)
+}
+
+/// Gray of each pixel value, the highest value is black.
+fn levels(bpp: u8) -> Vec<[u8; 3]> {
+    let max = (1 << bpp) - 1;
+    (0..=max)
+        .map(|x| [(255 - x * 255 / max) as u8; 3])
+        .collect()
+}
+
+/// Draws the glyphs in rows of 16 cells.
+pub fn write_sheet(json: &FontJson, pixels: &[Vec<u8>]) -> Result<Vec<u8>, Box<dyn Error>> {
+    let (cell_width, cell_height) = (json.cell_width as usize, json.cell_height as usize);
+    let rows = pixels.len().div_ceil(SHEET_COLUMNS).max(1);
+    let width = SHEET_COLUMNS * cell_width;
+    let mut image = vec![0; width * rows * cell_height];
+    for (i, glyph) in pixels.iter().enumerate() {
+        let (x, y) = (
+            i % SHEET_COLUMNS * cell_width,
+            i / SHEET_COLUMNS * cell_height,
+        );
+        for (p, value) in glyph.iter().enumerate() {
+            image[(y + p / cell_width) * width + x + p % cell_width] = *value;
+        }
+    }
+    graphics::write_png(width, rows * cell_height, &image, &levels(json.bpp))
+}
+
+/// Reads the glyphs of the JSON file back from a sheet. Images using other
+/// colors than the exported ones are read by how dark each pixel is.
+pub fn read_sheet(json: &FontJson, png: &[u8]) -> Result<Glyphs, Box<dyn Error>> {
+    let image = graphics::read_png(png)?;
+    let (cell_width, cell_height) = (json.cell_width as usize, json.cell_height as usize);
+    if cell_width == 0 || cell_height == 0 {
+        return Err("Invalid glyph cell size".into());
+    }
+    let columns = image.width / cell_width;
+    let count = json.glyphs.len();
+    if columns == 0 || count.div_ceil(columns) * cell_height > image.height {
+        return Err(format!(
+            "The sheet doesn't hold {} glyphs of {}x{} pixels",
+            count, cell_width, cell_height
+        )
+        .into());
+    }
+    let levels = levels(json.bpp);
+    let max = levels.len() - 1;
+    let values: Vec<u8> = match &image.indexed {
+        Some((indices, palette))
+            if indices
+                .iter()
+                .all(|x| (*x as usize) <= max && palette[*x as usize] == levels[*x as usize]) =>
+        {
+            indices.clone()
+        }
+        _ => image
+            .rgb
+            .iter()
+            .map(|[r, g, b]| {
+                let light = (*r as usize * 299 + *g as usize * 587 + *b as usize * 114) / 1000;
+                (((255 - light) * max + 127) / 255) as u8
+            })
+            .collect(),
+    };
+    Ok((0..count)
+        .map(|i| {
+            let (x, y) = (i % columns * cell_width, i / columns * cell_height);
+            (0..cell_width * cell_height)
+                .map(|p| values[(y + p / cell_width) * image.width + x + p % cell_width])
+                .collect()
+        })
+        .collect())
+}
+
+/// The glyph sheet written next to a JSON file.
+pub fn sheet_path(json: &Path) -> PathBuf {
+    json.with_extension("png")
+}
+
+/// Writes the JSON file and its sheet, returning whether the font rebuilds
+/// to the same data.
+pub fn export_files(data: &[u8], json_path: &Path) -> Result<bool, Box<dyn Error>> {
+    let (json, pixels) = export(data)?;
+    std::fs::write(json_path, serde_json::to_string_pretty(&json)?)?;
+    std::fs::write(sheet_path(json_path), write_sheet(&json, &pixels)?)?;
+    Ok(import(&json, &pixels).is_ok_and(|x| x == data))
+}
+
+/// Builds a font from a JSON file and its sheet.
+pub fn import_files(json_path: &Path) -> Result<(FontJson, Vec<u8>), Box<dyn Error>> {
+    let json: FontJson = serde_json::from_str(
+        &std::fs::read_to_string(json_path)
+            .map_err(|e| format!("Can't read {}: {}", json_path.display(), e))?,
+    )?;
+    let sheet = sheet_path(json_path);
+    let png =
+        std::fs::read(&sheet).map_err(|e| format!("Can't read {}: {}", sheet.display(), e))?;
+    let pixels = read_sheet(&json, &png)?;
+    let font = import(&json, &pixels)?;
+    Ok((json, font))
+}
+
+/// Characters of the table the font has no glyph for.
+pub fn missing_chars(json: &FontJson, table: &CharTable) -> Vec<char> {
+    let mut codes = HashSet::new();
+    for glyph in &json.glyphs {
+        codes.extend(glyph.codes(json.encoding).unwrap_or_default());
+    }
+    let mut missing: Vec<char> = table
+        .characters()
+        .filter(|c| *c != '\n')
+        .filter(|c| !char_code(json.encoding, *c).is_some_and(|x| codes.contains(&x)))
+        .collect();
+    missing.sort();
+    missing.dedup();
+    missing
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Font with two 8x2 glyphs of 2 bits per pixel, a direct map for "AB"
+    /// and a scan map making " " an alias of "A".
+    fn nftr() -> Vec<u8> {
+        let mut font = b"RTFN".to_vec();
+        font.extend([0xFF, 0xFE, 0x01, 0x01, 144, 0, 0, 0, 16, 0, 5, 0]);
+        let mut section = |magic: &[u8; 4], data: &[u8]| {
+            font.extend(magic);
+            font.extend((data.len() as u32 + 8).to_le_bytes());
+            font.extend(data);
+        };
+        // Type, line feed, error glyph, default left, width and advance,
+        // encoding and the offsets of the glyphs, widths and maps.
+        let mut info = vec![0, 10, 0, 0, 0, 8, 9, 1];
+        for offset in [52u32, 76, 100] {
+            info.extend(offset.to_le_bytes());
+        }
+        section(b"FNIF", &info);
+        section(
+            b"PLGC",
+            &[
+                8, 2, 4, 0, 1, 8, 2, 0, 0x1B, 0xE4, 0, 0xFF, 0x55, 0xAA, 0x0F, 0xF0,
+            ],
+        );
+        section(
+            b"HDWC",
+            &[0, 0, 1, 0, 0, 0, 0, 0, 0, 7, 8, 0xFF, 6, 7, 0, 0],
+        );
+        section(
+            b"PAMC",
+            &[0x41, 0, 0x42, 0, 0, 0, 0, 0, 124, 0, 0, 0, 0, 0, 0, 0],
+        );
+        section(
+            b"PAMC",
+            &[
+                0x20, 0, 0x20, 0, 2, 0, 0, 0, 0, 0, 0, 0, 1, 0, 0x20, 0, 0, 0, 0, 0,
+            ],
+        );
+        font
+    }
+
+    #[test]
+    fn font_round_trips() {
+        let data = nftr();
+        let (json, pixels) = export(&data).unwrap();
+        assert_eq!(json.glyphs[0].char.as_deref(), Some("A"));
+        assert_eq!(json.glyphs[0].aliases, [0x20]);
+        assert_eq!(json.glyphs[1].left, -1);
+        assert_eq!(pixels[0][..4], [0, 1, 2, 3]);
+        let sheet = write_sheet(&json, &pixels).unwrap();
+        let pixels = read_sheet(&json, &sheet).unwrap();
+        assert_eq!(import(&json, &pixels).unwrap(), data);
+    }
+
+    #[test]
+    fn rejects_map_cycles() {
+        let mut data = nftr();
+        // The scan map links back to the direct map.
+        data[132] = 100;
+        assert_eq!(export(&data).unwrap_err().to_string(), "Invalid font file");
+    }
+}
@@ -0,0 +1,827 @@
+//! Nitro graphics entries converted to indexed PNGs: palettes (NCLR) as a
+//! swatch of 16 colors per row, character data (NCGR) as tiles with their
+//! palette, and screens (NSCR) rendered with their character data and
+//! palette. Converting back only replaces the colors, pixels or map entries,
+//! every other field of the original file is kept. Headerless BGR555 palettes
+//! and 4bpp or 8bpp tiles are converted the same way, once declared in the
+//! manifest. Sprite cells and animations are handled by `sprite`.
+
+use std::{collections::HashMap, error::Error, ops::Range, path::Path};
+
+use byteorder::*;
+
+use crate::{manifest::RawFormat, sprite};
+
+/// Generic Nitro file: a header followed by tagged sections.
+pub struct NitroFile {
+    header: Vec<u8>,
+    sections: Vec<([u8; 4], Vec<u8>)>,
+}
+
+impl NitroFile {
+    pub fn parse(data: &[u8]) -> Option<Self> {
+        if data.len() < 16 || LE::read_u16(&data[4..]) != 0xFEFF {
+            return None;
+        }
+        let header_size = LE::read_u16(&data[12..]) as usize;
+        let count = LE::read_u16(&data[14..]) as usize;
+        let mut sections = Vec::with_capacity(count);
+        let mut offset = header_size;
+        for _ in 0..count {
+            let magic = data.get(offset..offset + 4)?.try_into().ok()?;
+            let size = LE::read_u32(data.get(offset + 4..offset + 8)?) as usize;
+            if size < 8 {
+                return None;
+            }
+            sections.push((magic, data.get(offset + 8..offset + size)?.to_vec()));
+            offset += size;
+        }
+        if offset != data.len() {
+            return None;
+        }
+        Some(Self {
+            header: data.get(..header_size)?.to_vec(),
+            sections,
+        })
+    }
+
+    pub fn write(&self) -> Vec<u8> {
+        let mut data = self.header.clone();
+        for (magic, section) in &self.sections {
+            data.extend(magic);
+            data.write_u32::<LE>(section.len() as u32 + 8).unwrap();
+            data.extend(section);
+        }
+        let size = data.len() as u32;
+        LE::write_u32(&mut data[8..], size);
+        data
+    }
+
+    pub fn section(&self, magic: &[u8; 4]) -> Option<&Vec<u8>> {
+        self.sections.iter().find(|x| &x.0 == magic).map(|x| &x.1)
+    }
+
+    pub fn section_mut(&mut self, magic: &[u8; 4]) -> Option<&mut Vec<u8>> {
+        self.sections
+            .iter_mut()
+            .find(|x| &x.0 == magic)
+            .map(|x| &mut x.1)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Kind {
+    Palette,
+    Tiles,
+    Screen,
+    RawPalette,
+    RawTiles4,
+    RawTiles8,
+    Cells,
+    Animation,
+}
+
+const KINDS: [Kind; 8] = [
+    Kind::Palette,
+    Kind::Tiles,
+    Kind::Screen,
+    Kind::RawPalette,
+    Kind::RawTiles4,
+    Kind::RawTiles8,
+    Kind::Cells,
+    Kind::Animation,
+];
+
+impl Kind {
+    /// Detects the kind of a Nitro graphics file by its magic.
+    pub fn detect(data: &[u8]) -> Option<Self> {
+        match data.get(..4)? {
+            b"RLCN" => Some(Self::Palette),
+            b"RGCN" => Some(Self::Tiles),
+            b"RCSN" => Some(Self::Screen),
+            b"RECN" => Some(Self::Cells),
+            b"RNAN" => Some(Self::Animation),
+            _ => None,
+        }
+    }
+
+    pub fn of_format(format: RawFormat) -> Self {
+        match format {
+            RawFormat::Palette => Self::RawPalette,
+            RawFormat::Tiles4 => Self::RawTiles4,
+            RawFormat::Tiles8 => Self::RawTiles8,
+        }
+    }
+
+    /// Extension of the converted file.
+    pub fn extension(&self) -> &'static str {
+        match self {
+            Self::Palette => "nclr.png",
+            Self::Tiles => "ncgr.png",
+            Self::Screen => "nscr.png",
+            Self::RawPalette => "pal.png",
+            Self::RawTiles4 => "4bpp.png",
+            Self::RawTiles8 => "8bpp.png",
+            Self::Cells => "ncer.json",
+            Self::Animation => "nanr.json",
+        }
+    }
+
+    /// Whether other entries can use it as their palette.
+    pub fn is_palette(&self) -> bool {
+        matches!(self, Self::Palette | Self::RawPalette)
+    }
+
+    fn raw_bpp(&self) -> Option<usize> {
+        match self {
+            Self::RawTiles4 => Some(4),
+            Self::RawTiles8 => Some(8),
+            _ => None,
+        }
+    }
+
+    /// Kind of a converted file, from its name.
+    pub fn of_path(path: &Path) -> Option<Self> {
+        let name = path.file_name()?.to_string_lossy().to_ascii_lowercase();
+        let name = name
+            .strip_suffix(".nocompress")
+            .or_else(|| name.strip_suffix(".compress"))
+            .unwrap_or(&name);
+        KINDS
+            .into_iter()
+            .find(|x| name.ends_with(&format!(".{}", x.extension())))
+    }
+}
+
+/// The closest index before `index`, else after it, among the candidates.
+pub fn nearest(index: usize, candidates: impl Iterator<Item = usize>) -> Option<usize> {
+    candidates.filter(|x| *x != index).min_by_key(|x| {
+        if *x < index {
+            index - x
+        } else {
+            usize::MAX / 2 + x
+        }
+    })
+}
+
+pub fn to_rgb(color: u16) -> [u8; 3] {
+    let channel = |x: u16| {
+        let x = (x & 0x1F) as u8;
+        (x << 3) | (x >> 2)
+    };
+    [channel(color), channel(color >> 5), channel(color >> 10)]
+}
+
+pub fn to_bgr555(rgb: [u8; 3]) -> u16 {
+    (rgb[0] as u16 >> 3) | ((rgb[1] as u16 >> 3) << 5) | ((rgb[2] as u16 >> 3) << 10)
+}
+
+/// Colors of an NCLR file and where they are in its palette section.
+fn palette_colors(file: &NitroFile) -> Option<(Range<usize>, Vec<u16>)> {
+    let section = file.section(b"TTLP")?;
+    let size = LE::read_u32(section.get(8..12)?) as usize;
+    let offset = LE::read_u32(section.get(12..16)?) as usize;
+    let end = (offset + size).min(section.len()) & !1;
+    let colors = section.get(offset..end)?;
+    Some((
+        offset..end,
+        colors.chunks_exact(2).map(LE::read_u16).collect(),
+    ))
+}
+
+/// RGB colors of an NCLR file or raw palette, or a grayscale ramp without
+/// one.
+pub fn palette_rgb(palette: Option<&[u8]>, count: usize) -> Result<Vec<[u8; 3]>, Box<dyn Error>> {
+    let Some(palette) = palette else {
+        return Ok((0..count)
+            .map(|i| [(i * 255 / (count - 1)) as u8; 3])
+            .collect());
+    };
+    let colors = match NitroFile::parse(palette) {
+        Some(file) => palette_colors(&file).ok_or("Invalid palette file")?.1,
+        None => palette.chunks_exact(2).map(LE::read_u16).collect(),
+    };
+    let mut rgb: Vec<_> = colors.into_iter().take(count).map(to_rgb).collect();
+    rgb.resize(count, [0; 3]);
+    Ok(rgb)
+}
+
+/// Character data of an NCGR file.
+pub struct CharData {
+    pub bpp: usize,
+    /// Width in tiles, if the file has one.
+    pub width: Option<usize>,
+    /// Pixels are stored as lines instead of 8x8 tiles.
+    pub linear: bool,
+    /// Where the pixels are in the character section.
+    pub range: Range<usize>,
+}
+
+impl CharData {
+    pub fn read(file: &NitroFile) -> Option<Self> {
+        let section = file.section(b"RAHC")?;
+        let width = LE::read_u16(section.get(2..4)?);
+        let bpp = match LE::read_u32(section.get(4..8)?) {
+            3 => 4,
+            4 => 8,
+            _ => return None,
+        };
+        let linear = LE::read_u32(section.get(12..16)?) & 0xFF != 0;
+        let size = LE::read_u32(section.get(16..20)?) as usize;
+        let offset = LE::read_u32(section.get(20..24)?) as usize;
+        Some(Self {
+            bpp,
+            width: (width != 0xFFFF && width != 0).then_some(width as usize),
+            linear,
+            range: offset..(offset + size).min(section.len()),
+        })
+    }
+}
+
+/// Palette indices of 4bpp or 8bpp pixel data.
+pub fn unpack_pixels(data: &[u8], bpp: usize) -> Vec<u8> {
+    if bpp == 8 {
+        return data.to_vec();
+    }
+    data.iter().flat_map(|x| [x & 0xF, x >> 4]).collect()
+}
+
+fn pack_pixels(pixels: &[u8], bpp: usize) -> Vec<u8> {
+    if bpp == 8 {
+        return pixels.to_vec();
+    }
+    pixels
+        .chunks(2)
+        .map(|x| x[0] | (x.get(1).unwrap_or(&0) << 4))
+        .collect()
+}
+
+/// Lays out 8x8 tiles in rows of `width` tiles.
+pub fn tiles_to_image(pixels: &[u8], width: usize) -> (usize, usize, Vec<u8>) {
+    let count = pixels.len() / 64;
+    let height = count.div_ceil(width);
+    let mut image = vec![0; width * 8 * height * 8];
+    for (tile, data) in pixels.chunks_exact(64).enumerate() {
+        let (tx, ty) = (tile % width, tile / width);
+        for (i, pixel) in data.iter().enumerate() {
+            image[(ty * 8 + i / 8) * width * 8 + tx * 8 + i % 8] = *pixel;
+        }
+    }
+    (width * 8, height * 8, image)
+}
+
+/// Splits an image into `count` 8x8 tiles, read in rows.
+pub fn image_to_tiles(image: &[u8], width: usize, count: usize) -> Vec<u8> {
+    let tiles_wide = width / 8;
+    let mut pixels = Vec::with_capacity(count * 64);
+    for tile in 0..count {
+        let (tx, ty) = (tile % tiles_wide, tile / tiles_wide);
+        for i in 0..64 {
+            pixels.push(image[(ty * 8 + i / 8) * width + tx * 8 + i % 8]);
+        }
+    }
+    pixels
+}
+
+/// A width in tiles for character data without one, as close to 32 as
+/// possible while keeping the image rectangular.
+fn default_width(count: usize) -> usize {
+    (1..=count.clamp(1, 32))
+        .rev()
+        .find(|x| count.is_multiple_of(*x))
+        .unwrap_or(1)
+}
+
+pub fn write_png(
+    width: usize,
+    height: usize,
+    pixels: &[u8],
+    palette: &[[u8; 3]],
+) -> Result<Vec<u8>, Box<dyn Error>> {
+    let mut data = Vec::new();
+    let mut encoder = png::Encoder::new(&mut data, width as u32, height as u32);
+    encoder.set_color(png::ColorType::Indexed);
+    encoder.set_depth(png::BitDepth::Eight);
+    encoder.set_palette(palette.concat());
+    encoder.write_header()?.write_image_data(pixels)?;
+    Ok(data)
+}
+
+/// A decoded PNG, with its palette indices if it is indexed.
+pub struct Image {
+    pub width: usize,
+    pub height: usize,
+    pub rgb: Vec<[u8; 3]>,
+    pub indexed: Option<(Vec<u8>, Vec<[u8; 3]>)>,
+}
+
+pub fn read_png(data: &[u8]) -> Result<Image, Box<dyn Error>> {
+    let mut decoder = png::Decoder::new(data);
+    decoder.set_transformations(png::Transformations::IDENTITY);
+    let mut reader = decoder.read_info()?;
+    let mut buf = vec![0; reader.output_buffer_size()];
+    let frame = reader.next_frame(&mut buf)?;
+    let (width, height) = (frame.width as usize, frame.height as usize);
+    let depth = frame.bit_depth as usize;
+    let line = frame.line_size;
+    // Samples of each pixel, widened to 8 bits.
+    let channels = frame.color_type.samples();
+    let sample = |y: usize, x: usize, c: usize| -> u16 {
+        let i = x * channels + c;
+        match depth {
+            16 => u16::from_be_bytes([buf[y * line + i * 2], buf[y * line + i * 2 + 1]]) >> 8,
+            8 => buf[y * line + i] as u16,
+            _ => {
+                let bit = i * depth;
+                let byte = buf[y * line + bit / 8];
+                (byte >> (8 - depth - bit % 8)) as u16 & ((1 << depth) - 1)
+            }
+        }
+    };
+    let scale = |x: u16| -> u8 {
+        match depth {
+            1 => (x * 255) as u8,
+            2 => (x * 85) as u8,
+            4 => (x * 17) as u8,
+            _ => x as u8,
+        }
+    };
+    let mut rgb = Vec::with_capacity(width * height);
+    let mut indices = Vec::new();
+    let palette: Vec<[u8; 3]> = reader
+        .info()
+        .palette
+        .as_ref()
+        .map(|x| x.chunks_exact(3).map(|x| [x[0], x[1], x[2]]).collect())
+        .unwrap_or_default();
+    for y in 0..height {
+        for x in 0..width {
+            match frame.color_type {
+                png::ColorType::Indexed => {
+                    let index = sample(y, x, 0) as u8;
+                    indices.push(index);
+                    rgb.push(*palette.get(index as usize).ok_or("Invalid palette index")?);
+                }
+                png::ColorType::Grayscale | png::ColorType::GrayscaleAlpha => {
+                    rgb.push([scale(sample(y, x, 0)); 3])
+                }
+                _ => rgb.push([
+                    sample(y, x, 0) as u8,
+                    sample(y, x, 1) as u8,
+                    sample(y, x, 2) as u8,
+                ]),
+            }
+        }
+    }
+    Ok(Image {
+        width,
+        height,
+        rgb,
+        indexed: (frame.color_type == png::ColorType::Indexed).then_some((indices, palette)),
+    })
+}
+
+/// Whether the PNG is indexed with the colors of the palette entry.
+pub fn uses_palette(png: &[u8], palette: &[u8]) -> bool {
+    let Ok(Image {
+        indexed: Some((_, colors)),
+        ..
+    }) = read_png(png)
+    else {
+        return false;
+    };
+    palette_rgb(Some(palette), colors.len()).is_ok_and(|rgb| {
+        rgb.iter()
+            .zip(&colors)
+            .all(|(a, b)| to_bgr555(*a) == to_bgr555(*b))
+    })
+}
+
+/// Palette indices of the image in the given palette. Indexed images using
+/// the same colors keep their indices, others get the closest color of each
+/// pixel.
+pub fn to_indices(image: &Image, palette: &[[u8; 3]]) -> Vec<u8> {
+    if let Some((indices, colors)) = &image.indexed {
+        let same = |i: u8| {
+            colors.get(i as usize).map(|x| to_bgr555(*x))
+                == palette.get(i as usize).map(|x| to_bgr555(*x))
+        };
+        if indices.iter().all(|x| same(*x)) {
+            return indices.clone();
+        }
+    }
+    let mut cache = HashMap::new();
+    image
+        .rgb
+        .iter()
+        .map(|color| {
+            *cache.entry(*color).or_insert_with(|| {
+                let distance = |x: &[u8; 3]| {
+                    (0..3)
+                        .map(|c| (x[c] as i32 - color[c] as i32).pow(2))
+                        .sum::<i32>()
+                };
+                // The first exact match wins, like the original palette order.
+                (0..palette.len())
+                    .min_by_key(|i| distance(&palette[*i]))
+                    .unwrap_or_default() as u8
+            })
+        })
+        .collect()
+}
+
+/// Swatch of the colors, 16 per row.
+fn palette_png(colors: &[u16]) -> Result<Vec<u8>, Box<dyn Error>> {
+    if colors.is_empty() || colors.len() > 256 {
+        return Err("Only palettes of 1 to 256 colors can be converted".into());
+    }
+    let rgb: Vec<_> = colors.iter().map(|x| to_rgb(*x)).collect();
+    let height = rgb.len().div_ceil(16);
+    let mut pixels: Vec<u8> = (0..rgb.len()).map(|x| x as u8).collect();
+    pixels.resize(16 * height, 0);
+    write_png(16, height, &pixels, &rgb)
+}
+
+/// Writes the colors of the image over the BGR555 colors, leaving the ones
+/// which didn't change as they were.
+fn import_palette(image: Image, colors: &mut [u8]) {
+    let mut rgb = image.indexed.map(|x| x.1).unwrap_or(image.rgb);
+    rgb.truncate(colors.len() / 2);
+    for (i, color) in rgb.into_iter().enumerate() {
+        let original = LE::read_u16(&colors[i * 2..]);
+        if to_bgr555(to_rgb(original)) != to_bgr555(color) {
+            LE::write_u16(&mut colors[i * 2..], to_bgr555(color));
+        }
+    }
+}
+
+/// Palette indices of a tile image, checked against the bit depth and the
+/// amount of pixels expected.
+fn import_tiles(
+    image: &Image,
+    palette: Option<&[u8]>,
+    bpp: usize,
+    size: usize,
+    linear: bool,
+) -> Result<Vec<u8>, Box<dyn Error>> {
+    let indices = to_indices(image, &palette_rgb(palette, 1 << bpp)?);
+    if indices.iter().any(|x| (*x as usize) >= 1 << bpp) {
+        return Err("Image uses colors out of the palette".into());
+    }
+    let pixels = if linear {
+        indices
+    } else {
+        if !image.width.is_multiple_of(8) || image.width * image.height < size {
+            return Err("Image doesn't hold the original amount of tiles".into());
+        }
+        image_to_tiles(&indices, image.width, size / 64)
+    };
+    if pixels.len() != size {
+        return Err(format!("Image has {} pixels, expecting {}", pixels.len(), size).into());
+    }
+    Ok(pack_pixels(&pixels, bpp))
+}
+
+/// Converts a graphics entry to a PNG, or cells and animations to JSON.
+/// Character data needs its palette and screens need both. Tiles are laid
+/// out `width` tiles wide if given, else as stored in the file or as close
+/// to 32 as possible.
+pub fn export(
+    kind: Kind,
+    data: &[u8],
+    palette: Option<&[u8]>,
+    tiles: Option<&[u8]>,
+    width: Option<usize>,
+) -> Result<Vec<u8>, Box<dyn Error>> {
+    if let Some(bpp) = kind.raw_bpp() {
+        let pixels = unpack_pixels(data, bpp);
+        let count = pixels.len() / 64;
+        if count == 0 || !pixels.len().is_multiple_of(64) {
+            return Err("Invalid tile data size".into());
+        }
+        let rgb = palette_rgb(palette, 1 << bpp)?;
+        let width = width
+            .filter(|x| *x != 0)
+            .unwrap_or_else(|| default_width(count));
+        let (width, height, image) = tiles_to_image(&pixels, width);
+        return write_png(width, height, &image, &rgb);
+    }
+    if kind == Kind::RawPalette {
+        if !data.len().is_multiple_of(2) {
+            return Err("Invalid palette size".into());
+        }
+        return palette_png(&data.chunks_exact(2).map(LE::read_u16).collect::<Vec<_>>());
+    }
+    let file = NitroFile::parse(data).ok_or("Invalid Nitro file")?;
+    match kind {
+        Kind::Cells => sprite::export_cells(&file),
+        Kind::Animation => sprite::export_animations(&file),
+        Kind::Palette => {
+            let (_, colors) = palette_colors(&file).ok_or("Invalid palette file")?;
+            palette_png(&colors)
+        }
+        Kind::Tiles => {
+            let chars = CharData::read(&file).ok_or("Invalid character file")?;
+            let section = file.section(b"RAHC").unwrap();
+            let pixels = unpack_pixels(&section[chars.range.clone()], chars.bpp);
+            let rgb = palette_rgb(palette, 1 << chars.bpp)?;
+            if chars.linear {
+                let width = chars.width.ok_or("Linear character data needs a width")? * 8;
+                if pixels.is_empty() || !pixels.len().is_multiple_of(width) {
+                    return Err("Invalid character data size".into());
+                }
+                return write_png(width, pixels.len() / width, &pixels, &rgb);
+            }
+            let count = pixels.len() / 64;
+            if count == 0 || !pixels.len().is_multiple_of(64) {
+                return Err("Invalid character data size".into());
+            }
+            let width = width
+                .filter(|x| *x != 0)
+                .or(chars.width.filter(|x| count.is_multiple_of(*x)))
+                .unwrap_or_else(|| default_width(count));
+            let (width, height, image) = tiles_to_image(&pixels, width);
+            write_png(width, height, &image, &rgb)
+        }
+        Kind::Screen => {
+            let tiles = tiles.ok_or("No character data for the screen")?;
+            let tiles = NitroFile::parse(tiles).ok_or("Invalid character file")?;
+            let chars = CharData::read(&tiles).ok_or("Invalid character file")?;
+            if chars.linear {
+                return Err("Screens of linear character data can't be converted".into());
+            }
+            let pixels = unpack_pixels(
+                &tiles.section(b"RAHC").unwrap()[chars.range.clone()],
+                chars.bpp,
+            );
+            let screen = Screen::read(&file).ok_or("Invalid screen file")?;
+            let rgb = palette_rgb(palette, 256)?;
+            let mut image = vec![0; screen.width * screen.height];
+            for (i, entry) in screen.entries.iter().enumerate() {
+                let tile = (*entry & 0x3FF) as usize;
+                let bank = if chars.bpp == 4 {
+                    (entry >> 12) as u8 * 16
+                } else {
+                    0
+                };
+                let tile = pixels
+                    .get(tile * 64..tile * 64 + 64)
+                    .ok_or("Screen uses a tile out of the character data")?;
+                let (tx, ty) = (i % (screen.width / 8), i / (screen.width / 8));
+                for y in 0..8 {
+                    for x in 0..8 {
+                        let sx = if entry & 0x400 != 0 { 7 - x } else { x };
+                        let sy = if entry & 0x800 != 0 { 7 - y } else { y };
+                        image[(ty * 8 + y) * screen.width + tx * 8 + x] = bank + tile[sy * 8 + sx];
+                    }
+                }
+            }
+            write_png(screen.width, screen.height, &image, &rgb)
+        }
+        _ => unreachable!(),
+    }
+}
+
+/// Converts a PNG, or the JSON of cells and animations, back to a graphics
+/// entry, keeping everything but the converted data from the original entry.
+pub fn import(
+    kind: Kind,
+    converted: &[u8],
+    original: &[u8],
+    palette: Option<&[u8]>,
+    tiles: Option<&[u8]>,
+) -> Result<Vec<u8>, Box<dyn Error>> {
+    if matches!(kind, Kind::Cells | Kind::Animation) {
+        let mut file = NitroFile::parse(original).ok_or("Invalid original Nitro file")?;
+        let json = std::str::from_utf8(converted).map_err(|_| "Invalid UTF-8 text")?;
+        if kind == Kind::Cells {
+            sprite::import_cells(json, &mut file)?;
+        } else {
+            sprite::import_animations(json, &mut file)?;
+        }
+        return Ok(file.write());
+    }
+    let image = read_png(converted)?;
+    if let Some(bpp) = kind.raw_bpp() {
+        return import_tiles(&image, palette, bpp, original.len() * 8 / bpp, false);
+    }
+    if kind == Kind::RawPalette {
+        // Colors the image can't change keep their original value.
+        let mut data = original.to_vec();
+        import_palette(image, &mut data);
+        return Ok(data);
+    }
+    let mut file = NitroFile::parse(original).ok_or("Invalid original Nitro file")?;
+    match kind {
+        Kind::Palette => {
+            let (range, _) = palette_colors(&file).ok_or("Invalid palette file")?;
+            let section = file.section_mut(b"TTLP").unwrap();
+            import_palette(image, &mut section[range]);
+        }
+        Kind::Tiles => {
+            let chars = CharData::read(&file).ok_or("Invalid character file")?;
+            let size = chars.range.len() * 8 / chars.bpp;
+            let data = import_tiles(&image, palette, chars.bpp, size, chars.linear)?;
+            let section = file.section_mut(b"RAHC").unwrap();
+            section[chars.range].copy_from_slice(&data);
+        }
+        Kind::Screen => {
+            let tiles = tiles.ok_or("No character data for the screen")?;
+            let tiles = NitroFile::parse(tiles).ok_or("Invalid character file")?;
+            let chars = CharData::read(&tiles).ok_or("Invalid character file")?;
+            let pixels = unpack_pixels(
+                &tiles.section(b"RAHC").unwrap()[chars.range.clone()],
+                chars.bpp,
+            );
+            let mut screen = Screen::read(&file).ok_or("Invalid screen file")?;
+            if image.width != screen.width || image.height != screen.height {
+                return Err(
+                    format!("Image must be {}x{} pixels", screen.width, screen.height).into(),
+                );
+            }
+            let indices = to_indices(&image, &palette_rgb(palette, 256)?);
+            let tiles_wide = screen.width / 8;
+            for (i, entry) in screen.entries.iter_mut().enumerate() {
+                let (tx, ty) = (i % tiles_wide, i / tiles_wide);
+                let block: Vec<u8> = (0..64)
+                    .map(|p| indices[(ty * 8 + p / 8) * screen.width + tx * 8 + p % 8])
+                    .collect();
+                *entry = match_tile(&block, *entry, &pixels, chars.bpp).ok_or_else(|| {
+                    format!("Tile at {}, {} isn't in the character data", tx * 8, ty * 8)
+                })?;
+            }
+            screen.write(&mut file);
+        }
+        _ => unreachable!(),
+    }
+    Ok(file.write())
+}
+
+/// Finds the map entry drawing the block, trying the original entry first.
+fn match_tile(block: &[u8], original: u16, pixels: &[u8], bpp: usize) -> Option<u16> {
+    let bank = if bpp == 4 {
+        // Color 0 of any bank is transparent, so only the others tell it.
+        block
+            .iter()
+            .find(|x| *x % 16 != 0)
+            .map_or(original >> 12, |x| (*x / 16) as u16)
+    } else {
+        original >> 12
+    };
+    if bpp == 4
+        && block
+            .iter()
+            .any(|x| *x % 16 != 0 && (*x / 16) as u16 != bank)
+    {
+        return None;
+    }
+    let colors: Vec<u8> = block
+        .iter()
+        .map(|x| if bpp == 4 { x % 16 } else { *x })
+        .collect();
+    let drawn = |tile: usize, flip: u16| {
+        let Some(data) = pixels.get(tile * 64..tile * 64 + 64) else {
+            return false;
+        };
+        (0..64).all(|i| {
+            let (x, y) = (i % 8, i / 8);
+            let sx = if flip & 1 != 0 { 7 - x } else { x };
+            let sy = if flip & 2 != 0 { 7 - y } else { y };
+            data[sy * 8 + sx] == colors[i]
+        })
+    };
+    let entry = |tile: usize, flip: u16| (tile as u16) | (flip << 10) | (bank << 12);
+    let (tile, flip) = ((original & 0x3FF) as usize, (original >> 10) & 3);
+    if drawn(tile, flip) {
+        return Some(entry(tile, flip));
+    }
+    (0..(pixels.len() / 64).min(0x400))
+        .flat_map(|tile| (0..4).map(move |flip| (tile, flip)))
+        .find(|(tile, flip)| drawn(*tile, *flip))
+        .map(|(tile, flip)| entry(tile, flip))
+}
+
+/// Map entries of an NSCR file.
+struct Screen {
+    width: usize,
+    height: usize,
+    entries: Vec<u16>,
+}
+
+impl Screen {
+    fn read(file: &NitroFile) -> Option<Self> {
+        let section = file.section(b"NRCS")?;
+        let width = LE::read_u16(section.get(0..2)?) as usize;
+        let height = LE::read_u16(section.get(2..4)?) as usize;
+        let size = LE::read_u32(section.get(8..12)?) as usize;
+        let entries: Vec<u16> = section
+            .get(12..12 + size)?
+            .chunks_exact(2)
+            .map(LE::read_u16)
+            .collect();
+        if width == 0 || !width.is_multiple_of(8) || !height.is_multiple_of(8) {
+            return None;
+        }
+        if entries.len() != width / 8 * (height / 8) {
+            return None;
+        }
+        Some(Self {
+            width,
+            height,
+            entries,
+        })
+    }
+
+    fn write(&self, file: &mut NitroFile) {
+        let section = file.section_mut(b"NRCS").unwrap();
+        for (i, entry) in self.entries.iter().enumerate() {
+            LE::write_u16(&mut section[12 + i * 2..], *entry);
+        }
+    }
+}
+
+#[cfg(test)]
+pub mod tests {
+    use super::*;
+
+    /// A Nitro file with the given sections, each with its 8 byte header.
+    pub fn nitro(magic: &[u8; 4], sections: Vec<([u8; 4], Vec<u8>)>) -> Vec<u8> {
+        let mut header = magic.to_vec();
+        header.extend([0xFF, 0xFE, 0x00, 0x01, 0, 0, 0, 0, 16, 0]);
+        header.extend((sections.len() as u16).to_le_bytes());
+        NitroFile { header, sections }.write()
+    }
+
+    fn words(values: &[u32]) -> Vec<u8> {
+        values.iter().flat_map(|x| x.to_le_bytes()).collect()
+    }
+
+    /// 16 distinct BGR555 colors.
+    fn colors() -> Vec<u8> {
+        (0..16u16)
+            .flat_map(|i| (i * 0x0843).to_le_bytes())
+            .collect()
+    }
+
+    pub fn nclr() -> Vec<u8> {
+        let mut section = words(&[3, 0, 32, 16]);
+        section.extend(colors());
+        nitro(b"RLCN", vec![(*b"TTLP", section)])
+    }
+
+    /// 4bpp character data of `count` tiles, 2 tiles wide.
+    pub fn ncgr(count: usize) -> Vec<u8> {
+        let size = count * 32;
+        let mut section = vec![(count / 2) as u8, 0, 2, 0];
+        section.extend(words(&[3, 0, 0, size as u32, 24]));
+        section.extend((0..size).map(|i| (i * 7 % 256) as u8));
+        nitro(b"RGCN", vec![(*b"RAHC", section)])
+    }
+
+    fn nscr() -> Vec<u8> {
+        let entries: [u16; 4] = [0, 1 | 0x400, 2 | 0x800, 1 | 0xC00];
+        let mut section = vec![16, 0, 16, 0, 0, 0, 0, 0];
+        section.extend(words(&[8]));
+        section.extend(entries.iter().flat_map(|x| x.to_le_bytes()));
+        nitro(b"RCSN", vec![(*b"NRCS", section)])
+    }
+
+    fn round_trip(kind: Kind, data: &[u8], palette: Option<&[u8]>, tiles: Option<&[u8]>) {
+        let png = export(kind, data, palette, tiles, None).unwrap();
+        assert_eq!(import(kind, &png, data, palette, tiles).unwrap(), data);
+    }
+
+    #[test]
+    fn nitro_files_round_trip() {
+        let (palette, tiles) = (nclr(), ncgr(4));
+        assert_eq!(Kind::detect(&palette), Some(Kind::Palette));
+        round_trip(Kind::Palette, &palette, None, None);
+        round_trip(Kind::Tiles, &tiles, Some(&palette), None);
+        round_trip(Kind::Screen, &nscr(), Some(&palette), Some(&tiles));
+    }
+
+    #[test]
+    fn edited_tiles_are_imported() {
+        let (palette, tiles) = (nclr(), ncgr(2));
+        let png = export(Kind::Tiles, &tiles, Some(&palette), None, None).unwrap();
+        let mut image = read_png(&png).unwrap();
+        let (indices, _) = image.indexed.as_mut().unwrap();
+        indices[0] = 0xF;
+        let rgb = palette_rgb(Some(&palette), 16).unwrap();
+        let edited = write_png(image.width, image.height, indices, &rgb).unwrap();
+        let imported = import(Kind::Tiles, &edited, &tiles, Some(&palette), None).unwrap();
+        let offset = tiles.len() - 64;
+        assert_eq!(imported[offset] & 0xF, 0xF);
+        assert_eq!(imported[..offset], tiles[..offset]);
+        assert_eq!(imported[offset + 1..], tiles[offset + 1..]);
+    }
+
+    #[test]
+    fn raw_entries_round_trip() {
+        let palette = colors();
+        let tiles = (0..128).map(|i| (i * 7) as u8).collect::<Vec<_>>();
+        round_trip(Kind::RawPalette, &palette, None, None);
+        round_trip(Kind::RawTiles4, &tiles, Some(&palette), None);
+        round_trip(Kind::RawTiles8, &tiles[..64], None, None);
+    }
+}
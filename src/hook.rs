@@ -0,0 +1,265 @@
+//! External converters run on the subfiles, for formats not built in. A hook
+//! selects subfiles by file name pattern or by the magic their data starts
+//! with, and gives the commands making the editable file on unpack and the
+//! subfile back on pack:
+//!
+//! ```toml
+//! [[hook]]
+//! match = "mess_*.bin"
+//! magic = "BMD0"
+//! extension = "xml"
+//! unpack = "tools/converter.exe export {input} {output}"
+//! pack = "tools/converter.exe import {input} {output}"
+//! ```
+//!
+//! `{input}` and `{output}` are replaced by the paths of the file to read and
+//! the file to write, and `{index}` by the subfile number. Commands aren't run
+//! through a shell, double quotes group words with spaces.
+
+use std::{
+    error::Error,
+    path::{Path, PathBuf},
+    process::Command,
+};
+
+use serde::{Deserialize, Serialize};
+
+use crate::{glob, temp::TempFile};
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct Hook {
+    /// File name pattern of the subfile as it is unpacked without the hook,
+    /// like "name_003.bin".
+    #[serde(default, rename = "match", skip_serializing_if = "Option::is_none")]
+    pub pattern: Option<String>,
+    /// Bytes the subfile data starts with.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub magic: Option<String>,
+    /// Extension of the editable file.
+    pub extension: String,
+    /// Command converting the subfile to the editable file.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub unpack: Option<String>,
+    /// Command converting the editable file back to the subfile.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub pack: Option<String>,
+    /// Directory the commands run in, the one of the file declaring the hook.
+    #[serde(skip)]
+    pub dir: PathBuf,
+}
+
+#[derive(Deserialize)]
+struct HookFile {
+    #[serde(default, rename = "hook")]
+    hooks: Vec<Hook>,
+}
+
+/// Loads the hooks of a TOML file with `[[hook]]` tables.
+pub fn load(path: &Path) -> Result<Vec<Hook>, Box<dyn Error>> {
+    let file: HookFile = toml::from_str(&std::fs::read_to_string(path)?)?;
+    let dir = path.parent().unwrap_or(Path::new("")).to_path_buf();
+    Ok(in_dir(file.hooks, &dir))
+}
+
+/// Sets the directory the commands of the hooks run in.
+pub fn in_dir(hooks: Vec<Hook>, dir: &Path) -> Vec<Hook> {
+    hooks
+        .into_iter()
+        .map(|hook| Hook {
+            dir: dir.to_path_buf(),
+            ..hook
+        })
+        .collect()
+}
+
+impl Hook {
+    /// Whether the hook applies to a subfile with the given unpacked name
+    /// and data. A hook without pattern and magic applies to every subfile.
+    pub fn matches(&self, name: &str, data: &[u8]) -> bool {
+        self.pattern
+            .as_ref()
+            .is_none_or(|pattern| glob::matches(pattern, name))
+            && self
+                .magic
+                .as_ref()
+                .is_none_or(|magic| data.starts_with(magic.as_bytes()))
+    }
+
+    /// Runs the unpack command, writing the editable file to `output`.
+    pub fn unpack(&self, index: usize, data: &[u8], output: &Path) -> Result<(), Box<dyn Error>> {
+        let command = self.unpack.as_deref().ok_or("Hook has no unpack command")?;
+        let input = TempFile::new("input.bin");
+        std::fs::write(&input.0, data)?;
+        // A file left by an earlier unpack would pass for the result.
+        if output.is_file() {
+            std::fs::remove_file(output)?;
+        }
+        self.run(command, index, &input.0, output)?;
+        if !output.is_file() {
+            return Err(format!("Hook \"{}\" didn't write {}", command, output.display()).into());
+        }
+        Ok(())
+    }
+
+    /// Runs the pack command on the editable file, returning the subfile.
+    pub fn pack(&self, index: usize, input: &Path) -> Result<Vec<u8>, Box<dyn Error>> {
+        let command = self.pack.as_deref().ok_or("Hook has no pack command")?;
+        let output = TempFile::new("output.bin");
+        self.run(command, index, input, &output.0)?;
+        std::fs::read(&output.0)
+            .map_err(|e| format!("Hook \"{}\" didn't write its output: {}", command, e).into())
+    }
+
+    fn run(
+        &self,
+        command: &str,
+        index: usize,
+        input: &Path,
+        output: &Path,
+    ) -> Result<(), Box<dyn Error>> {
+        let input = std::path::absolute(input)?;
+        let output = std::path::absolute(output)?;
+        let mut words = split_words(command).into_iter().map(|word| {
+            word.replace("{input}", &input.to_string_lossy())
+                .replace("{output}", &output.to_string_lossy())
+                .replace("{index}", &index.to_string())
+        });
+        let program = words.next().ok_or("Empty hook command")?;
+        // A program given as a path is relative to the directory of the hook.
+        let program = if program.contains(['/', '\\']) {
+            std::path::absolute(self.dir.join(program))?
+        } else {
+            PathBuf::from(program)
+        };
+        let dir = if self.dir == Path::new("") {
+            Path::new(".")
+        } else {
+            &self.dir
+        };
+        let result = Command::new(&program)
+            .args(words)
+            .current_dir(dir)
+            .output()
+            .map_err(|e| format!("Can't run hook \"{}\": {}", command, e))?;
+        if !result.status.success() {
+            let stderr = String::from_utf8_lossy(&result.stderr);
+            let stdout = String::from_utf8_lossy(&result.stdout);
+            let message = if stderr.trim().is_empty() {
+                stdout
+            } else {
+                stderr
+            };
+            return Err(format!(
+                "Hook \"{}\" failed with {}: {}",
+                command,
+                result.status,
+                message.trim()
+            )
+            .into());
+        }
+        Ok(())
+    }
+}
+
+/// Splits a command into words at whitespace, keeping the ones in double
+/// quotes together.
+fn split_words(command: &str) -> Vec<String> {
+    let mut words = vec![];
+    let mut word = None::<String>;
+    let mut quoted = false;
+    for c in command.chars() {
+        match c {
+            '"' => {
+                quoted = !quoted;
+                word.get_or_insert_with(String::new);
+            }
+            c if c.is_whitespace() && !quoted => words.extend(word.take()),
+            c => word.get_or_insert_with(String::new).push(c),
+        }
+    }
+    words.extend(word);
+    words
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::TempDir;
+
+    fn hook(pattern: Option<&str>, magic: Option<&str>) -> Hook {
+        Hook {
+            pattern: pattern.map(String::from),
+            magic: magic.map(String::from),
+            extension: "xml".to_owned(),
+            unpack: None,
+            pack: None,
+            dir: PathBuf::new(),
+        }
+    }
+
+    #[test]
+    fn splits_quoted_words() {
+        assert_eq!(
+            split_words("tool  \"a b\"\tc\"d e\" \"\" end"),
+            ["tool", "a b", "cd e", "", "end"]
+        );
+        assert!(split_words("  ").is_empty());
+    }
+
+    #[test]
+    fn matches_pattern_and_magic() {
+        let data = b"BMD0data";
+        assert!(hook(None, None).matches("a_1.bin", data));
+        assert!(hook(Some("a_*.bin"), None).matches("a_1.bin", data));
+        assert!(!hook(Some("b_*.bin"), None).matches("a_1.bin", data));
+        assert!(hook(None, Some("BMD0")).matches("a_1.bin", data));
+        assert!(!hook(None, Some("BMD0")).matches("a_1.bin", b"BM"));
+        assert!(hook(Some("a_*"), Some("BMD0")).matches("a_1.bin", data));
+        assert!(!hook(Some("a_*"), Some("NCLR")).matches("a_1.bin", data));
+        assert!(!hook(Some("b_*"), Some("BMD0")).matches("a_1.bin", data));
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn runs_commands() {
+        let dir = TempDir::new();
+        let output = dir.path().join("a_0.xml");
+        let mut hook = hook(None, None);
+        hook.unpack = Some("cp {input} {output}".to_owned());
+        hook.pack = Some("sh -c \"printf {index} > '{output}'\"".to_owned());
+        hook.unpack(0, b"data", &output).unwrap();
+        assert_eq!(std::fs::read(&output).unwrap(), b"data");
+        assert_eq!(hook.pack(7, &output).unwrap(), b"7");
+        // The file of the previous unpack doesn't count as a result.
+        hook.unpack = Some("true".to_owned());
+        assert_eq!(
+            hook.unpack(0, b"data", &output).unwrap_err().to_string(),
+            format!("Hook \"true\" didn't write {}", output.display())
+        );
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn reports_failed_commands() {
+        let dir = TempDir::new();
+        let output = dir.path().join("a_0.xml");
+        let mut hook = hook(None, None);
+        hook.unpack = Some("sh -c \"echo oops >&2; exit 3\"".to_owned());
+        assert_eq!(
+            hook.unpack(0, b"", &output).unwrap_err().to_string(),
+            "Hook \"sh -c \"echo oops >&2; exit 3\"\" failed with exit status: 3: oops"
+        );
+        hook.unpack = Some("sfarctool-missing-tool {input}".to_owned());
+        let err = hook.unpack(0, b"", &output).unwrap_err().to_string();
+        assert!(err.starts_with("Can't run hook \"sfarctool-missing-tool {input}\""));
+        hook.unpack = Some(" ".to_owned());
+        assert_eq!(
+            hook.unpack(0, b"", &output).unwrap_err().to_string(),
+            "Empty hook command"
+        );
+        assert_eq!(
+            hook.pack(0, &output).unwrap_err().to_string(),
+            "Hook has no pack command"
+        );
+    }
+}
@@ -0,0 +1,233 @@
+//! Records the inputs of every packed archive so unchanged archives can be
+//! skipped on the next build.
+
+use std::{collections::BTreeMap, error::Error, path::Path, time::UNIX_EPOCH};
+
+use serde::{Deserialize, Serialize};
+
+use crate::{archive::PackOptions, batch, manifest::Manifest, nds};
+
+pub const DEFAULT_CACHE_NAME: &str = ".sfarctool-cache.toml";
+
+#[derive(Debug, Default, Deserialize, Serialize)]
+pub struct BuildCache {
+    /// Stamps keyed by the output path of the archive.
+    #[serde(default)]
+    pub archives: BTreeMap<String, ArchiveStamp>,
+}
+
+#[derive(Debug, Default, Clone, PartialEq, Eq, Deserialize, Serialize)]
+pub struct ArchiveStamp {
+    /// The pack options used, so changing them rebuilds the archive.
+    pub options: String,
+    pub files: BTreeMap<String, FileStamp>,
+    /// FNV-1a hash of the archive written, hex encoded. The output is read
+    /// back to check it still holds it, since an output inside a ROM has no
+    /// file of its own to stamp.
+    #[serde(default)]
+    pub output: String,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize, Serialize)]
+pub struct FileStamp {
+    pub size: u64,
+    /// Modification time in nanoseconds since the Unix epoch.
+    pub modified: u64,
+    /// FNV-1a hash of the content, hex encoded.
+    pub hash: String,
+}
+
+impl BuildCache {
+    /// Loads the cache, or an empty one if the file doesn't exist.
+    pub fn load(path: &Path) -> Result<Self, Box<dyn Error>> {
+        if !path.is_file() {
+            return Ok(Self::default());
+        }
+        Ok(toml::from_str(&std::fs::read_to_string(path)?)?)
+    }
+
+    pub fn save(&self, path: &Path) -> Result<(), Box<dyn Error>> {
+        std::fs::write(path, toml::to_string(self)?)?;
+        Ok(())
+    }
+
+    /// Stamps the files under the input directory and the other files the
+    /// archive is packed from: the schemas of the manifest and the template.
+    /// Only the files whose size or modification time differ from the
+    /// previous build are hashed.
+    pub fn stamp(
+        &self,
+        output: &str,
+        input: &Path,
+        options: &PackOptions,
+    ) -> Result<ArchiveStamp, Box<dyn Error>> {
+        let previous = self.archives.get(output).map(|x| &x.files);
+        let mut paths = vec![];
+        batch::walk(input, &mut |path| {
+            if path.is_file() {
+                paths.push((batch::relative_path(input, path), path.to_owned()));
+            }
+            true
+        })?;
+        let mut manifest = Manifest::load(input)?;
+        manifest.merge(&options.entries);
+        for schema in manifest.entries.iter().filter_map(|x| x.schema.as_ref()) {
+            paths.push((format!("schema:{}", schema), input.join(schema)));
+        }
+        let mut files = BTreeMap::new();
+        for (name, path) in paths {
+            let stamp = stamp_file(previous.and_then(|x| x.get(&name)), &path)?;
+            files.insert(name, stamp);
+        }
+        // The template can be inside a ROM, so it is always hashed.
+        if let Some(template) = &options.template {
+            let data = nds::read_archive(template)?;
+            files.insert(
+                format!("template:{}", template),
+                FileStamp {
+                    size: data.len() as u64,
+                    modified: 0,
+                    hash: hash(&data),
+                },
+            );
+        }
+        Ok(ArchiveStamp {
+            options: options.fingerprint(),
+            files,
+            output: String::new(),
+        })
+    }
+
+    /// Whether the archive was built from the same inputs. The modification
+    /// times are ignored, a file saved without changes doesn't count.
+    pub fn is_up_to_date(&self, output: &str, stamp: &ArchiveStamp) -> bool {
+        let Some(previous) = self.archives.get(output) else {
+            return false;
+        };
+        if !nds::read_archive(output).is_ok_and(|x| hash(&x) == previous.output) {
+            return false;
+        }
+        previous.options == stamp.options
+            && previous.files.len() == stamp.files.len()
+            && previous
+                .files
+                .iter()
+                .zip(&stamp.files)
+                .all(|(a, b)| a.0 == b.0 && a.1.size == b.1.size && a.1.hash == b.1.hash)
+    }
+
+    pub fn update(&mut self, output: String, stamp: ArchiveStamp) {
+        self.archives.insert(output, stamp);
+    }
+}
+
+/// Stamps a file, reusing the hash of the previous stamp if the size and
+/// modification time didn't change.
+fn stamp_file(previous: Option<&FileStamp>, path: &Path) -> Result<FileStamp, Box<dyn Error>> {
+    let metadata = std::fs::metadata(path)?;
+    let size = metadata.len();
+    let modified = metadata
+        .modified()?
+        .duration_since(UNIX_EPOCH)
+        .map(|x| x.as_nanos() as u64)
+        .unwrap_or_default();
+    let hash = match previous {
+        Some(stamp) if stamp.size == size && stamp.modified == modified => stamp.hash.clone(),
+        _ => hash(&std::fs::read(path)?),
+    };
+    Ok(FileStamp {
+        size,
+        modified,
+        hash,
+    })
+}
+
+/// The FNV-1a hash of the data, hex encoded.
+pub fn hash(data: &[u8]) -> String {
+    format!("{:016x}", fnv1a(data))
+}
+
+pub fn fnv1a(data: &[u8]) -> u64 {
+    let mut hash = 0xcbf29ce484222325u64;
+    for byte in data {
+        hash ^= *byte as u64;
+        hash = hash.wrapping_mul(0x100000001b3);
+    }
+    hash
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::TempDir;
+
+    #[test]
+    fn rebuilds_on_layout_change() {
+        let dir = TempDir::new();
+        let input = dir.path().join("a");
+        dir.write("a/a_0.bin", b"ABCD");
+        dir.write("a/.layout/trailer.bin", b"END");
+        let output = dir.path().join("a.bin");
+        let options = PackOptions::default();
+        let mut cache = BuildCache::default();
+        let pack = |cache: &mut BuildCache| {
+            batch::pack_cached(&input, &output, &options, Some(cache), |data| {
+                Ok(std::fs::write(&output, data)?)
+            })
+            .unwrap()
+        };
+        assert!(pack(&mut cache));
+        assert!(!pack(&mut cache));
+        dir.write("a/.layout/trailer.bin", b"NEW END");
+        assert!(pack(&mut cache));
+        assert!(std::fs::read(&output).unwrap().ends_with(b"NEW END"));
+    }
+
+    #[test]
+    fn rebuilds_on_schema_change() {
+        let dir = TempDir::new();
+        let input = dir.path().join("a");
+        dir.write("a/a_0.bin", b"ABCD");
+        dir.write(
+            "a/manifest.toml",
+            "[[entry]]\nindex = 0\nschema = \"../schema.toml\"\n",
+        );
+        dir.write("schema.toml", "size = 4\n");
+        let output = dir.path().join("a.bin");
+        let options = PackOptions::default();
+        let mut cache = BuildCache::default();
+        let pack = |cache: &mut BuildCache| {
+            batch::pack_cached(&input, &output, &options, Some(cache), |data| {
+                Ok(std::fs::write(&output, data)?)
+            })
+            .unwrap()
+        };
+        assert!(pack(&mut cache));
+        assert!(!pack(&mut cache));
+        dir.write("schema.toml", "size = 2\n");
+        assert!(pack(&mut cache));
+    }
+
+    #[test]
+    fn rebuilds_when_the_rom_lost_the_archive() {
+        let dir = TempDir::new();
+        let input = dir.path().join("a");
+        dir.write("a/a_0.bin", b"ABCD");
+        let rom = dir.write("rom.nds", nds::tests::rom());
+        let output = format!("{}:/sub/b.bin", rom.display());
+        let options = PackOptions::default();
+        let mut cache = BuildCache::default();
+        let pack = |cache: &mut BuildCache| {
+            batch::pack_cached(&input, Path::new(&output), &options, Some(cache), |data| {
+                nds::write_archive(&output, data)
+            })
+            .unwrap()
+        };
+        assert!(pack(&mut cache));
+        assert!(!pack(&mut cache));
+        // A clean copy of the ROM doesn't have the archive any more.
+        std::fs::write(&rom, nds::tests::rom()).unwrap();
+        assert!(pack(&mut cache));
+        assert!(!pack(&mut cache));
+    }
+}
@@ -49,22 +49,70 @@ pub struct CompressWindow<'a, const LEN: u32, const MIN: u32, const MAX: u32> {
     pub(self) start: u32,
     pub(self) stop: u32,
     pub(self) index: u32,
+    pub(self) depth: usize,
 }
 
 pub type NLZ10Window<'a> = CompressWindow<'a, 4096, 3, { 3 + 0xF }>;
-// pub type NLZ11Window<'a> = CompressWindow<'a, 4096, 3, { 0x111 + 0xFFFF }>;
+pub type NLZ11Window<'a> = CompressWindow<'a, 4096, 3, { 0x111 + 0xFFFF }>;
+
+/// The LZ variant used to encode a stream.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Codec {
+    LZ10,
+    LZ11,
+}
+
+impl Codec {
+    pub fn parse(name: &str) -> Option<Self> {
+        match name.to_ascii_lowercase().as_str() {
+            "10" | "lz10" | "0x10" => Some(Self::LZ10),
+            "11" | "lz11" | "0x11" => Some(Self::LZ11),
+            _ => None,
+        }
+    }
+}
+
+/// Highest (and default) compression level, which searches the whole window.
+pub const MAX_LEVEL: u32 = 9;
+
+#[derive(Debug, Clone, Copy)]
+pub struct CompressOptions {
+    pub codec: Codec,
+    /// From 1 to [`MAX_LEVEL`], lower levels search fewer previous occurrences
+    /// of each byte, which is faster but usually gives larger output.
+    pub level: u32,
+    /// Avoids copying from the previous byte, so the game can decompress the
+    /// data directly into VRAM. Turning it off gives smaller data that can
+    /// only be decompressed into WRAM.
+    pub vram_safe: bool,
+}
+
+impl Default for CompressOptions {
+    fn default() -> Self {
+        Self {
+            codec: Codec::LZ10,
+            level: MAX_LEVEL,
+            vram_safe: true,
+        }
+    }
+}
 
 impl<'a, const LEN: u32, const MIN: u32, const MAX: u32> CompressWindow<'a, LEN, MIN, MAX> {
-    fn new(input: &'a [u8]) -> Self {
+    fn new(input: &'a [u8], level: u32, vram_safe: bool) -> Self {
         Self {
             input,
             hash: DefaultMap::new(),
             full: false,
-            disp_min: 2,
+            disp_min: if vram_safe { 2 } else { 1 },
             disp_start: 1,
             start: 0,
             stop: 0,
             index: 0,
+            depth: if level >= MAX_LEVEL {
+                usize::MAX
+            } else {
+                4 << level.max(1)
+            },
         }
     }
 
@@ -104,7 +152,10 @@ impl<'a, const LEN: u32, const MIN: u32, const MAX: u32> CompressWindow<'a, LEN,
         let mut counts = vec![];
         let input_byte = self.input[self.index as usize] as _;
         let indices = self.hash.get(&input_byte);
-        for i in indices.iter() {
+        for i in indices
+            .iter()
+            .skip(indices.len().saturating_sub(self.depth))
+        {
             let matchlen = self.match_data(*i, self.index);
             if matchlen >= MIN {
                 let disp = self.index as i32 - *i as i32;
@@ -141,7 +192,7 @@ impl<'a, const LEN: u32, const MIN: u32, const MAX: u32> CompressWindow<'a, LEN,
         let mut matchlen = 0;
         let it = 0..(self.input.len() as u32 - bufstart).min(MAX);
         for i in it {
-            if self.input[(start + (i % LEN)) as usize] == self.input[(bufstart + i) as usize] {
+            if self.input[(start + i) as usize] == self.input[(bufstart + i) as usize] {
                 matchlen += 1;
             } else {
                 break;
@@ -200,25 +251,21 @@ impl<const LEN: u32, const MIN: u32, const MAX: u32> Iterator for Compressor<'_,
     }
 }
 
-pub fn compress_nlz10(input: &[u8], output: &mut impl Write) -> Result<(), Box<dyn Error>> {
+pub fn compress_nlz10(
+    input: &[u8],
+    level: u32,
+    vram_safe: bool,
+    output: &mut impl Write,
+) -> Result<(), Box<dyn Error>> {
+    if input.len() >= 0x1000000 {
+        return Err("LZ10 can't hold data of 16 MiB or more".into());
+    }
     output.write_u32::<LE>(((input.len() as u32) << 8) + 0x10)?;
     let mut length = 0;
-    let window = NLZ10Window::new(input);
+    let window = NLZ10Window::new(input, level, vram_safe);
     for c in &Compressor::new(window).chunks(8) {
         let c = c.collect_vec();
-        let mut flag = 0u8;
-        let mut flagit = c
-            .iter()
-            .map(|x| matches!(x, CompressChunkType::Replace(_, _)));
-        for _ in 0..8 {
-            flag <<= 1;
-            if let Some(c) = flagit.next() {
-                if c {
-                    flag |= 1;
-                }
-            }
-        }
-        output.write_u8(flag)?;
+        output.write_u8(chunk_flag(&c))?;
         length += 1;
 
         for c in c {
@@ -239,81 +286,202 @@ pub fn compress_nlz10(input: &[u8], output: &mut impl Write) -> Result<(), Box<d
             }
         }
     }
-    let padding = if length % 4 == 0 { 0 } else { 4 - length % 4 };
+    write_padding(length, output)
+}
+
+pub fn compress_nlz11(
+    input: &[u8],
+    level: u32,
+    vram_safe: bool,
+    output: &mut impl Write,
+) -> Result<(), Box<dyn Error>> {
+    // A length of 0 in the first word means the length follows it.
+    if input.is_empty() || input.len() >= 0x1000000 {
+        output.write_u32::<LE>(0x11)?;
+        output.write_u32::<LE>(input.len() as u32)?;
+    } else {
+        output.write_u32::<LE>(((input.len() as u32) << 8) + 0x11)?;
+    }
+    let mut length = 0;
+
+    let window = NLZ11Window::new(input, level, vram_safe);
+    for c in &Compressor::new(window).chunks(8) {
+        let c = c.collect_vec();
+        output.write_u8(chunk_flag(&c))?;
+        length += 1;
+        for c in c {
+            match c {
+                CompressChunkType::Replace(mut count, disp) => {
+                    let disp = ((-disp) - 1).unsigned_abs();
+                    debug_assert!(disp <= 0xFFF);
+                    if count <= 1 + 0xF {
+                        count -= 1;
+                        debug_assert!((2..=0xF).contains(&count));
+                        let sh = ((count << 12) | disp) as u16;
+                        output.write_u16::<BE>(sh)?;
+                        length += 2;
+                    } else if count <= 0x11 + 0xFF {
+                        count -= 0x11;
+                        debug_assert!(count <= 0xFF);
+                        let b = (count >> 4) as u8;
+                        let sh = (((count & 0xF) << 12) | disp) as u16;
+                        output.write_u8(b)?;
+                        output.write_u16::<BE>(sh)?;
+                        length += 3;
+                    } else {
+                        count -= 0x111;
+                        debug_assert!(count <= 0xFFFF);
+                        let l = (1 << 28) | (count << 12) | disp;
+                        output.write_u32::<BE>(l)?;
+                        length += 4;
+                    }
+                }
+                CompressChunkType::Data(data) => {
+                    output.write_u8(data)?;
+                    length += 1;
+                }
+            }
+        }
+    }
+    write_padding(length, output)
+}
+
+fn chunk_flag(chunk: &[CompressChunkType]) -> u8 {
+    let mut flag = 0u8;
+    let mut flagit = chunk
+        .iter()
+        .map(|x| matches!(x, CompressChunkType::Replace(_, _)));
+    for _ in 0..8 {
+        flag <<= 1;
+        if let Some(c) = flagit.next() {
+            if c {
+                flag |= 1;
+            }
+        }
+    }
+    flag
+}
+
+fn write_padding(length: usize, output: &mut impl Write) -> Result<(), Box<dyn Error>> {
+    let padding = if length.is_multiple_of(4) {
+        0
+    } else {
+        4 - length % 4
+    };
     for _ in 0..padding {
         output.write_u8(0xFF)?;
     }
-
     Ok(())
 }
 
-// pub fn compress_nlz11(input: &[u8], output: &mut impl Write) -> Result<(), Box<dyn Error>> {
-//     output.write_u32::<LE>(((input.len() as u32) << 8) + 0x11)?;
-//     let mut length = 0;
-
-//     let window = NLZ11Window::new(input);
-//     for c in &Compressor::new(window).chunks(8) {
-//         let c = c.collect_vec();
-//         let mut flag = 0u8;
-//         let mut flagit = c
-//             .iter()
-//             .map(|x| matches!(x, CompressChunkType::Replace(_, _)));
-//         for _ in 0..8 {
-//             flag <<= 1;
-//             if let Some(c) = flagit.next() {
-//                 if c {
-//                     flag |= 1;
-//                 }
-//             }
-//         }
-//         output.write_u8(flag)?;
-//         length += 1;
-//         for c in c {
-//             match c {
-//                 CompressChunkType::Replace(mut count, disp) => {
-//                     let disp = (-disp) - 1;
-//                     debug_assert!((0..=0xFFF).contains(&disp));
-//                     if count <= 1 + 0xF {
-//                         count -= 1;
-//                         debug_assert!((2..=0xF).contains(&count));
-//                         let sh = ((count << 12) & 0xFFFF) as u16 | (disp.abs() & 0xFFFF) as u16;
-//                         output.write_u16::<BE>(sh)?;
-//                         length += 2;
-//                     } else if count <= 0x11 + 0xFF {
-//                         count -= 0x11;
-//                         debug_assert!((0..=0xFF).contains(&count));
-//                         let b = (count >> 4 & 0xFF) as u8;
-//                         let sh = ((count & 0xF) << 12) as u16 | disp.abs() as u16;
-//                         output.write_u8(b)?;
-//                         output.write_u16::<BE>(sh)?;
-//                         length += 3;
-//                     } else if count <= 0x111 + 0xFFFF {
-//                         count -= 0x111;
-//                         debug_assert!((0..=0xFFFF).contains(&count));
-//                         let l = (1 << 28) | (count << 12) | disp.abs() as u32;
-//                         output.write_u32::<BE>(l as _)?;
-//                         length += 4;
-//                     } else {
-//                         panic!();
-//                     }
-//                 }
-//                 CompressChunkType::Data(data) => {
-//                     output.write_u8(data)?;
-//                     length += 1;
-//                 }
-//             }
-//         }
-//     }
-//     let padding = if length % 4 == 0 { 0 } else { 4 - length % 4 };
-//     for _ in 0..padding {
-//         output.write_u8(0xFF)?;
-//     }
-
-//     Ok(())
-// }
-
-pub fn compress_arr(input: &[u8]) -> Result<Vec<u8>, Box<dyn Error>> {
+pub fn compress_with(input: &[u8], options: CompressOptions) -> Result<Vec<u8>, Box<dyn Error>> {
     let mut result = Cursor::new(Vec::new());
-    compress_nlz10(input, &mut result)?;
+    match options.codec {
+        Codec::LZ10 => compress_nlz10(input, options.level, options.vram_safe, &mut result)?,
+        Codec::LZ11 => compress_nlz11(input, options.level, options.vram_safe, &mut result)?,
+    }
     Ok(result.into_inner())
 }
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+
+    use super::*;
+    use crate::nlz;
+
+    fn round_trip(codec: Codec, input: &[u8]) -> Result<Vec<u8>, Box<dyn Error>> {
+        let options = CompressOptions {
+            codec,
+            ..Default::default()
+        };
+        let compressed = compress_with(input, options)?;
+        assert_eq!(compressed.len() % 4, 0);
+        nlz::decompress(&mut Cursor::new(compressed))
+    }
+
+    /// Repeats every byte value, so long matches are found quickly.
+    fn pattern(len: usize) -> Vec<u8> {
+        (0..len).map(|i| i as u8).collect()
+    }
+
+    #[test]
+    fn lz10_round_trips() {
+        for len in [0, 1, 0xFFFFFF] {
+            let input = pattern(len);
+            assert_eq!(round_trip(Codec::LZ10, &input).unwrap(), input);
+        }
+    }
+
+    #[test]
+    fn lz10_rejects_16_mib() {
+        assert!(round_trip(Codec::LZ10, &pattern(0x1000000)).is_err());
+    }
+
+    #[test]
+    fn lz11_round_trips() {
+        for len in [0, 1, 0xFFFFFF, 0x1000000] {
+            let input = pattern(len);
+            assert_eq!(round_trip(Codec::LZ11, &input).unwrap(), input);
+        }
+    }
+
+    #[test]
+    fn lz11_empty_uses_extended_header() {
+        let compressed = compress_with(
+            &[],
+            CompressOptions {
+                codec: Codec::LZ11,
+                ..Default::default()
+            },
+        )
+        .unwrap();
+        assert_eq!(compressed, [0x11, 0, 0, 0, 0, 0, 0, 0]);
+    }
+
+    /// Matches longer than the window must compare the bytes the decoder
+    /// copies, not ones wrapped back by the window length.
+    #[test]
+    fn lz11_matches_past_window_length() {
+        let input = (0..9000)
+            .map(|i| b"ABC"[if i < 4099 { i % 3 } else { (i - 1) % 3 }])
+            .collect::<Vec<_>>();
+        for vram_safe in [true, false] {
+            let options = CompressOptions {
+                codec: Codec::LZ11,
+                vram_safe,
+                ..Default::default()
+            };
+            let compressed = compress_with(&input, options).unwrap();
+            assert_eq!(
+                nlz::decompress(&mut Cursor::new(compressed)).unwrap(),
+                input
+            );
+        }
+    }
+
+    /// Runs of one byte are best copied from the previous byte, which the
+    /// VRAM-safe encoder must not do.
+    #[test]
+    fn vram_safe_output_avoids_previous_byte() {
+        let input = [[b'A'; 40], [b'B'; 40], [b'C'; 40]].concat();
+        for codec in [Codec::LZ10, Codec::LZ11] {
+            for vram_safe in [true, false] {
+                let options = CompressOptions {
+                    codec,
+                    vram_safe,
+                    ..Default::default()
+                };
+                let compressed = compress_with(&input, options).unwrap();
+                assert_eq!(
+                    nlz::is_vram_safe(&mut Cursor::new(&compressed)).unwrap(),
+                    vram_safe
+                );
+                assert_eq!(
+                    nlz::decompress(&mut Cursor::new(compressed)).unwrap(),
+                    input
+                );
+            }
+        }
+    }
+}
@@ -1,24 +1,39 @@
+mod archive;
+mod batch;
+mod font;
+mod glob;
+mod graphics;
+mod hook;
+mod incremental;
 mod lzss;
+mod manifest;
+mod nds;
 mod nlz;
+mod patch;
+mod project;
+mod record;
+mod sprite;
+mod temp;
+#[cfg(test)]
+mod test_util;
+mod text;
+mod translation;
+mod watch;
 
 use std::{
     fs::OpenOptions,
-    io::{Read, Seek, SeekFrom, Write},
-    path::PathBuf,
+    io::{Cursor, Read, Seek, SeekFrom, Write},
+    path::{Path, PathBuf},
+    time::Duration,
 };
 
-use byteorder::*;
-
-#[derive(Debug)]
-struct SubFile {
-    offset: u32,
-    size: u32,
-    compressed: bool,
-}
+use itertools::Itertools;
+use manifest::Compression;
 
 fn main() {
     let args = zigarg::Arguments::new();
-    let slience = args.exist("-s");
+    // Never mix messages into the data when writing to stdout.
+    let slience = args.exist("-s") || args.get_value("-o").map(|x| x.as_str()) == Some("-");
     if !slience {
         println!("Star Force Archive Tool (Rust) v1.0 by SteveXMH (Original by Prof.9)");
     }
@@ -30,9 +45,59 @@ fn main() {
         println!("        -o [path]       Specifies output path.");
         println!("        -x              Unpacks archive to folder. Requires -i and -o.");
         println!("        -p              Packs folder to archive. Requires -i and -o.");
+        println!(
+            "        -d              Decompresses a single LZ10/LZ11 file. Requires -i and -o."
+        );
+        println!("        -z              Compresses a single file. Requires -i and -o.");
         println!("        -eof            Indicates the archive has an EOF subfile entry. Requires -x or -p.");
         println!("        -c              Compress sub files if can be smaller. Requires -p.");
         println!("        --ignore-zero   Skip zero sized sub files when unpacking or add zero size sub files on missing index file when packing. Requires -x or -p.");
+        println!(
+            "        -b              Builds every archive listed in a project file. Requires -i."
+        );
+        println!("        -l              Lists sub files of an archive, or files of a ROM. Requires -i.");
+        println!("        --inject        Replaces a file in a ROM with the input file. Requires -i and -o.");
+        println!("        --check-vram    Reports whether the compressed sub files of an archive are VRAM-safe. Requires -i.");
+        println!("        --codec [lz]    Compression format, \"lz10\" (default) or \"lz11\". Requires -p or -z.");
+        println!("        --level [n]     Compression level from 1 (fastest) to 9 (smallest, default). Requires -p or -z.");
+        println!("        --vram [mode]   \"safe\" (default) to allow decompressing into VRAM, or \"unsafe\" for smaller data. Requires -p or -z.");
+        println!("        --compress-always [pattern]  Always compress sub files matching the pattern. Requires -p.");
+        println!("        --compress-never [pattern]   Never compress sub files matching the pattern. Requires -p.");
+        println!("        --compress-auto [pattern]    Compress sub files matching the pattern if can be smaller. Requires -p.");
+        println!("        --min-savings [n]            Bytes (or percent with \"%\") automatic compression must save. Default is 1. Requires -p.");
+        println!("        --dedupe        Store identical sub files once, sharing their offset in the header, and report the bytes saved. Requires -p.");
+        println!("        --align [n]     Aligns the offset of every sub file after the first one to n bytes. Requires -p.");
+        println!("        --fill [byte]   Byte filling the space left by --align, 0xFF by default. Requires -p.");
+        println!("        --template [path]  Packs the input directory like this archive: the sub files missing from the input are copied from it, and the others get its compression, codec, alignment and order. Requires -p.");
+        println!("        --verify        Check every compressed sub file decompresses back to the input. Default in debug builds, --no-verify to skip. Requires -p or -z.");
+        println!("        --raw           Keep compressed sub files compressed as \"name_XXX.bin.lz\" when unpacking. Requires -x.");
+        println!("        --table [path]  .tbl character table to decode message archives to \"name_XXX.txt\" scripts, and encode them back. Requires -x or -p.");
+        println!("        --export-text   Exports every script of the unpacked archives in the input directory, or in a project file, to a .po or .csv file. Requires -i and -o.");
+        println!("        --translations [path]  .po or .csv file whose translated strings replace the scripts. Requires -p or -b.");
+        println!("        --max-length [n]       Warn about translated strings longer than n bytes. Requires --translations.");
+        println!("        --validate-text Reports the bytes missing from the --table character table in the message archive or script given, or in the scripts and the message archives matching --include of the input directory. Requires -i.");
+        println!("        --export-font   Exports an NFTR font to a JSON file and a PNG glyph sheet with the same name. Requires -i and -o.");
+        println!("        --import-font   Builds an NFTR font from a JSON file and its PNG glyph sheet, warning about characters of the --table without glyph. Requires -i and -o.");
+        println!("        --images        Converts NCLR, NCGR and NSCR sub files to \"name_XXX.nclr.png\", \"name_XXX.ncgr.png\" and \"name_XXX.nscr.png\", \
+                    and NCER and NANR sub files to \"name_XXX.ncer.json\" and \"name_XXX.nanr.json\" with their frames rendered next to them. Requires -x.");
+        println!("        --raw-image [index:format[:width[:palette]]]  Converts a sub file without header to \"name_XXX.pal.png\", \"name_XXX.4bpp.png\" or \"name_XXX.8bpp.png\", \
+                    for the format \"palette\", \"tiles4\" or \"tiles8\", with the width in tiles and the palette sub file. Can be repeated. Requires -x or -p.");
+        println!("        --schema [index:path]  Converts a sub file which is a table of fixed-size records to \"name_XXX.csv\" or \"name_XXX.json\" with the schema file. Can be repeated. Requires -x or -p.");
+        println!("        --hooks [path]  TOML file with [[hook]] tables running external converters on the matching sub files. Requires -x or -p.");
+        println!("        -r              Unpacks every archive in the input directory tree, or packs every unpacked directory. Requires -x or -p.");
+        println!("        --include [pattern]  Only process paths matching the pattern, relative to the input directory. Requires -r or --validate-text.");
+        println!("        --exclude [pattern]  Skip paths matching the pattern, relative to the input directory. Requires -r or --validate-text.");
+        println!("        --incremental   Skip packing archives whose input files didn't change since the last build, if the output still holds the archive. Requires -p.");
+        println!("        --cache [path]  File recording the inputs for --incremental, \".sfarctool-cache.toml\" by default.");
+        println!("        -w              Keep running and pack again the archives whose input files changed. The cache is only written with --incremental, or the incremental setting of a project. Requires -p or -b.");
+        println!("        --interval [ms] How often -w checks for changes, 500 by default.");
+        println!("        --inject-into [rom path]  Also inject the archive packed by -w into a ROM, like \"rom.nds:/path/in/rom.bin\". Not available with -r or -b.");
+        println!("        --make-patch    Makes a patch from the --base file to the input file. Requires -i, -o and --base.");
+        println!("        --apply-patch   Applies the --patch file to the input file. Requires -i, -o and --patch.");
+        println!("        --emit-patch [path]  Also makes a patch from the --base file to the packed archive, or the ROM it went into. Requires -p.");
+        println!("        --base [path]   Original file a patch is made against.");
+        println!("        --patch [path]  Patch file to apply.");
+        println!("        --format [fmt]  Patch format, \"ips\" or \"bps\". Guessed from the patch extension by default, else BPS.");
         println!("        -s              Slience mode. No output.");
         println!("        -v              Toggle verbose mode which will output a lot of message.");
         println!();
@@ -41,296 +106,583 @@ fn main() {
                     \"XXX\" is the subfile number and \"ext\" is any extension \
                     (multiple extensions are allowed. \
                     Any files that do not adhere to this format will be skipped or be writen as zero size sub file when using --ignore-zero option.");
+        println!(
+            "Subfiles with the last extension \".lz\" are stored as is and marked as compressed."
+        );
+        println!("Subfiles ending with \".nclr.png\", \".ncgr.png\", \".nscr.png\", \".pal.png\", \".4bpp.png\" or \".8bpp.png\", \
+                    \".ncer.json\" or \".nanr.json\" are converted back using their original data in \".original\".");
+        println!("Subfiles with the extension \".txt\" are message archive scripts, encoded with the --table character table.");
+        println!("Subfiles with the last extension \".compress\" or \".nocompress\" are always or never compressed, \
+                    this overrides entries in \"manifest.toml\", which override the patterns.");
+        println!();
+        println!("For option -d and -z, \"-\" can be used as path to read from stdin or write to stdout.");
+        println!("For option -x, -l and --check-vram, an archive inside a ROM can be used as \"rom.nds:/path/in/rom.bin\".");
+        println!("For option -p and --inject, the output can be a file inside a ROM in the same way, which is replaced in place. A missing ROM is an error.");
+        println!("For option -r, \"dir/name.bin\" is unpacked to \"dir/name/\" in the output directory and packed back to \"dir/name.bin\", other extensions are kept in the manifest. Files that aren't archives are skipped.");
         return;
     }
     let input = args
         .get_value("-i")
         .cloned()
         .expect("Not selected an input path");
-    let output = args
-        .get_value("-o")
-        .cloned()
-        .expect("Not selected a output path");
+    let output = args.get_value("-o").cloned();
     let unpack = args.exist("-x");
     let pack = args.exist("-p");
+    let decompress = args.exist("-d");
+    let compress_single = args.exist("-z");
+    let check_vram = args.exist("--check-vram");
+    let list = args.exist("-l");
+    let inject = args.exist("--inject");
+    let batch = args.exist("-r");
+    let incremental = args.exist("--incremental");
+    let build = args.exist("-b");
+    let watch = args.exist("-w");
+    let make_patch = args.exist("--make-patch");
+    let apply_patch = args.exist("--apply-patch");
+    let export_text = args.exist("--export-text");
+    let validate_text = args.exist("--validate-text");
+    let export_font = args.exist("--export-font");
+    let import_font = args.exist("--import-font");
     let eof = args.exist("-eof");
     let compress = args.exist("-c");
     let verbose = args.exist("-v");
     let ignore_zero = args.exist("--ignore-zero");
-    if (unpack && pack) || (!unpack && !pack) {
-        println!("Error: Both set pack and unpack mode.");
-        return;
+    let raw = args.exist("--raw");
+    // Checking every compressed stream is slow, so release builds only do it on request.
+    let verify = if cfg!(debug_assertions) {
+        !args.exist("--no-verify")
+    } else {
+        args.exist("--verify")
+    };
+    let mut options = lzss::CompressOptions::default();
+    if let Some(codec) = args.get_value("--codec") {
+        options.codec = lzss::Codec::parse(codec).expect("Unknown compression format");
     }
-    if unpack {
-        if verbose {
-            println!("Unpacking archive");
+    if let Some(level) = args.get_value("--level") {
+        options.level = level.parse().expect("Invalid compression level");
+        if !(1..=lzss::MAX_LEVEL).contains(&options.level) {
+            println!("Error: Compression level must be between 1 and 9.");
+            return;
         }
-        let mut subfiles: Vec<SubFile> = Vec::new();
-        let mut file = OpenOptions::new()
-            .read(true)
-            .open(&input)
-            .expect("Can't open input archive");
-        let file_size = file
-            .metadata()
-            .expect("Can't get metadata of the input archive")
-            .len() as usize;
-        let mut header_end = file_size;
-        let mut pos = 0;
-        let mut max_size = 0;
-        while pos < header_end {
-            let offset = file.read_u32::<LE>().expect("Can't read offset");
-            let size = file.read_u32::<LE>().expect("Can't read size");
-            pos += 8;
-            let subfile = SubFile {
-                offset: offset as _,
-                size: (size & 0x7FFFFFFF) as _,
-                compressed: (size & 0x80000000) != 0,
-            };
-            if verbose {
-                if subfile.compressed {
-                    println!(
-                        "Entry {} at 0x{:08x}, size 0x{:08x}, compressed",
-                        subfiles.len(),
-                        subfile.offset,
-                        subfile.size
-                    );
-                } else {
-                    println!(
-                        "Entry {} at 0x{:08x}, size 0x{:08x}",
-                        subfiles.len(),
-                        subfile.offset,
-                        subfile.size
-                    );
-                }
+    }
+    if let Some(vram) = args.get_value("--vram") {
+        options.vram_safe = match vram.to_ascii_lowercase().as_str() {
+            "safe" => true,
+            "unsafe" => false,
+            _ => {
+                println!("Error: --vram must be \"safe\" or \"unsafe\".");
+                return;
             }
-            subfiles.push(subfile);
-            header_end = (offset as usize).min(header_end);
-            max_size = (size as usize).max(max_size);
+        };
+    }
+    let options = options;
+    let table = args
+        .get_value("--table")
+        .map(|x| text::CharTable::load(Path::new(x)).expect("Can't read character table"));
+    let mut entries: Vec<_> = get_values(&args, "--raw-image")
+        .iter()
+        .map(|x| {
+            manifest::EntryManifest::parse_raw_image(x)
+                .expect("Invalid raw image, expecting \"index:format[:width[:palette]]\"")
+        })
+        .collect();
+    entries.extend(get_values(&args, "--schema").iter().map(|x| {
+        manifest::EntryManifest::parse_schema(x).expect("Invalid schema, expecting \"index:path\"")
+    }));
+    let hooks = args
+        .get_value("--hooks")
+        .map(|x| hook::load(Path::new(x)).expect("Can't read hooks"))
+        .unwrap_or_default();
+    let unpack_options = archive::UnpackOptions {
+        eof,
+        ignore_zero,
+        raw,
+        verbose,
+        table: table.clone(),
+        images: args.exist("--images"),
+        entries: entries.clone(),
+        hooks: hooks.clone(),
+    };
+    let mut patterns = Vec::new();
+    for (i, arg) in args.0.iter().enumerate() {
+        let policy = match arg.to_ascii_lowercase().as_str() {
+            "--compress-auto" => Compression::Auto,
+            "--compress-always" => Compression::Always,
+            "--compress-never" => Compression::Never,
+            _ => continue,
+        };
+        let pattern = args
+            .get(i + 1)
+            .expect("Missing pattern for compression policy");
+        patterns.push((pattern.to_owned(), policy));
+    }
+    let pack_options = archive::PackOptions {
+        eof,
+        compress,
+        ignore_zero,
+        dedupe: args.exist("--dedupe"),
+        align: args
+            .get_value("--align")
+            .map(|x| x.parse().expect("Invalid alignment")),
+        fill: args
+            .get_value("--fill")
+            .map(|x| archive::parse_byte(x).expect("Invalid fill byte")),
+        template: args.get_value("--template").cloned(),
+        verify,
+        verbose,
+        slience,
+        compress_options: options,
+        patterns,
+        min_savings: archive::MinSavings::parse(
+            args.get_value("--min-savings").map_or("1", |x| x.as_str()),
+        )
+        .expect("Invalid minimum savings"),
+        table,
+        translations: args.get_value("--translations").map(|x| {
+            translation::Translations::load(Path::new(x)).expect("Can't read translations")
+        }),
+        max_length: args
+            .get_value("--max-length")
+            .map(|x| x.parse().expect("Invalid maximum length")),
+        text_root: None,
+        entries,
+        hooks,
+    };
+    let filters = batch::Filters {
+        include: get_values(&args, "--include"),
+        exclude: get_values(&args, "--exclude"),
+    };
+    let modes = [
+        unpack,
+        pack,
+        decompress,
+        compress_single,
+        check_vram,
+        list,
+        inject,
+        build,
+        make_patch,
+        apply_patch,
+        export_text,
+        validate_text,
+        export_font,
+        import_font,
+    ];
+    match modes.iter().filter(|x| **x).count() {
+        0 => {
+            println!(
+                "Error: No mode selected, use one of -x, -p, -d, -z, -l, -b, --check-vram, --inject, --make-patch, --apply-patch, --export-text, --validate-text, --export-font or --import-font."
+            );
+            return;
         }
-        if pos != header_end
-            || (eof
-                && (subfiles.last().expect("").size > 0 || subfiles.last().expect("").compressed))
-        {
-            println!("Invalid archive file header.");
+        1 => {}
+        _ => {
+            println!(
+                "Error: Only one of -x, -p, -d, -z, -l, -b, --check-vram, --inject, --make-patch, --apply-patch, --export-text, --validate-text, --export-font or --import-font can be set."
+            );
             return;
         }
-        if eof {
-            subfiles.pop();
+    }
+    let output = if check_vram || list || build || validate_text {
+        String::new()
+    } else {
+        output.expect("Not selected a output path")
+    };
+    if list && nds::split_rom_path(&input).is_none() && nds::is_rom(&input) {
+        let mut rom = OpenOptions::new()
+            .read(true)
+            .open(&input)
+            .expect("Can't open input ROM");
+        let fs = nds::NitroFs::read(&mut rom).expect("Can't read ROM file system");
+        for file in fs.files {
+            println!(
+                "File {:4} at 0x{:08x}, size 0x{:08x}  {}",
+                file.id,
+                file.start,
+                file.end - file.start,
+                file.path
+            );
         }
-        let subfile_len = subfiles.len() - 1;
-        let zero_subfile_amounts = subfiles.iter().filter(|x| x.size == 0).count();
-        let output_basename = {
-            if let Some(sep) = input.as_str().rfind(['/', '\\']) {
-                if let Some(dot) = input.as_str().rfind('.') {
-                    input.as_str()[sep + 1..dot].to_owned()
-                } else {
-                    input.as_str()[sep + 1..].to_owned()
-                }
-            } else {
-                input.to_owned()
-            }
+    } else if list {
+        let data = nds::read_archive(&input).expect("Can't open input archive");
+        let file_size = data.len();
+        let Ok(subfiles) = archive::read_header(&mut Cursor::new(data), file_size) else {
+            println!("Invalid archive file header.");
+            return;
         };
-        let max_size = subfiles.iter().map(|x| x.size).max().unwrap_or_default();
-        if verbose {
-            println!("Largest entry size: 0x{:08x}", max_size);
-        }
-        let mut buf = vec![0; max_size as _];
-        let padding = (subfiles.len() - 1).to_string().len();
-        let to_padded_string = |num: u32| -> String {
-            let num = num.to_string();
-            let mut padding = "0".to_string().repeat(padding - num.len());
-            padding.push_str(num.as_str());
-            padding
+        archive::print_subfiles(&subfiles);
+    } else if check_vram {
+        let mut file = Cursor::new(nds::read_archive(&input).expect("Can't open input archive"));
+        let file_size = file.get_ref().len();
+        let Ok(subfiles) = archive::read_header(&mut file, file_size) else {
+            println!("Invalid archive file header.");
+            return;
         };
-        let output_basename = output_basename;
-        std::fs::create_dir_all(&output).expect("Can't create output directory");
-        for (i, subfile) in subfiles.into_iter().enumerate() {
-            let subfile = subfile;
-            if i == subfile_len
-                && subfile.offset == file_size as _
-                && subfile.size == 0xFFFF
-                && !subfile.compressed
-            {
-                continue;
-            }
-            if ignore_zero && subfile.size == 0 {
-                println!("Warning: Entry {} is empty, skipped.", i);
-                if i == subfile_len - 1 {
-                    println!("Tip: It seems like there is a zero-sized subfile at the end of the archive, maybe it is a end-of-file mark.");
-                    println!("     If you think that so, you can use -eof option to skip writing this subfile.");
-                }
-                continue;
-            }
-            let output_name = format!("{}_{}.bin", output_basename, to_padded_string(i as _));
-            let mut output = std::path::PathBuf::from(&output);
-            output.push(output_name);
-            let mut output = std::fs::OpenOptions::new()
-                .write(true)
-                .truncate(true)
-                .create(true)
-                .open(output)
-                .expect("Can't write subfile");
+        let mut compressed = 0;
+        let mut unsafe_entries = Vec::new();
+        for (i, subfile) in subfiles.iter().enumerate().filter(|x| x.1.compressed) {
             file.seek(SeekFrom::Start(subfile.offset as _))
                 .expect("Can't seek file");
-            if subfile.compressed {
-                if verbose {
-                    println!("Decompressing entry {}", i);
-                }
-                let decompressed = nlz::decompress(&mut file).expect("Can't decompress file");
-                output
-                    .write_all(&decompressed)
-                    .expect("Can't write subfile");
-            } else {
-                if verbose {
-                    println!("Unpacking entry {} with size {}", i, subfile.size);
-                }
-                file.read_exact(&mut buf[..subfile.size as usize])
-                    .expect("Can't read file");
-                output
-                    .write_all(&buf[..subfile.size as usize])
-                    .expect("Can't write subfile");
+            let safe = nlz::is_vram_safe(&mut file).expect("Can't decompress file");
+            if verbose {
+                println!(
+                    "Entry {} is {}",
+                    i,
+                    if safe { "VRAM-safe" } else { "not VRAM-safe" }
+                );
+            }
+            compressed += 1;
+            if !safe {
+                unsafe_entries.push(i);
             }
         }
-        if zero_subfile_amounts > 0 {
+        if unsafe_entries.is_empty() {
+            println!("All {} compressed entries are VRAM-safe.", compressed);
+        } else {
             println!(
-                "Tip: There {} zero-sized subfile{} in the archive.",
-                if zero_subfile_amounts > 1 {
-                    "are some"
-                } else {
-                    "is a"
-                },
-                if zero_subfile_amounts > 1 { "s" } else { "" }
+                "{} of {} compressed entries are not VRAM-safe: {}",
+                unsafe_entries.len(),
+                compressed,
+                unsafe_entries.iter().join(", ")
             );
-            println!("     If you are using tools like TextPet, it may come to an error when reading text archive.");
-            println!("     To ignore zero file writing, use --ignore-zero option to skip writing zero files.");
+            println!("Tip: Use --vram unsafe when packing to get the same behavior.");
         }
-    } else if pack {
-        // Parse files and sort them.
-        let mut input_dir = std::fs::read_dir(&input).expect("Can't read input directory");
-        let mut files = Vec::new();
-        while let Some(Ok(entry)) = input_dir.next() {
-            if entry.path().is_file() {
-                let file_name = entry.file_name();
-                let file_name = file_name.to_string_lossy();
-                let dot = file_name.rfind('.');
-                let underline = file_name.rfind('_');
-                if let (Some(dot), Some(underline)) = (dot, underline) {
-                    if let Ok(index) = file_name[underline + 1..dot].parse::<usize>() {
-                        files.push((index, entry.path()))
-                    }
-                }
+    } else if decompress || compress_single {
+        let data = read_input(&input).expect("Can't read input file");
+        let input_len = data.len();
+        let data = if decompress {
+            nlz::decompress(&mut Cursor::new(data)).expect("Can't decompress file")
+        } else {
+            let compressed = lzss::compress_with(&data, options).expect("Can't compress file");
+            if verify && !archive::decompresses_to(&compressed, &data) {
+                panic!("Compressed data doesn't decompress back to the input");
             }
+            compressed
+        };
+        if verbose && !slience {
+            println!("{} bytes -> {} bytes", input_len, data.len());
         }
-        files.sort_by(|a, b| a.0.cmp(&b.0));
-        if !files.is_empty() {
-            let max_index = files.last().map(|x| x.0).unwrap();
-            let mut i = 0;
-            if files.len() < max_index + 1 || ignore_zero {
-                while files.len() < max_index + 1 {
-                    let file = &files[i];
-                    if i != file.0 {
-                        files.insert(i, (i, PathBuf::default()));
-                        if !slience {
-                            println!("Warning: Missing file {}, using zero size file", i);
-                        }
-                    }
-                    i += 1;
+        write_output(&output, &data).expect("Can't write output file");
+    } else if unpack && batch {
+        batch::unpack_tree(
+            Path::new(&input),
+            Path::new(&output),
+            &filters,
+            &unpack_options,
+        )
+        .print(slience);
+    } else if unpack {
+        if verbose {
+            println!("Unpacking archive");
+        }
+        let data = nds::read_archive(&input).expect("Can't open input archive");
+        let basename = Path::new(&input)
+            .file_stem()
+            .map(|x| x.to_string_lossy().into_owned())
+            .unwrap_or_default();
+        if let Err(err) = archive::unpack(&data, &basename, Path::new(&output), &unpack_options) {
+            println!("Error: {}", err);
+        }
+    } else if (pack || build) && watch {
+        let interval = Duration::from_millis(
+            args.get_value("--interval")
+                .map_or(Ok(500), |x| x.parse())
+                .expect("Invalid interval"),
+        );
+        let inject_into = args.get_value("--inject-into").cloned();
+        if inject_into.is_some() && (build || batch) {
+            println!(
+                "Error: --inject-into only works when packing a single archive, not with -r or -b."
+            );
+            return;
+        }
+        let mut dirs = vec![PathBuf::from(&input)];
+        // Archives whose inputs didn't change since the last pack are skipped,
+        // the cache file is only used with --incremental.
+        let cache_path = args
+            .get_value("--cache")
+            .map_or(incremental::DEFAULT_CACHE_NAME, |x| x.as_str());
+        let mut cache = if incremental {
+            incremental::BuildCache::load(Path::new(cache_path)).expect("Can't read cache")
+        } else {
+            incremental::BuildCache::default()
+        };
+        let mut project = None;
+        if build {
+            let path = Path::new(&input);
+            let loaded = project::Project::load(path).expect("Can't read project file");
+            let base = path.parent().unwrap_or(Path::new("")).to_owned();
+            // Fonts are watched through the directory of their JSON file.
+            dirs = loaded
+                .archives
+                .iter()
+                .map(|x| base.join(&x.input))
+                .chain(
+                    loaded
+                        .fonts
+                        .iter()
+                        .filter_map(|x| base.join(&x.input).parent().map(|x| x.to_owned())),
+                )
+                .collect();
+            project = Some((loaded, base));
+        }
+        let mut repack = || {
+            if let Some((project, base)) = &project {
+                // Projects with their own cache file keep using it.
+                if project.incremental {
+                    project.build(base, &pack_options).print(slience);
+                } else {
+                    project
+                        .build_cached(base, &pack_options, Some(&mut cache))
+                        .print(slience);
                 }
+                return;
+            }
+            if batch {
+                batch::pack_tree(
+                    Path::new(&input),
+                    Path::new(&output),
+                    &filters,
+                    &pack_options,
+                    Some(&mut cache),
+                )
+                .print(slience);
             } else {
-                println!(
-                    "Incorrect subfile amount, expecting {} files but got {} subfiles",
-                    max_index + 1,
-                    files.len()
+                let result = batch::pack_cached(
+                    Path::new(&input),
+                    Path::new(&output),
+                    &pack_options,
+                    Some(&mut cache),
+                    |data| {
+                        nds::write_archive(&output, data)?;
+                        match &inject_into {
+                            Some(rom) => nds::write_archive(rom, data),
+                            None => Ok(()),
+                        }
+                    },
                 );
-                println!("List of missing sub files:");
-                for i in 0..max_index + 1 {
-                    if !files.iter().any(|x| x.0 == i) {
-                        println!("Missing sub file {}", i);
-                    }
+                match result {
+                    Ok(true) if !slience => println!("Packed {}", output),
+                    Ok(false) if !slience => println!("Archive is up to date."),
+                    Ok(_) => {}
+                    Err(err) => println!("Error: {}", err),
+                }
+            }
+            if incremental {
+                if let Err(err) = cache.save(Path::new(cache_path)) {
+                    println!("Error: Can't write cache: {}", err);
                 }
-                println!("Tip: If it's not an error, use --ignore-zero to ignore missing files and write zero size sub file.");
-                return;
             }
+        };
+        repack();
+        if !slience {
+            println!("Watching {} for changes, press Ctrl+C to stop.", input);
         }
-        let header_size = if eof {
-            (files.len() + 2) * 8
+        watch::watch(&dirs, interval, repack);
+    } else if pack {
+        let cache_path = args
+            .get_value("--cache")
+            .map_or(incremental::DEFAULT_CACHE_NAME, |x| x.as_str());
+        let mut cache = if incremental {
+            Some(incremental::BuildCache::load(Path::new(cache_path)).expect("Can't read cache"))
         } else {
-            (files.len() + 1) * 8
+            None
         };
-        let mut offset = header_size as u32;
-        let mut file = OpenOptions::new()
-            .write(true)
-            .truncate(true)
-            .create(true)
-            .open(&output)
-            .expect("Can't open output file");
-        let mut file_size = header_size;
-        for _ in 0..files.len() + 1 {
-            file.write_u64::<LE>(0).expect("Can't fill header");
+        if batch {
+            batch::pack_tree(
+                Path::new(&input),
+                Path::new(&output),
+                &filters,
+                &pack_options,
+                cache.as_mut(),
+            )
+            .print(slience);
+        } else {
+            let result = batch::pack_cached(
+                Path::new(&input),
+                Path::new(&output),
+                &pack_options,
+                cache.as_mut(),
+                |data| nds::write_archive(&output, data),
+            );
+            match result {
+                Ok(false) if !slience => println!("Archive is up to date."),
+                Ok(_) => {}
+                Err(err) => println!("Error: {}", err),
+            }
+            if let Some(patch_path) = args.get_value("--emit-patch") {
+                let base = args
+                    .get_value("--base")
+                    .expect("Not selected a base file for the patch");
+                // Patch the ROM itself when the archive went into one.
+                let target = nds::split_rom_path(&output).map_or(output.as_str(), |x| x.0);
+                write_patch(base, target, patch_path, args.get_value("--format"))
+                    .expect("Can't write patch");
+            }
         }
-        let mut buf = Vec::new();
-        let files = files
-            .into_iter()
-            .map(|(_i, entry)| {
-                if entry == PathBuf::default() {
-                    (false, vec![], 0)
-                } else {
-                    let mut subfile = OpenOptions::new()
-                        .read(true)
-                        .open(&entry)
-                        .expect("Can't open subfile");
-                    buf.clear();
-                    subfile.read_to_end(&mut buf).expect("Can't read subfile");
-                    let uncompressed_size = buf.len();
-                    if compress {
-                        if let Ok(compressed_data) = lzss::compress_arr(&buf) {
-                            if uncompressed_size <= compressed_data.len() {
-                                file_size += uncompressed_size;
-                                (false, buf.to_owned(), uncompressed_size)
-                            } else {
-                                file_size += compressed_data.len();
-                                (true, compressed_data, uncompressed_size)
-                            }
-                        } else {
-                            (false, buf.to_owned(), uncompressed_size)
-                        }
-                    } else {
-                        (false, buf.to_owned(), uncompressed_size)
-                    }
-                }
-            })
-            .collect::<Vec<_>>();
-        file.seek(SeekFrom::Start(0))
-            .expect("Can't seek file to start");
-        for (compressed, data, uncompressed_size) in &files {
-            if verbose {
+        if let Some(cache) = cache {
+            cache
+                .save(Path::new(cache_path))
+                .expect("Can't write cache");
+        }
+    } else if build {
+        let path = Path::new(&input);
+        let project = project::Project::load(path).expect("Can't read project file");
+        project
+            .build(path.parent().unwrap_or(Path::new("")), &pack_options)
+            .print(slience);
+    } else if make_patch {
+        let base = args
+            .get_value("--base")
+            .expect("Not selected a base file for the patch");
+        write_patch(base, &input, &output, args.get_value("--format")).expect("Can't write patch");
+    } else if apply_patch {
+        let patch = std::fs::read(
+            args.get_value("--patch")
+                .expect("Not selected a patch file"),
+        )
+        .expect("Can't read patch");
+        let source = std::fs::read(&input).expect("Can't read input file");
+        let target = patch::apply(&patch, &source).expect("Can't apply patch");
+        std::fs::write(&output, target).expect("Can't write output file");
+    } else if export_text {
+        // A project file exports the text of all its archives.
+        let path = Path::new(&input);
+        let (roots, base) = if path.is_file() {
+            let project = project::Project::load(path).expect("Can't read project file");
+            let base = path.parent().unwrap_or(Path::new(""));
+            let roots = project
+                .archives
+                .iter()
+                .map(|x| base.join(&x.input))
+                .collect();
+            (roots, base)
+        } else {
+            (vec![path.to_owned()], path)
+        };
+        let mut entries = translation::collect(&roots, base).expect("Can't read text");
+        translation::export(&mut entries, Path::new(&output)).expect("Can't write translations");
+        if !slience {
+            println!("Exported {} strings.", entries.len());
+        }
+    } else if validate_text {
+        let Some(table) = &pack_options.table else {
+            println!("Error: --validate-text requires --table.");
+            return;
+        };
+        let validation =
+            text::validate(Path::new(&input), &filters, table).expect("Can't read input");
+        let mut counts = std::collections::BTreeMap::new();
+        for (file, x) in &validation.unmapped {
+            *counts.entry(x.byte).or_insert(0) += 1;
+            match x.offset {
+                Some(offset) => println!(
+                    "{}: script {} at 0x{:04x}: unmapped byte {:02X}",
+                    file.display(),
+                    x.script,
+                    offset,
+                    x.byte
+                ),
+                None => println!(
+                    "{}: script {}: unmapped byte {:02X}",
+                    file.display(),
+                    x.script,
+                    x.byte
+                ),
+            }
+        }
+        for (file, err) in &validation.skipped {
+            println!("Skipped {}: {}", file.display(), err);
+        }
+        let checked = validation.checked;
+        if counts.is_empty() {
+            println!("No unmapped bytes in {} message archives.", checked);
+        } else {
+            println!(
+                "{} unmapped bytes in {} message archives: {}",
+                counts.values().sum::<usize>(),
+                checked,
+                counts
+                    .iter()
+                    .map(|(byte, count)| format!("{:02X} x{}", byte, count))
+                    .join(", ")
+            );
+        }
+    } else if export_font {
+        let data = nds::read_archive(&input).expect("Can't read input font");
+        let same = font::export_files(&data, Path::new(&output)).expect("Can't export font");
+        if !same && !slience {
+            println!("Warning: The font doesn't rebuild to the same data, check it before replacing the original.");
+        }
+    } else if import_font {
+        let (json, data) = font::import_files(Path::new(&input)).expect("Can't build font");
+        if let Some(table) = &pack_options.table {
+            let missing = font::missing_chars(&json, table);
+            if !missing.is_empty() && !slience {
                 println!(
-                    "Subfile {} bytes -> {} bytes",
-                    data.len(),
-                    uncompressed_size
+                    "Warning: {} characters of the table have no glyph: {}",
+                    missing.len(),
+                    missing.iter().collect::<String>()
                 );
             }
-            file.write_u32::<LE>(offset)
-                .expect("Can't write file size at end of entries");
-            file.write_u32::<LE>(
-                (*uncompressed_size as u32 & 0x7FFFFFFF) | if *compressed { 0x80000000 } else { 0 },
-            )
-            .expect("Can't write file size at end of entries");
-            offset += data.len() as u32;
-        }
-        if eof {
-            file.write_u32::<LE>(file_size as _)
-                .expect("Can't write file size at end of entries");
-            file.write_u32::<LE>(0)
-                .expect("Can't write file size at end of entries");
         }
-        file.write_u32::<LE>(file_size as _)
-            .expect("Can't write file size at end of entries");
-        file.write_u32::<LE>(0xFFFF)
-            .expect("Can't write file size at end of entries");
-        for (_, data, _) in files {
-            file.write_all(&data)
-                .expect("Can't write subfile to archive")
+        nds::write_archive(&output, &data).expect("Can't write output font");
+    } else if inject {
+        let data = std::fs::read(&input).expect("Can't read input file");
+        if nds::split_rom_path(&output).is_none() {
+            println!("Error: Output must be a file in a ROM, like \"rom.nds:/path/in/rom.bin\".");
+            return;
         }
+        nds::write_archive(&output, &data).expect("Can't inject file into ROM");
+    }
+}
+
+/// Writes a patch turning the base file into the target file, in the given
+/// format or the one matching the extension of the patch.
+fn write_patch(
+    base: &str,
+    target: &str,
+    patch_path: &str,
+    format: Option<&String>,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let format = match format {
+        Some(format) => patch::PatchFormat::parse(format).ok_or("Unknown patch format")?,
+        None => patch::PatchFormat::from_path(patch_path).unwrap_or(patch::PatchFormat::Bps),
+    };
+    let patch = patch::make(format, &std::fs::read(base)?, &std::fs::read(target)?)?;
+    Ok(std::fs::write(patch_path, patch)?)
+}
+
+/// Collects the values of an option which can be given several times.
+fn get_values(args: &zigarg::Arguments, flag: &str) -> Vec<String> {
+    args.0
+        .windows(2)
+        .filter(|x| x[0].eq_ignore_ascii_case(flag))
+        .map(|x| x[1].to_owned())
+        .collect()
+}
+
+/// Reads a whole file, or stdin when the path is "-".
+fn read_input(path: &str) -> std::io::Result<Vec<u8>> {
+    let mut data = Vec::new();
+    if path == "-" {
+        std::io::stdin().lock().read_to_end(&mut data)?;
+    } else {
+        OpenOptions::new()
+            .read(true)
+            .open(path)?
+            .read_to_end(&mut data)?;
+    }
+    Ok(data)
+}
+
+/// Writes a whole file, or stdout when the path is "-".
+fn write_output(path: &str, data: &[u8]) -> std::io::Result<()> {
+    if path == "-" {
+        let mut stdout = std::io::stdout().lock();
+        stdout.write_all(data)?;
+        stdout.flush()
+    } else {
+        std::fs::write(path, data)
     }
 }
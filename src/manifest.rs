@@ -0,0 +1,174 @@
+//! Optional `manifest.toml` placed in an unpacked archive directory, holding
+//! per-entry settings that can't be expressed by the subfile names.
+
+use std::{error::Error, path::Path};
+
+use serde::{Deserialize, Serialize};
+
+use crate::hook::{self, Hook};
+
+pub const MANIFEST_NAME: &str = "manifest.toml";
+
+#[derive(Debug, Default, Clone, Deserialize, Serialize)]
+pub struct Manifest {
+    /// Extension of the archive file when it isn't "bin", found when
+    /// unpacking a directory tree.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub extension: Option<String>,
+    /// Alignment of the subfile offsets, found when unpacking.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub align: Option<usize>,
+    /// Byte filling the space left by the alignment.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub fill: Option<u8>,
+    #[serde(default, rename = "entry", skip_serializing_if = "Vec::is_empty")]
+    pub entries: Vec<EntryManifest>,
+    /// External converters, tried before the ones given on the command line.
+    #[serde(default, rename = "hook", skip_serializing_if = "Vec::is_empty")]
+    pub hooks: Vec<Hook>,
+}
+
+#[derive(Debug, Default, Clone, Deserialize, Serialize)]
+pub struct EntryManifest {
+    pub index: usize,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub compression: Option<Compression>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub vram_safe: Option<bool>,
+    /// Palette entry used by graphics, instead of the closest one.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub palette: Option<usize>,
+    /// Character entry used by a screen, instead of the closest one.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub tiles: Option<usize>,
+    /// Cell entry used by animations, instead of the closest one.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub cells: Option<usize>,
+    /// Graphics format of an entry without a Nitro header.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub format: Option<RawFormat>,
+    /// Width in tiles of raw tile data.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub width: Option<usize>,
+    /// Schema of a record table, relative to the unpacked directory.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub schema: Option<String>,
+    /// Earlier entry whose data this one shares in the archive.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub shared: Option<usize>,
+}
+
+impl EntryManifest {
+    /// Parses a raw graphics declaration as "index:format[:width[:palette]]".
+    pub fn parse_raw_image(value: &str) -> Option<Self> {
+        let mut parts = value.split(':');
+        let index = parts.next()?.trim().parse().ok()?;
+        let format = RawFormat::parse(parts.next()?)?;
+        let mut number = || -> Option<Option<usize>> {
+            match parts.next().map(str::trim) {
+                None | Some("") => Some(None),
+                Some(x) => x.parse().ok().map(Some),
+            }
+        };
+        let (width, palette) = (number()?, number()?);
+        if parts.next().is_some() {
+            return None;
+        }
+        Some(Self {
+            index,
+            format: Some(format),
+            width,
+            palette,
+            ..Default::default()
+        })
+    }
+
+    /// Parses a record table declaration as "index:path", keeping the path
+    /// absolute so it still resolves from the unpacked directory.
+    pub fn parse_schema(value: &str) -> Option<Self> {
+        let (index, path) = value.split_once(':')?;
+        let path = std::path::absolute(path.trim()).ok()?;
+        Some(Self {
+            index: index.trim().parse().ok()?,
+            schema: Some(path.to_string_lossy().into_owned()),
+            ..Default::default()
+        })
+    }
+}
+
+/// Headerless graphics data.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum RawFormat {
+    /// BGR555 colors.
+    Palette,
+    /// 4bpp 8x8 tiles.
+    Tiles4,
+    /// 8bpp 8x8 tiles.
+    Tiles8,
+}
+
+impl RawFormat {
+    pub fn parse(value: &str) -> Option<Self> {
+        match value.trim().to_ascii_lowercase().as_str() {
+            "palette" => Some(Self::Palette),
+            "tiles4" => Some(Self::Tiles4),
+            "tiles8" => Some(Self::Tiles8),
+            _ => None,
+        }
+    }
+}
+
+/// Whether a subfile gets compressed on pack.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Compression {
+    /// Compress only if it saves at least the configured amount of bytes.
+    Auto,
+    Always,
+    Never,
+}
+
+impl Manifest {
+    /// Loads the manifest in the directory, or an empty one if there is none.
+    pub fn load(dir: &Path) -> Result<Self, Box<dyn Error>> {
+        let path = dir.join(MANIFEST_NAME);
+        if !path.is_file() {
+            return Ok(Self::default());
+        }
+        let mut manifest: Self = toml::from_str(&std::fs::read_to_string(path)?)?;
+        manifest.hooks = hook::in_dir(manifest.hooks, dir);
+        Ok(manifest)
+    }
+
+    pub fn save(&self, dir: &Path) -> Result<(), Box<dyn Error>> {
+        Ok(std::fs::write(
+            dir.join(MANIFEST_NAME),
+            toml::to_string(self)?,
+        )?)
+    }
+
+    pub fn entry(&self, index: usize) -> Option<&EntryManifest> {
+        self.entries.iter().find(|x| x.index == index)
+    }
+
+    /// Adds the settings of the given entries, replacing the ones they set.
+    pub fn merge(&mut self, entries: &[EntryManifest]) {
+        for entry in entries {
+            let Some(existing) = self.entries.iter_mut().find(|x| x.index == entry.index) else {
+                self.entries.push(entry.clone());
+                continue;
+            };
+            existing.compression = entry.compression.or(existing.compression);
+            existing.vram_safe = entry.vram_safe.or(existing.vram_safe);
+            existing.palette = entry.palette.or(existing.palette);
+            existing.tiles = entry.tiles.or(existing.tiles);
+            existing.cells = entry.cells.or(existing.cells);
+            existing.format = entry.format.or(existing.format);
+            existing.width = entry.width.or(existing.width);
+            existing.schema = entry.schema.clone().or(existing.schema.take());
+            existing.shared = entry.shared.or(existing.shared);
+        }
+        self.entries.sort_by_key(|x| x.index);
+    }
+}
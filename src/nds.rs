@@ -0,0 +1,406 @@
+//! Nintendo DS ROM file system (NitroFS), made of the file name table (FNT)
+//! describing the directory tree and the file allocation table (FAT) holding
+//! the start and end offset of every file in the ROM.
+
+use std::{
+    error::Error,
+    fmt::{Debug, Display},
+    fs::OpenOptions,
+    io::{Read, Seek, SeekFrom, Write},
+    path::Path,
+};
+
+use byteorder::*;
+
+#[derive(Debug)]
+pub struct NitroFile {
+    pub id: u16,
+    /// Full path from the root, like "/data/msg/mess_0001.bin".
+    pub path: String,
+    pub start: u32,
+    pub end: u32,
+}
+
+#[derive(Debug)]
+pub struct NitroFs {
+    pub fat_offset: u32,
+    /// Start and end of every FAT entry, including overlays.
+    pub fat: Vec<(u32, u32)>,
+    /// Files in FNT order. Overlays and other unnamed FAT entries are left out.
+    pub files: Vec<NitroFile>,
+}
+
+pub struct FileNotFoundError(pub String);
+
+impl Debug for FileNotFoundError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "FileNotFoundError({:?})", self.0)
+    }
+}
+
+impl Display for FileNotFoundError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "File {} not found in ROM", self.0)
+    }
+}
+
+impl Error for FileNotFoundError {}
+
+impl NitroFs {
+    pub fn read(rom: &mut (impl Read + Seek)) -> Result<Self, Box<dyn Error>> {
+        rom.seek(SeekFrom::Start(0x40))?;
+        let fnt_offset = rom.read_u32::<LE>()?;
+        let _fnt_size = rom.read_u32::<LE>()?;
+        let fat_offset = rom.read_u32::<LE>()?;
+        let fat_size = rom.read_u32::<LE>()?;
+
+        rom.seek(SeekFrom::Start(fat_offset as _))?;
+        let mut fat = Vec::with_capacity(fat_size as usize / 8);
+        for _ in 0..fat_size / 8 {
+            let start = rom.read_u32::<LE>()?;
+            let end = rom.read_u32::<LE>()?;
+            fat.push((start, end));
+        }
+
+        // The root entry of the main table holds the directory count instead
+        // of a parent id.
+        rom.seek(SeekFrom::Start(fnt_offset as u64 + 6))?;
+        let dir_count = rom.read_u16::<LE>()?;
+        let mut dirs = Vec::with_capacity(dir_count as _);
+        rom.seek(SeekFrom::Start(fnt_offset as _))?;
+        for _ in 0..dir_count {
+            let subtable = rom.read_u32::<LE>()?;
+            let first_id = rom.read_u16::<LE>()?;
+            let _parent = rom.read_u16::<LE>()?;
+            dirs.push((subtable, first_id));
+        }
+
+        let mut files = Vec::new();
+        let mut stack = vec![(0usize, String::new())];
+        while let Some((dir, prefix)) = stack.pop() {
+            let (subtable, mut id) = *dirs.get(dir).ok_or("Invalid directory id in FNT")?;
+            rom.seek(SeekFrom::Start(fnt_offset as u64 + subtable as u64))?;
+            loop {
+                let kind = rom.read_u8()?;
+                if kind == 0 {
+                    break;
+                }
+                let mut name = vec![0; (kind & 0x7F) as usize];
+                rom.read_exact(&mut name)?;
+                let path = format!("{}/{}", prefix, String::from_utf8_lossy(&name));
+                if kind & 0x80 != 0 {
+                    let dir_id = rom.read_u16::<LE>()? & 0xFFF;
+                    stack.push((dir_id as usize, path));
+                } else {
+                    let (start, end) = *fat.get(id as usize).ok_or("Invalid file id in FNT")?;
+                    files.push(NitroFile {
+                        id,
+                        path,
+                        start,
+                        end,
+                    });
+                    id += 1;
+                }
+            }
+        }
+        files.sort_by_key(|x| x.id);
+
+        Ok(Self {
+            fat_offset,
+            fat,
+            files,
+        })
+    }
+
+    /// Finds a file by its path, the leading '/' is optional.
+    pub fn find(&self, path: &str) -> Result<&NitroFile, Box<dyn Error>> {
+        let path = path.replace('\\', "/");
+        let path = path.trim_start_matches('/');
+        self.files
+            .iter()
+            .find(|x| &x.path[1..] == path)
+            .ok_or_else(|| FileNotFoundError(path.to_owned()).into())
+    }
+}
+
+impl NitroFile {
+    pub fn read(&self, rom: &mut (impl Read + Seek)) -> Result<Vec<u8>, Box<dyn Error>> {
+        let mut data = vec![0; self.end.saturating_sub(self.start) as usize];
+        rom.seek(SeekFrom::Start(self.start as _))?;
+        rom.read_exact(&mut data)?;
+        Ok(data)
+    }
+}
+
+/// Offset of the total used ROM size in the header.
+const ROM_SIZE_OFFSET: u64 = 0x80;
+/// Offset of the device capacity in the header, stored as `128KB << n`.
+const CAPACITY_OFFSET: u64 = 0x14;
+/// Offset of the CRC16 of the header bytes before it.
+const HEADER_CRC_OFFSET: u64 = 0x15E;
+/// Files are aligned to this in ROMs built by the official tools.
+const FILE_ALIGNMENT: u32 = 0x200;
+/// Size of the RSA signature download play ROMs have after the used size.
+const SIGNATURE_SIZE: u32 = 0x88;
+
+/// Replaces the data of a file. The file is kept in place if the new data
+/// fits in the old one, otherwise it's moved to the end of the ROM, after the
+/// download play signature, which is copied after it to follow the used size
+/// again. The FAT, the header size fields and the header CRC are updated
+/// accordingly.
+pub fn replace_file(
+    rom: &mut (impl Read + Write + Seek),
+    fs: &NitroFs,
+    file: &NitroFile,
+    data: &[u8],
+) -> Result<(), Box<dyn Error>> {
+    let old_size = file.end.saturating_sub(file.start);
+    rom.seek(SeekFrom::Start(ROM_SIZE_OFFSET))?;
+    let mut rom_size = rom.read_u32::<LE>()?;
+    let mut signature = None;
+    let start = if data.len() as u32 <= old_size {
+        file.start
+    } else {
+        // Clear the old data so it doesn't look like a file anymore.
+        rom.seek(SeekFrom::Start(file.start as _))?;
+        rom.write_all(&vec![0xFF; old_size as usize])?;
+        if rom.seek(SeekFrom::End(0))? >= (rom_size + SIGNATURE_SIZE) as u64 {
+            let mut bytes = vec![0; SIGNATURE_SIZE as usize];
+            rom.seek(SeekFrom::Start(rom_size as _))?;
+            rom.read_exact(&mut bytes)?;
+            signature = Some(bytes);
+        }
+        let reserved = rom_size + signature.as_ref().map_or(0, |x| x.len() as u32);
+        let used = fs.fat.iter().map(|x| x.1).max().unwrap_or_default();
+        let start = align(reserved.max(used), FILE_ALIGNMENT);
+        rom_size = start + data.len() as u32;
+        start
+    };
+    let end = start + data.len() as u32;
+    rom.seek(SeekFrom::Start(start as _))?;
+    rom.write_all(data)?;
+    if end < file.end {
+        rom.write_all(&vec![0xFF; (file.end - end) as usize])?;
+    }
+    if let Some(signature) = signature {
+        rom.write_all(&signature)?;
+    }
+
+    rom.seek(SeekFrom::Start(fs.fat_offset as u64 + file.id as u64 * 8))?;
+    rom.write_u32::<LE>(start)?;
+    rom.write_u32::<LE>(end)?;
+
+    rom.seek(SeekFrom::Start(ROM_SIZE_OFFSET))?;
+    rom.write_u32::<LE>(rom_size)?;
+    rom.seek(SeekFrom::Start(CAPACITY_OFFSET))?;
+    let mut capacity = rom.read_u8()?;
+    while (0x20000u64 << capacity) < rom_size as u64 {
+        capacity += 1;
+    }
+    rom.seek(SeekFrom::Start(CAPACITY_OFFSET))?;
+    rom.write_u8(capacity)?;
+
+    update_header_crc(rom)
+}
+
+pub fn update_header_crc(rom: &mut (impl Read + Write + Seek)) -> Result<(), Box<dyn Error>> {
+    let mut header = vec![0; HEADER_CRC_OFFSET as usize];
+    rom.seek(SeekFrom::Start(0))?;
+    rom.read_exact(&mut header)?;
+    rom.write_u16::<LE>(crc16(&header))?;
+    Ok(())
+}
+
+/// CRC-16/MODBUS, as used by the ROM header.
+pub fn crc16(data: &[u8]) -> u16 {
+    let mut crc = 0xFFFFu16;
+    for byte in data {
+        crc ^= *byte as u16;
+        for _ in 0..8 {
+            crc = if crc & 1 != 0 {
+                (crc >> 1) ^ 0xA001
+            } else {
+                crc >> 1
+            };
+        }
+    }
+    crc
+}
+
+fn align(value: u32, alignment: u32) -> u32 {
+    value.div_ceil(alignment) * alignment
+}
+
+/// Splits "rom.nds:/path/in/rom" into the ROM path and the path inside it.
+/// A ".nds" ROM is split even if it doesn't exist, so writing to it fails
+/// instead of writing another file.
+pub fn split_rom_path(input: &str) -> Option<(&str, &str)> {
+    if Path::new(input).exists() {
+        return None;
+    }
+    let (rom, path) = input.rsplit_once(':')?;
+    (is_rom(rom) || Path::new(rom).is_file()).then_some((rom, path))
+}
+
+fn open_rom(path: &str, write: bool) -> Result<std::fs::File, Box<dyn Error>> {
+    OpenOptions::new()
+        .read(true)
+        .write(write)
+        .open(path)
+        .map_err(|e| format!("Can't open ROM {}: {}", path, e).into())
+}
+
+pub fn is_rom(path: &str) -> bool {
+    Path::new(path)
+        .extension()
+        .is_some_and(|x| x.eq_ignore_ascii_case("nds"))
+}
+
+/// Reads an archive from a file, or from the file system of a ROM when the
+/// path is like "rom.nds:/path/in/rom".
+pub fn read_archive(input: &str) -> Result<Vec<u8>, Box<dyn Error>> {
+    if let Some((rom, path)) = split_rom_path(input) {
+        let mut rom = open_rom(rom, false)?;
+        let fs = NitroFs::read(&mut rom)?;
+        fs.find(path)?.read(&mut rom)
+    } else {
+        Ok(std::fs::read(input)?)
+    }
+}
+
+/// Writes an archive to a file, or replaces a file in the file system of a ROM
+/// when the path is like "rom.nds:/path/in/rom".
+pub fn write_archive(output: &str, data: &[u8]) -> Result<(), Box<dyn Error>> {
+    if let Some((rom, path)) = split_rom_path(output) {
+        let mut rom = open_rom(rom, true)?;
+        let fs = NitroFs::read(&mut rom)?;
+        replace_file(&mut rom, &fs, fs.find(path)?, data)
+    } else {
+        Ok(std::fs::write(output, data)?)
+    }
+}
+
+#[cfg(test)]
+pub mod tests {
+    use super::*;
+    use std::io::Cursor;
+
+    /// A ROM with "a.bin", "sub/b.bin" and "sub/deep/c.bin", the files are
+    /// 0x10 bytes of their id + 1 at 0x400, 0x600 and 0x800. The used size
+    /// is followed by a download play signature of 0x88 bytes of 0xAB.
+    pub fn rom() -> Vec<u8> {
+        let mut rom = vec![0; 0x810];
+        let subtables: [&[u8]; 3] = [
+            b"\x05a.bin\x83sub\x01\xF0\0",
+            b"\x05b.bin\x84deep\x02\xF0\0",
+            b"\x05c.bin\0",
+        ];
+        let mut fnt = vec![];
+        let mut subtable = subtables.len() * 8;
+        for (i, parent) in [3u16, 0xF000, 0xF001].into_iter().enumerate() {
+            fnt.extend((subtable as u32).to_le_bytes());
+            fnt.extend((i as u16).to_le_bytes());
+            fnt.extend(parent.to_le_bytes());
+            subtable += subtables[i].len();
+        }
+        fnt.extend(subtables.concat());
+        rom[0x200..0x200 + fnt.len()].copy_from_slice(&fnt);
+        for (i, start) in [0x400u32, 0x600, 0x800].into_iter().enumerate() {
+            LE::write_u32(&mut rom[0x300 + i * 8..], start);
+            LE::write_u32(&mut rom[0x304 + i * 8..], start + 0x10);
+            rom[start as usize..start as usize + 0x10].fill(i as u8 + 1);
+        }
+        for (offset, value) in [(0x40, 0x200), (0x44, fnt.len()), (0x48, 0x300), (0x4C, 24)] {
+            LE::write_u32(&mut rom[offset..], value as u32);
+        }
+        LE::write_u32(&mut rom[ROM_SIZE_OFFSET as usize..], 0x810);
+        rom.extend([0xAB; 0x88]);
+        let crc = crc16(&rom[..HEADER_CRC_OFFSET as usize]);
+        LE::write_u16(&mut rom[HEADER_CRC_OFFSET as usize..], crc);
+        rom
+    }
+
+    #[test]
+    fn reads_nested_directories() {
+        let mut rom = Cursor::new(rom());
+        let fs = NitroFs::read(&mut rom).unwrap();
+        let paths: Vec<_> = fs.files.iter().map(|x| (x.id, x.path.as_str())).collect();
+        assert_eq!(
+            paths,
+            [(0, "/a.bin"), (1, "/sub/b.bin"), (2, "/sub/deep/c.bin")]
+        );
+        let file = fs.find("sub\\deep/c.bin").unwrap();
+        assert_eq!(file.read(&mut rom).unwrap(), [3; 0x10]);
+        assert_eq!(fs.find("/a.bin").unwrap().start, 0x400);
+        assert_eq!(
+            fs.find("sub/c.bin").unwrap_err().to_string(),
+            "File sub/c.bin not found in ROM"
+        );
+    }
+
+    #[test]
+    fn rejects_files_past_the_fat() {
+        let mut data = rom();
+        LE::write_u32(&mut data[0x4C..], 16);
+        let err = NitroFs::read(&mut Cursor::new(data)).unwrap_err();
+        assert_eq!(err.to_string(), "Invalid file id in FNT");
+    }
+
+    /// Replaces a file of the ROM, returning the ROM and its file system.
+    fn replace(rom: Vec<u8>, path: &str, data: &[u8]) -> (Vec<u8>, NitroFs) {
+        let mut rom = Cursor::new(rom);
+        let fs = NitroFs::read(&mut rom).unwrap();
+        replace_file(&mut rom, &fs, fs.find(path).unwrap(), data).unwrap();
+        let fs = NitroFs::read(&mut rom).unwrap();
+        (rom.into_inner(), fs)
+    }
+
+    fn has_valid_crc(rom: &[u8]) -> bool {
+        let crc = LE::read_u16(&rom[HEADER_CRC_OFFSET as usize..]);
+        crc == crc16(&rom[..HEADER_CRC_OFFSET as usize])
+    }
+
+    #[test]
+    fn missing_roms_are_errors() {
+        let output = std::env::temp_dir().join("sfarctool-missing.nds");
+        let output = format!("{}:/a.bin", output.display());
+        assert_eq!(split_rom_path(&output), output.rsplit_once(':'));
+        assert!(write_archive(&output, b"data").is_err());
+        assert!(!Path::new(&output).exists());
+    }
+
+    #[test]
+    fn replaces_files_in_place() {
+        let original = rom();
+        let (rom, fs) = replace(original.clone(), "sub/b.bin", &[9; 0x10]);
+        assert_eq!(rom[0x600..0x610], [9; 0x10]);
+        assert_eq!(rom.len(), original.len());
+        assert_eq!(rom[..0x600], original[..0x600]);
+        assert_eq!(fs.fat[1], (0x600, 0x610));
+        // A smaller file keeps its start and clears the rest.
+        let (rom, fs) = replace(rom, "sub/b.bin", &[7; 4]);
+        assert_eq!(fs.fat[1], (0x600, 0x604));
+        assert_eq!(
+            rom[0x600..0x610],
+            [[7; 4], [0xFF; 4], [0xFF; 4], [0xFF; 4]].concat()
+        );
+        assert_eq!(LE::read_u32(&rom[ROM_SIZE_OFFSET as usize..]), 0x810);
+        assert!(has_valid_crc(&rom));
+    }
+
+    #[test]
+    fn moves_grown_files_after_the_signature() {
+        let data = vec![5; 0x30000];
+        let (rom, fs) = replace(rom(), "a.bin", &data);
+        // The file starts at the first aligned offset after the signature.
+        assert_eq!(fs.fat[0], (0xA00, 0x30A00));
+        assert_eq!(rom[0x400..0x410], [0xFF; 0x10]);
+        assert_eq!(rom[0x810..0x898], [0xAB; 0x88]);
+        assert_eq!(rom[0xA00..0x30A00], data);
+        assert_eq!(rom[0x30A00..], [0xAB; 0x88]);
+        assert_eq!(LE::read_u32(&rom[ROM_SIZE_OFFSET as usize..]), 0x30A00);
+        assert_eq!(rom[CAPACITY_OFFSET as usize], 1);
+        assert!(has_valid_crc(&rom));
+        assert_eq!(fs.find("sub/b.bin").unwrap().start, 0x600);
+    }
+}
@@ -23,10 +23,27 @@ impl Display for InvalidMagicNumberError {
 
 impl Error for InvalidMagicNumberError {}
 
+/// Most bytes reserved up front, as the header of a corrupt file can claim
+/// any size up to 4 GiB.
+const MAX_RESERVE: usize = 0x1000000;
+
+/// Reads the header of an LZ10/LZ11 compressed file and returns the size of
+/// the decompressed data without decompressing it.
+pub fn decompressed_size(inp: &mut impl Read) -> Result<usize, Box<dyn std::error::Error>> {
+    let length = inp.read_u32::<LittleEndian>()? as usize;
+    match length & 0xFF {
+        0x10 => Ok(length >> 8),
+        0x11 if length >> 8 == 0 => Ok(inp.read_u32::<LittleEndian>()? as usize),
+        0x11 => Ok(length >> 8),
+        _ => Err(InvalidMagicNumberError.into()),
+    }
+}
+
 /// Decompresses an LZ10/LZ11 compressed file. It returns an error when:
 ///
 /// - The file is not a valid LZ10/LZ11 file
 /// - The file is truncated (More data was expected than present)
+/// - A back reference points before the start of the data
 ///
 /// # Example
 ///
@@ -35,6 +52,24 @@ impl Error for InvalidMagicNumberError {}
 /// let mut decompressed = nintendo_lz::decompress(&mut f).unwrap();
 /// ```
 pub fn decompress(inp: &mut impl Read) -> Result<Vec<u8>, Box<dyn std::error::Error>> {
+    decode(inp, |_| {})
+}
+
+/// Checks whether an LZ10/LZ11 compressed file can be decompressed straight
+/// into VRAM, which is written 16 bits at a time and so can't copy from the
+/// byte right before the one being written.
+pub fn is_vram_safe(inp: &mut impl Read) -> Result<bool, Box<dyn std::error::Error>> {
+    let mut safe = true;
+    decode(inp, |disp| safe &= disp > 0)?;
+    Ok(safe)
+}
+
+/// Decompresses the file, calling `on_copy` with the encoded displacement of
+/// every back reference.
+fn decode(
+    inp: &mut impl Read,
+    mut on_copy: impl FnMut(usize),
+) -> Result<Vec<u8>, Box<dyn std::error::Error>> {
     let mut length = inp.read_u32::<LittleEndian>()? as usize;
     let ver = match length & 0xFF {
         0x10 => Ok(0),
@@ -45,8 +80,7 @@ pub fn decompress(inp: &mut impl Read) -> Result<Vec<u8>, Box<dyn std::error::Er
     if length == 0 && ver == 1 {
         length = inp.read_u32::<LittleEndian>()? as usize;
     }
-    let mut out: Vec<u8> = Vec::new();
-    out.reserve(length);
+    let mut out: Vec<u8> = Vec::with_capacity(length.min(MAX_RESERVE));
     while out.len() < length {
         let byte = inp.read_u8()?;
         for bit_no in (0..8).rev() {
@@ -80,7 +114,11 @@ pub fn decompress(inp: &mut impl Read) -> Result<Vec<u8>, Box<dyn std::error::Er
                     length += 0x111;
                     disp = ((byte1 & 15) << 8) + byte2;
                 }
-                let start: usize = out.len() - disp - 1;
+                on_copy(disp);
+                let start: usize = out
+                    .len()
+                    .checked_sub(disp + 1)
+                    .ok_or("Back reference before the start of the data")?;
 
                 for i in 0..length {
                     let val = out[start + i];
@@ -91,3 +129,28 @@ pub fn decompress(inp: &mut impl Read) -> Result<Vec<u8>, Box<dyn std::error::Er
     }
     Ok(out)
 }
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+
+    use super::*;
+
+    #[test]
+    fn rejects_back_reference_before_start() {
+        let data = [0x10, 0x08, 0, 0, 0x80, 0x00, 0x05];
+        assert!(decompress(&mut Cursor::new(data)).is_err());
+    }
+
+    #[test]
+    fn rejects_truncated_data() {
+        let data = [0x10, 0x08, 0, 0, 0x00, b'A', b'B'];
+        assert!(decompress(&mut Cursor::new(data)).is_err());
+    }
+
+    #[test]
+    fn rejects_missing_data_of_huge_size() {
+        let data = [0x11, 0, 0, 0, 0xFF, 0xFF, 0xFF, 0xFF];
+        assert!(decompress(&mut Cursor::new(data)).is_err());
+    }
+}
@@ -0,0 +1,461 @@
+//! IPS and BPS binary patches, to ship the changes made to an archive or a
+//! whole ROM without the data itself.
+
+use std::{
+    collections::HashMap,
+    error::Error,
+    io::{Cursor, Read, Write},
+};
+
+use byteorder::*;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PatchFormat {
+    Ips,
+    Bps,
+}
+
+impl PatchFormat {
+    pub fn parse(name: &str) -> Option<Self> {
+        match name.to_ascii_lowercase().as_str() {
+            "ips" => Some(Self::Ips),
+            "bps" => Some(Self::Bps),
+            _ => None,
+        }
+    }
+
+    /// Guesses the format from the file extension of a patch.
+    pub fn from_path(path: &str) -> Option<Self> {
+        Self::parse(path.rsplit_once('.')?.1)
+    }
+}
+
+pub fn make(format: PatchFormat, source: &[u8], target: &[u8]) -> Result<Vec<u8>, Box<dyn Error>> {
+    match format {
+        PatchFormat::Ips => make_ips(source, target),
+        PatchFormat::Bps => Ok(make_bps(source, target)),
+    }
+}
+
+/// Applies a patch, detecting its format from the header.
+pub fn apply(patch: &[u8], source: &[u8]) -> Result<Vec<u8>, Box<dyn Error>> {
+    if patch.starts_with(IPS_MAGIC) {
+        apply_ips(patch, source)
+    } else if patch.starts_with(BPS_MAGIC) {
+        apply_bps(patch, source)
+    } else {
+        Err("Unknown patch format".into())
+    }
+}
+
+const IPS_MAGIC: &[u8] = b"PATCH";
+const IPS_EOF: &[u8] = b"EOF";
+const IPS_MAX_SIZE: usize = 0x1000000;
+const IPS_MAX_RECORD: usize = 0xFFFF;
+/// Differences closer than this are merged into one record, since starting a
+/// new record costs 5 bytes.
+const IPS_MERGE_GAP: usize = 5;
+
+pub fn make_ips(source: &[u8], target: &[u8]) -> Result<Vec<u8>, Box<dyn Error>> {
+    if target.len() > IPS_MAX_SIZE {
+        return Err("IPS patches can't address data past 16MB, use BPS instead".into());
+    }
+    let differs = |i: usize| source.get(i) != Some(&target[i]);
+    let mut patch = IPS_MAGIC.to_vec();
+    let mut i = 0;
+    while i < target.len() {
+        if !differs(i) {
+            i += 1;
+            continue;
+        }
+        // Offset 0x454F46 reads as "EOF", start the record one byte earlier.
+        let start = if i == 0x454F46 { i - 1 } else { i };
+        let mut end = i + 1;
+        let mut last_diff = i;
+        while end < target.len() && end - start < IPS_MAX_RECORD {
+            if differs(end) {
+                last_diff = end;
+            } else if end - last_diff > IPS_MERGE_GAP {
+                break;
+            }
+            end += 1;
+        }
+        let end = (last_diff + 1).min(start + IPS_MAX_RECORD);
+        let data = &target[start..end];
+        patch.write_u24::<BE>(start as u32)?;
+        if data.len() > 8 && data.iter().all(|x| *x == data[0]) {
+            patch.write_u16::<BE>(0)?;
+            patch.write_u16::<BE>(data.len() as u16)?;
+            patch.write_u8(data[0])?;
+        } else {
+            patch.write_u16::<BE>(data.len() as u16)?;
+            patch.write_all(data)?;
+        }
+        i = end;
+    }
+    patch.write_all(IPS_EOF)?;
+    if target.len() < source.len() {
+        patch.write_u24::<BE>(target.len() as u32)?;
+    }
+    Ok(patch)
+}
+
+pub fn apply_ips(patch: &[u8], source: &[u8]) -> Result<Vec<u8>, Box<dyn Error>> {
+    let mut target = source.to_vec();
+    let mut patch = Cursor::new(&patch[IPS_MAGIC.len()..]);
+    loop {
+        let mut offset = [0; 3];
+        patch.read_exact(&mut offset)?;
+        if offset == IPS_EOF {
+            break;
+        }
+        let offset = u32::from_be_bytes([0, offset[0], offset[1], offset[2]]) as usize;
+        let size = patch.read_u16::<BE>()? as usize;
+        let data = if size == 0 {
+            let size = patch.read_u16::<BE>()? as usize;
+            vec![patch.read_u8()?; size]
+        } else {
+            let mut data = vec![0; size];
+            patch.read_exact(&mut data)?;
+            data
+        };
+        if target.len() < offset + data.len() {
+            target.resize(offset + data.len(), 0);
+        }
+        target[offset..offset + data.len()].copy_from_slice(&data);
+    }
+    if let Ok(size) = patch.read_u24::<BE>() {
+        target.truncate(size as usize);
+    }
+    Ok(target)
+}
+
+const BPS_MAGIC: &[u8] = b"BPS1";
+const SOURCE_READ: u64 = 0;
+const TARGET_READ: u64 = 1;
+const SOURCE_COPY: u64 = 2;
+const TARGET_COPY: u64 = 3;
+/// Length of the blocks indexed to find copies. Only blocks starting at a
+/// multiple of it are indexed in the source, which is enough to find any copy
+/// that long while keeping the index small for whole ROMs.
+const BPS_BLOCK: usize = 8;
+
+pub fn make_bps(source: &[u8], target: &[u8]) -> Vec<u8> {
+    let mut patch = BPS_MAGIC.to_vec();
+    write_varint(&mut patch, source.len() as u64);
+    write_varint(&mut patch, target.len() as u64);
+    write_varint(&mut patch, 0);
+
+    let block_at = |data: &[u8], i: usize| {
+        data.get(i..i + BPS_BLOCK)
+            .map(|x| u64::from_le_bytes(x.try_into().unwrap()))
+    };
+    let mut source_index = HashMap::new();
+    for i in (0..source.len()).step_by(BPS_BLOCK) {
+        if let Some(block) = block_at(source, i) {
+            source_index.entry(block).or_insert(i);
+        }
+    }
+    let mut target_index = HashMap::new();
+    let match_len = |a: &[u8], b: &[u8]| a.iter().zip(b).take_while(|(a, b)| a == b).count();
+
+    let mut literal_start = 0;
+    let mut source_offset = 0i64;
+    let mut target_offset = 0i64;
+    let mut i = 0;
+    while i < target.len() {
+        // Source read keeps the data at the same offset and costs no offset.
+        let read = if i < source.len() {
+            match_len(&source[i..], &target[i..])
+        } else {
+            0
+        };
+        let mut best = (read, SOURCE_READ, 0usize);
+        if let Some(block) = block_at(target, i).filter(|_| read < BPS_BLOCK * 4) {
+            if let Some(&at) = source_index.get(&block) {
+                let len = match_len(&source[at..], &target[i..]);
+                if len > best.0 + 2 {
+                    best = (len, SOURCE_COPY, at);
+                }
+            }
+            if let Some(&at) = target_index.get(&block) {
+                // The copy may overlap what it writes, like LZ.
+                let len = (0..target.len() - i)
+                    .take_while(|x| target[at + x] == target[i + x])
+                    .count();
+                if len > best.0 + 2 {
+                    best = (len, TARGET_COPY, at);
+                }
+            }
+        }
+        let (mut len, action, mut at) = best;
+        let worth = match action {
+            SOURCE_READ => len >= 4,
+            _ => len >= BPS_BLOCK,
+        };
+        if !worth {
+            if let Some(block) = block_at(target, i) {
+                target_index.insert(block, i);
+            }
+            i += 1;
+            continue;
+        }
+        if action == SOURCE_COPY {
+            // The copy may have started in the pending literal data.
+            while i > literal_start && at > 0 && source[at - 1] == target[i - 1] {
+                i -= 1;
+                at -= 1;
+                len += 1;
+            }
+        }
+        if literal_start < i {
+            write_varint(
+                &mut patch,
+                ((i - literal_start - 1) as u64) << 2 | TARGET_READ,
+            );
+            patch.extend_from_slice(&target[literal_start..i]);
+        }
+        write_varint(&mut patch, ((len - 1) as u64) << 2 | action);
+        match action {
+            SOURCE_COPY => {
+                write_signed(&mut patch, at as i64 - source_offset);
+                source_offset = (at + len) as i64;
+            }
+            TARGET_COPY => {
+                write_signed(&mut patch, at as i64 - target_offset);
+                target_offset = (at + len) as i64;
+            }
+            _ => {}
+        }
+        i += len;
+        literal_start = i;
+    }
+    if literal_start < target.len() {
+        write_varint(
+            &mut patch,
+            ((target.len() - literal_start - 1) as u64) << 2 | TARGET_READ,
+        );
+        patch.extend_from_slice(&target[literal_start..]);
+    }
+
+    patch.extend_from_slice(&crc32(source).to_le_bytes());
+    patch.extend_from_slice(&crc32(target).to_le_bytes());
+    let patch_crc = crc32(&patch);
+    patch.extend_from_slice(&patch_crc.to_le_bytes());
+    patch
+}
+
+pub fn apply_bps(patch: &[u8], source: &[u8]) -> Result<Vec<u8>, Box<dyn Error>> {
+    if patch.len() < BPS_MAGIC.len() + 12 {
+        return Err("Truncated BPS patch".into());
+    }
+    let (body, footer) = patch.split_at(patch.len() - 12);
+    let mut footer = Cursor::new(footer);
+    let source_crc = footer.read_u32::<LE>()?;
+    let target_crc = footer.read_u32::<LE>()?;
+    let patch_crc = footer.read_u32::<LE>()?;
+    if crc32(&patch[..patch.len() - 4]) != patch_crc {
+        return Err("BPS patch is corrupted".into());
+    }
+    if crc32(source) != source_crc {
+        return Err("BPS patch is made for another source file".into());
+    }
+
+    let mut body = Cursor::new(&body[BPS_MAGIC.len()..]);
+    let source_size = read_varint(&mut body)? as usize;
+    let target_size = read_varint(&mut body)? as usize;
+    let metadata_size = read_varint(&mut body)?;
+    body.set_position(body.position() + metadata_size);
+    if source_size != source.len() {
+        return Err("BPS patch is made for another source file".into());
+    }
+
+    let mut target = Vec::with_capacity(target_size);
+    let mut source_offset = 0i64;
+    let mut target_offset = 0i64;
+    let end = body.get_ref().len() as u64;
+    while body.position() < end {
+        let action = read_varint(&mut body)?;
+        let len = (action >> 2) as usize + 1;
+        match action & 3 {
+            SOURCE_READ => {
+                let at = target.len();
+                target.extend_from_slice(
+                    source
+                        .get(at..at + len)
+                        .ok_or("BPS patch reads past the source")?,
+                );
+            }
+            TARGET_READ => {
+                let mut data = vec![0; len];
+                body.read_exact(&mut data)?;
+                target.extend_from_slice(&data);
+            }
+            SOURCE_COPY => {
+                source_offset += read_signed(&mut body)?;
+                let at = source_offset as usize;
+                target.extend_from_slice(
+                    source
+                        .get(at..at + len)
+                        .ok_or("BPS patch reads past the source")?,
+                );
+                source_offset += len as i64;
+            }
+            _ => {
+                target_offset += read_signed(&mut body)?;
+                for _ in 0..len {
+                    let byte = *target
+                        .get(target_offset as usize)
+                        .ok_or("BPS patch reads past the target")?;
+                    target.push(byte);
+                    target_offset += 1;
+                }
+            }
+        }
+    }
+    if target.len() != target_size || crc32(&target) != target_crc {
+        return Err("BPS patch produced a wrong result".into());
+    }
+    Ok(target)
+}
+
+fn write_varint(out: &mut Vec<u8>, mut value: u64) {
+    loop {
+        let x = (value & 0x7F) as u8;
+        value >>= 7;
+        if value == 0 {
+            out.push(0x80 | x);
+            break;
+        }
+        out.push(x);
+        value -= 1;
+    }
+}
+
+fn write_signed(out: &mut Vec<u8>, value: i64) {
+    write_varint(out, value.unsigned_abs() << 1 | (value < 0) as u64);
+}
+
+fn read_varint(inp: &mut impl Read) -> Result<u64, Box<dyn Error>> {
+    let mut value = 0u64;
+    let mut shift = 1u64;
+    loop {
+        let x = inp.read_u8()?;
+        value += (x & 0x7F) as u64 * shift;
+        if x & 0x80 != 0 {
+            return Ok(value);
+        }
+        shift <<= 7;
+        value += shift;
+    }
+}
+
+fn read_signed(inp: &mut impl Read) -> Result<i64, Box<dyn Error>> {
+    let value = read_varint(inp)?;
+    let magnitude = (value >> 1) as i64;
+    Ok(if value & 1 != 0 {
+        -magnitude
+    } else {
+        magnitude
+    })
+}
+
+/// CRC-32 (IEEE), as used by BPS patches.
+pub fn crc32(data: &[u8]) -> u32 {
+    let mut crc = !0u32;
+    for byte in data {
+        crc ^= *byte as u32;
+        for _ in 0..8 {
+            crc = if crc & 1 != 0 {
+                (crc >> 1) ^ 0xEDB88320
+            } else {
+                crc >> 1
+            };
+        }
+    }
+    !crc
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Deterministic bytes which rarely repeat.
+    fn noise(len: usize, seed: u32) -> Vec<u8> {
+        let mut x = seed.max(1);
+        (0..len)
+            .map(|_| {
+                x ^= x << 13;
+                x ^= x >> 17;
+                x ^= x << 5;
+                x as u8
+            })
+            .collect()
+    }
+
+    fn cases() -> Vec<(&'static str, Vec<u8>, Vec<u8>)> {
+        let source = noise(0x10000, 1);
+        let mut edited = source.clone();
+        edited[0x10..0x20].copy_from_slice(&[0xAA; 0x10]);
+        edited[0x8000] ^= 0xFF;
+        let mut grown = edited.clone();
+        grown.extend(noise(0x300, 2));
+        grown.extend([0; 0x100]);
+        let mut moved = source[0x4000..].to_vec();
+        moved.extend(&source[..0x4000]);
+        vec![
+            ("unchanged", source.clone(), source.clone()),
+            ("edited", source.clone(), edited.clone()),
+            ("grown", source.clone(), grown),
+            ("truncated", source.clone(), edited[..0x9000].to_vec()),
+            ("emptied", source.clone(), vec![]),
+            ("from empty", vec![], noise(0x200, 3)),
+            ("moved", source.clone(), moved),
+        ]
+    }
+
+    #[test]
+    fn ips_round_trips() {
+        for (name, source, target) in cases() {
+            let patch = make(PatchFormat::Ips, &source, &target).unwrap();
+            assert_eq!(apply(&patch, &source).unwrap(), target, "{}", name);
+        }
+    }
+
+    #[test]
+    fn bps_round_trips() {
+        for (name, source, target) in cases() {
+            let patch = make(PatchFormat::Bps, &source, &target).unwrap();
+            assert_eq!(apply(&patch, &source).unwrap(), target, "{}", name);
+        }
+    }
+
+    #[test]
+    fn bps_rejects_other_source() {
+        let source = noise(0x1000, 1);
+        let patch = make_bps(&source, &noise(0x1000, 2));
+        assert!(apply(&patch, &noise(0x1000, 3)).is_err());
+    }
+
+    /// A record at offset 0x454F46 would read as the "EOF" marker.
+    #[test]
+    fn ips_avoids_eof_offset() {
+        let source = vec![0; 0x454F50];
+        for start in [0x454F45, 0x454F46] {
+            let mut target = source.clone();
+            target[0x454F46] = 1;
+            target[start] = 1;
+            let patch = make_ips(&source, &target).unwrap();
+            assert_eq!(
+                &patch[IPS_MAGIC.len()..IPS_MAGIC.len() + 3],
+                [0x45, 0x4F, 0x45]
+            );
+            assert_eq!(apply_ips(&patch, &source).unwrap(), target);
+        }
+    }
+
+    #[test]
+    fn ips_rejects_targets_past_16_mib() {
+        assert!(make_ips(&[], &vec![0; IPS_MAX_SIZE + 1]).is_err());
+    }
+}
@@ -0,0 +1,324 @@
+//! Project file listing every archive to build with its options, so a whole
+//! set of archives can be rebuilt consistently with one command. Fonts
+//! exported to JSON are rebuilt along with them.
+//!
+//! ```toml
+//! [defaults]
+//! compress = true
+//!
+//! [[archive]]
+//! input = "unpacked/mess_0001"
+//! output = "build/data/msg/mess_0001.bin"
+//! eof = true
+//! codec = "lz11"
+//!
+//! [[hook]]
+//! match = "*.bin"
+//! magic = "BMD0"
+//! extension = "xml"
+//! unpack = "tools/converter.exe export {input} {output}"
+//! pack = "tools/converter.exe import {input} {output}"
+//!
+//! [[font]]
+//! input = "fonts/main.json"
+//! output = "build/data/font/main.NFTR"
+//! ```
+
+use std::{error::Error, path::Path};
+
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    archive::{MinSavings, PackOptions},
+    batch::{self, Summary},
+    font,
+    hook::{self, Hook},
+    incremental::{self, BuildCache},
+    lzss::{self, Codec},
+    manifest::Compression,
+    nds,
+    text::CharTable,
+    translation::Translations,
+};
+
+#[derive(Debug, Default, Deserialize, Serialize)]
+pub struct Project {
+    /// Skip archives whose inputs didn't change since the last build.
+    #[serde(default)]
+    pub incremental: bool,
+    /// Cache for incremental builds, relative to the project file.
+    #[serde(default)]
+    pub cache: Option<String>,
+    /// Options used by every archive unless it sets its own.
+    #[serde(default)]
+    pub defaults: ArchiveOptions,
+    #[serde(default, rename = "archive")]
+    pub archives: Vec<ProjectArchive>,
+    #[serde(default, rename = "font", skip_serializing_if = "Vec::is_empty")]
+    pub fonts: Vec<ProjectFont>,
+    /// External converters used by every archive, after the ones of their
+    /// manifest. Commands run in the directory of the project file.
+    #[serde(default, rename = "hook", skip_serializing_if = "Vec::is_empty")]
+    pub hooks: Vec<Hook>,
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+pub struct ProjectArchive {
+    /// Unpacked archive directory, relative to the project file.
+    pub input: String,
+    /// Output archive, relative to the project file. It can be a file inside
+    /// a ROM like "rom.nds:/path/in/rom.bin".
+    pub output: String,
+    #[serde(flatten)]
+    pub options: ArchiveOptions,
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+pub struct ProjectFont {
+    /// Font exported to JSON, with its glyph sheet next to it.
+    pub input: String,
+    /// Output font, which can be a file inside a ROM like the archives.
+    pub output: String,
+    /// Character table whose characters must all have a glyph, the one in
+    /// `[defaults]` by default.
+    pub table: Option<String>,
+}
+
+#[derive(Debug, Default, Clone, Deserialize, Serialize)]
+pub struct ArchiveOptions {
+    pub eof: Option<bool>,
+    pub compress: Option<bool>,
+    pub ignore_zero: Option<bool>,
+    pub dedupe: Option<bool>,
+    /// Alignment of the subfile offsets in bytes.
+    pub align: Option<usize>,
+    /// Byte filling the space left by the alignment.
+    pub fill: Option<u8>,
+    /// Archive the input replaces sub files of, relative to the project file.
+    /// It can be a file inside a ROM.
+    pub template: Option<String>,
+    /// "lz10" or "lz11".
+    pub codec: Option<String>,
+    pub level: Option<u32>,
+    pub vram_safe: Option<bool>,
+    /// Bytes, or percent with "%", automatic compression must save.
+    pub min_savings: Option<String>,
+    #[serde(default)]
+    pub compress_always: Vec<String>,
+    #[serde(default)]
+    pub compress_never: Vec<String>,
+    #[serde(default)]
+    pub compress_auto: Vec<String>,
+    /// Character table for ".txt" subfiles, relative to the project file.
+    pub table: Option<String>,
+    /// Translations replacing the scripts, relative to the project file.
+    pub translations: Option<String>,
+    /// Warn about translated strings longer than this many bytes.
+    pub max_length: Option<usize>,
+}
+
+impl ArchiveOptions {
+    /// Applies the options which are set on top of the base options.
+    pub fn apply(&self, options: &mut PackOptions) -> Result<(), Box<dyn Error>> {
+        if let Some(eof) = self.eof {
+            options.eof = eof;
+        }
+        if let Some(compress) = self.compress {
+            options.compress = compress;
+        }
+        if let Some(ignore_zero) = self.ignore_zero {
+            options.ignore_zero = ignore_zero;
+        }
+        if let Some(dedupe) = self.dedupe {
+            options.dedupe = dedupe;
+        }
+        if let Some(align) = self.align {
+            if align == 0 {
+                return Err("Alignment must be at least 1".into());
+            }
+            options.align = Some(align);
+        }
+        if let Some(fill) = self.fill {
+            options.fill = Some(fill);
+        }
+        if let Some(codec) = &self.codec {
+            options.compress_options.codec = Codec::parse(codec)
+                .ok_or_else(|| format!("Unknown compression format {}", codec))?;
+        }
+        if let Some(level) = self.level {
+            if !(1..=lzss::MAX_LEVEL).contains(&level) {
+                return Err("Compression level must be between 1 and 9".into());
+            }
+            options.compress_options.level = level;
+        }
+        if let Some(max_length) = self.max_length {
+            options.max_length = Some(max_length);
+        }
+        if let Some(vram_safe) = self.vram_safe {
+            options.compress_options.vram_safe = vram_safe;
+        }
+        if let Some(min_savings) = &self.min_savings {
+            options.min_savings = MinSavings::parse(min_savings)
+                .ok_or_else(|| format!("Invalid minimum savings {}", min_savings))?;
+        }
+        for (patterns, policy) in [
+            (&self.compress_auto, Compression::Auto),
+            (&self.compress_always, Compression::Always),
+            (&self.compress_never, Compression::Never),
+        ] {
+            options
+                .patterns
+                .extend(patterns.iter().map(|x| (x.to_owned(), policy)));
+        }
+        Ok(())
+    }
+}
+
+impl Project {
+    pub fn load(path: &Path) -> Result<Self, Box<dyn Error>> {
+        Ok(toml::from_str(&std::fs::read_to_string(path)?)?)
+    }
+
+    /// Builds every archive of the project, paths are resolved against `base`.
+    /// Incremental builds read and write the cache file of the project.
+    pub fn build(&self, base: &Path, options: &PackOptions) -> Summary {
+        let cache_path = base.join(
+            self.cache
+                .as_deref()
+                .unwrap_or(incremental::DEFAULT_CACHE_NAME),
+        );
+        let mut cache = None;
+        if self.incremental {
+            match BuildCache::load(&cache_path) {
+                Ok(loaded) => cache = Some(loaded),
+                Err(err) => {
+                    let mut summary = Summary::default();
+                    summary
+                        .failed
+                        .push((cache_path.display().to_string(), err.to_string()));
+                    return summary;
+                }
+            }
+        }
+        let mut summary = self.build_cached(base, options, cache.as_mut());
+        if let Some(cache) = cache {
+            if let Err(err) = cache.save(&cache_path) {
+                summary
+                    .failed
+                    .push((cache_path.display().to_string(), err.to_string()));
+            }
+        }
+        summary
+    }
+
+    /// Builds every archive of the project, skipping the ones the cache says
+    /// are up to date.
+    pub fn build_cached(
+        &self,
+        base: &Path,
+        options: &PackOptions,
+        mut cache: Option<&mut BuildCache>,
+    ) -> Summary {
+        let mut summary = Summary::default();
+        for archive in &self.archives {
+            let input = base.join(&archive.input);
+            let output = resolve(base, &archive.output);
+            if options.verbose {
+                println!("Packing {}", archive.output);
+            }
+            let result = self.options_of(archive, base, options).and_then(|options| {
+                batch::pack_cached(
+                    &input,
+                    Path::new(&output),
+                    &options,
+                    cache.as_deref_mut(),
+                    |data| nds::write_archive(&output, data),
+                )
+            });
+            match result {
+                Ok(false) => summary.skipped.push(archive.output.clone()),
+                result => summary.record(archive.output.clone(), result.map(|_| ())),
+            }
+        }
+        for font in &self.fonts {
+            if options.verbose {
+                println!("Building {}", font.output);
+            }
+            let result = self.build_font(font, base, options.slience);
+            summary.record(font.output.clone(), result);
+        }
+        summary
+    }
+
+    fn build_font(
+        &self,
+        font: &ProjectFont,
+        base: &Path,
+        slience: bool,
+    ) -> Result<(), Box<dyn Error>> {
+        let (json, data) = font::import_files(&base.join(&font.input))?;
+        if let Some(table) = font.table.as_ref().or(self.defaults.table.as_ref()) {
+            let missing = font::missing_chars(&json, &CharTable::load(&base.join(table))?);
+            if !missing.is_empty() && !slience {
+                println!(
+                    "Warning: {}: {} characters of the table have no glyph: {}",
+                    font.output,
+                    missing.len(),
+                    missing.iter().collect::<String>()
+                );
+            }
+        }
+        nds::write_archive(&resolve(base, &font.output), &data)
+    }
+
+    fn options_of(
+        &self,
+        archive: &ProjectArchive,
+        base: &Path,
+        defaults: &PackOptions,
+    ) -> Result<PackOptions, Box<dyn Error>> {
+        let mut options = defaults.clone();
+        self.defaults.apply(&mut options)?;
+        archive.options.apply(&mut options)?;
+        if let Some(template) = archive
+            .options
+            .template
+            .as_ref()
+            .or(self.defaults.template.as_ref())
+        {
+            options.template = Some(resolve(base, template));
+        }
+        options.hooks = hook::in_dir(self.hooks.clone(), base)
+            .into_iter()
+            .chain(defaults.hooks.iter().cloned())
+            .collect();
+        if let Some(table) = archive
+            .options
+            .table
+            .as_ref()
+            .or(self.defaults.table.as_ref())
+        {
+            options.table = Some(CharTable::load(&base.join(table))?);
+        }
+        if let Some(translations) = archive
+            .options
+            .translations
+            .as_ref()
+            .or(self.defaults.translations.as_ref())
+        {
+            options.translations = Some(Translations::load(&base.join(translations))?);
+        }
+        options.text_root = Some(base.to_owned());
+        Ok(options)
+    }
+}
+
+/// Resolves a path relative to the project file, keeping the ROM suffix of
+/// paths like "rom.nds:/path/in/rom.bin".
+fn resolve(base: &Path, path: &str) -> String {
+    let joined = |path: &str| base.join(path).display().to_string();
+    match path.rsplit_once(':') {
+        Some((rom, inner)) if nds::is_rom(rom) => format!("{}:{}", joined(rom), inner),
+        _ => joined(path),
+    }
+}
@@ -0,0 +1,568 @@
+//! Fixed-size record tables, like card or enemy stats, converted to CSV or
+//! JSON with one row per record. A TOML schema describes the fields of a
+//! record:
+//!
+//! ```toml
+//! size = 8            # bytes per record, by default the end of the last field
+//! header = 4          # bytes before the first record, kept as they are
+//! endian = "little"   # or "big"
+//! format = "csv"      # or "json"
+//!
+//! [[field]]
+//! name = "hp"
+//! type = "u16"        # u8, u16, u32, i8, i16, i32 or bytes
+//! max = 999
+//!
+//! [[field]]
+//! name = "element"
+//! type = "u8"
+//! offset = 4          # by default right after the previous field, fields can't overlap
+//! enum = { none = 0, fire = 1, aqua = 2 }
+//! ```
+//!
+//! Bytes not covered by any field, and the data after the last whole record,
+//! are kept from the original entry.
+
+use std::{collections::BTreeMap, error::Error, path::Path};
+
+use itertools::Itertools;
+use serde::Deserialize;
+use serde_json::{Map, Value};
+
+use crate::translation;
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct Schema {
+    /// Bytes per record, by default the end of the last field.
+    pub size: Option<usize>,
+    /// Bytes before the first record.
+    #[serde(default)]
+    pub header: usize,
+    #[serde(default)]
+    pub endian: Endian,
+    #[serde(default)]
+    pub format: TableFormat,
+    #[serde(default, rename = "field")]
+    pub fields: Vec<Field>,
+    /// Offset and size of every field, set by `load`.
+    #[serde(skip)]
+    layout: Vec<(usize, usize)>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct Field {
+    pub name: String,
+    #[serde(rename = "type")]
+    pub kind: FieldType,
+    /// Offset in the record, by default right after the previous field.
+    pub offset: Option<usize>,
+    /// Size of a `bytes` field.
+    pub size: Option<usize>,
+    /// Byte order of this field, instead of the one of the schema.
+    pub endian: Option<Endian>,
+    pub min: Option<i64>,
+    pub max: Option<i64>,
+    /// Names written instead of the values.
+    #[serde(default, rename = "enum")]
+    pub names: BTreeMap<String, i64>,
+}
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Endian {
+    #[default]
+    Little,
+    Big,
+}
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum TableFormat {
+    #[default]
+    Csv,
+    Json,
+}
+
+impl TableFormat {
+    pub fn extension(self) -> &'static str {
+        match self {
+            Self::Csv => "csv",
+            Self::Json => "json",
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum FieldType {
+    U8,
+    U16,
+    U32,
+    I8,
+    I16,
+    I32,
+    /// Raw bytes written in hex.
+    Bytes,
+}
+
+impl FieldType {
+    fn size(self) -> Option<usize> {
+        match self {
+            Self::U8 | Self::I8 => Some(1),
+            Self::U16 | Self::I16 => Some(2),
+            Self::U32 | Self::I32 => Some(4),
+            Self::Bytes => None,
+        }
+    }
+
+    fn signed(self) -> bool {
+        matches!(self, Self::I8 | Self::I16 | Self::I32)
+    }
+}
+
+impl Schema {
+    /// Loads a schema, checking that its fields fit in a record.
+    pub fn load(path: &Path) -> Result<Self, Box<dyn Error>> {
+        let source = std::fs::read_to_string(path)
+            .map_err(|e| format!("Can't read schema {}: {}", path.display(), e))?;
+        let mut schema: Self = toml::from_str(&source)
+            .map_err(|e| format!("Invalid schema {}: {}", path.display(), e))?;
+        schema.resolve()?;
+        Ok(schema)
+    }
+
+    fn resolve(&mut self) -> Result<(), Box<dyn Error>> {
+        let mut next = 0;
+        for (i, field) in self.fields.iter().enumerate() {
+            if self.fields[..i].iter().any(|x| x.name == field.name) {
+                return Err(format!("Field \"{}\" is defined twice", field.name).into());
+            }
+            let size = match (field.kind.size(), field.size) {
+                (Some(size), _) => size,
+                (None, Some(size)) if size > 0 => size,
+                _ => return Err(format!("Field \"{}\" needs a size", field.name).into()),
+            };
+            let offset = field.offset.unwrap_or(next);
+            if let Some(other) = self
+                .layout
+                .iter()
+                .position(|x| offset < x.0 + x.1 && x.0 < offset + size)
+            {
+                return Err(format!(
+                    "Fields \"{}\" and \"{}\" overlap",
+                    self.fields[other].name, field.name
+                )
+                .into());
+            }
+            next = offset + size;
+            self.layout.push((offset, size));
+        }
+        let end = self.layout.iter().map(|x| x.0 + x.1).max().unwrap_or(0);
+        let size = self.size.unwrap_or(end);
+        if size == 0 {
+            return Err("Records can't be empty".into());
+        }
+        if end > size {
+            return Err(
+                format!("Fields end at byte {}, after the record size {}", end, size).into(),
+            );
+        }
+        self.size = Some(size);
+        Ok(())
+    }
+
+    fn record_size(&self) -> usize {
+        self.size.unwrap_or(1)
+    }
+
+    /// Splits the data into its header, records and trailing data.
+    fn split<'a>(&self, data: &'a [u8]) -> (&'a [u8], Vec<&'a [u8]>, &'a [u8]) {
+        let header = self.header.min(data.len());
+        let size = self.record_size();
+        let count = (data.len() - header) / size;
+        let end = header + count * size;
+        let records = data[header..end].chunks(size).collect();
+        (&data[..header], records, &data[end..])
+    }
+
+    fn read(&self, index: usize, record: &[u8]) -> String {
+        let field = &self.fields[index];
+        let (offset, size) = self.layout[index];
+        let bytes = &record[offset..offset + size];
+        if field.kind == FieldType::Bytes {
+            return bytes.iter().map(|x| format!("{:02x}", x)).collect();
+        }
+        let mut value = 0u64;
+        for i in 0..size {
+            let byte = match field.endian.unwrap_or(self.endian) {
+                Endian::Little => bytes[size - 1 - i],
+                Endian::Big => bytes[i],
+            };
+            value = value << 8 | byte as u64;
+        }
+        let bits = size as u32 * 8;
+        let value = if field.kind.signed() {
+            ((value << (64 - bits)) as i64) >> (64 - bits)
+        } else {
+            value as i64
+        };
+        match field.names.iter().find(|x| *x.1 == value) {
+            Some((name, _)) => name.clone(),
+            None => value.to_string(),
+        }
+    }
+
+    /// Writes a field of a record, checking the value fits its type and range.
+    fn write(&self, index: usize, record: &mut [u8], value: &str) -> Result<(), String> {
+        let field = &self.fields[index];
+        let (offset, size) = self.layout[index];
+        let value = value.trim();
+        if field.kind == FieldType::Bytes {
+            let digits = value.replace(' ', "");
+            let bytes = (0..digits.len())
+                .step_by(2)
+                .map(|i| {
+                    digits
+                        .get(i..i + 2)
+                        .and_then(|x| u8::from_str_radix(x, 16).ok())
+                })
+                .collect::<Option<Vec<_>>>()
+                .filter(|x| x.len() == size)
+                .ok_or_else(|| format!("\"{}\" isn't {} bytes in hex", value, size))?;
+            record[offset..offset + size].copy_from_slice(&bytes);
+            return Ok(());
+        }
+        let number = match field.names.get(value) {
+            Some(number) => *number,
+            None => parse_number(value).ok_or_else(|| {
+                if field.names.is_empty() {
+                    format!("\"{}\" isn't a number", value)
+                } else {
+                    format!("\"{}\" isn't a number or one of the names", value)
+                }
+            })?,
+        };
+        let bits = size as u32 * 8;
+        let (low, high) = if field.kind.signed() {
+            (-(1i64 << (bits - 1)), (1i64 << (bits - 1)) - 1)
+        } else {
+            (0, (1i64 << bits) - 1)
+        };
+        if number < low || number > high {
+            return Err(format!("{} doesn't fit in {:?}", number, field.kind).to_lowercase());
+        }
+        if let Some(min) = field.min.filter(|x| number < *x) {
+            return Err(format!("{} is below the minimum {}", number, min));
+        }
+        if let Some(max) = field.max.filter(|x| number > *x) {
+            return Err(format!("{} is above the maximum {}", number, max));
+        }
+        let bytes = &mut record[offset..offset + size];
+        for i in 0..size {
+            let byte = (number >> (i * 8)) as u8;
+            match field.endian.unwrap_or(self.endian) {
+                Endian::Little => bytes[i] = byte,
+                Endian::Big => bytes[size - 1 - i] = byte,
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Parses a decimal or "0x" prefixed hex number.
+fn parse_number(value: &str) -> Option<i64> {
+    let (negative, digits) = match value.strip_prefix('-') {
+        Some(digits) => (true, digits),
+        None => (false, value),
+    };
+    let number = match digits
+        .strip_prefix("0x")
+        .or_else(|| digits.strip_prefix("0X"))
+    {
+        Some(hex) => i64::from_str_radix(hex, 16).ok()?,
+        None => digits.parse().ok()?,
+    };
+    Some(if negative { -number } else { number })
+}
+
+/// Converts a table to CSV or JSON, with the fields of every record.
+pub fn export(schema: &Schema, data: &[u8]) -> Result<Vec<u8>, Box<dyn Error>> {
+    let (_, records, _) = schema.split(data);
+    let rows = records
+        .iter()
+        .map(|record| {
+            (0..schema.fields.len())
+                .map(|i| schema.read(i, record))
+                .collect::<Vec<_>>()
+        })
+        .collect::<Vec<_>>();
+    match schema.format {
+        TableFormat::Csv => {
+            let mut csv = schema
+                .fields
+                .iter()
+                .map(|x| translation::csv_field(&x.name))
+                .join(",");
+            csv.push_str("\r\n");
+            for row in rows {
+                csv.push_str(&row.iter().map(|x| translation::csv_field(x)).join(","));
+                csv.push_str("\r\n");
+            }
+            Ok(csv.into_bytes())
+        }
+        TableFormat::Json => {
+            let records = rows
+                .into_iter()
+                .map(|row| {
+                    let object = schema
+                        .fields
+                        .iter()
+                        .zip(row)
+                        .map(|(field, value)| {
+                            let value = match value.parse::<i64>() {
+                                Ok(number) if field.kind != FieldType::Bytes => number.into(),
+                                _ => Value::String(value),
+                            };
+                            (field.name.clone(), value)
+                        })
+                        .collect::<Map<_, _>>();
+                    Value::Object(object)
+                })
+                .collect();
+            Ok(serde_json::to_string_pretty(&Value::Array(records))?.into_bytes())
+        }
+    }
+}
+
+/// Builds a table back from its CSV or JSON, starting from the original data
+/// for the bytes not covered by the fields. Records can be added or removed,
+/// and fields left out keep their original value.
+pub fn import(
+    schema: &Schema,
+    converted: &[u8],
+    original: &[u8],
+) -> Result<Vec<u8>, Box<dyn Error>> {
+    let text = std::str::from_utf8(converted).map_err(|_| "Table isn't valid UTF-8 text")?;
+    let rows = match schema.format {
+        TableFormat::Csv => read_csv_rows(schema, text)?,
+        TableFormat::Json => read_json_rows(schema, text)?,
+    };
+    let (header, records, trailer) = schema.split(original);
+    let mut data = header.to_vec();
+    data.resize(schema.header, 0);
+    let mut errors = vec![];
+    for (i, row) in rows.iter().enumerate() {
+        let mut record = records
+            .get(i)
+            .map_or_else(|| vec![0; schema.record_size()], |x| x.to_vec());
+        for (field, value) in row.iter().enumerate() {
+            let Some(value) = value else {
+                continue;
+            };
+            if let Err(err) = schema.write(field, &mut record, value) {
+                errors.push(format!(
+                    "record {}, field \"{}\": {}",
+                    i, schema.fields[field].name, err
+                ));
+            }
+        }
+        data.extend(record);
+    }
+    if !errors.is_empty() {
+        return Err(format!("Invalid values in {}", errors.join("; ")).into());
+    }
+    data.extend_from_slice(trailer);
+    Ok(data)
+}
+
+/// Reads the value of every field in each CSV row, matching the columns by
+/// name. Empty rows are skipped.
+fn read_csv_rows(schema: &Schema, text: &str) -> Result<Vec<Vec<Option<String>>>, Box<dyn Error>> {
+    let mut rows = translation::read_csv(text.trim_start_matches('\u{feff}')).into_iter();
+    let header = rows.next().unwrap_or_default();
+    for name in &header {
+        if !name.trim().is_empty() && !schema.fields.iter().any(|x| x.name == name.trim()) {
+            return Err(format!("Unknown column \"{}\"", name.trim()).into());
+        }
+    }
+    let columns = schema
+        .fields
+        .iter()
+        .map(|field| header.iter().position(|x| x.trim() == field.name))
+        .collect::<Vec<_>>();
+    Ok(rows
+        .filter(|row| row.iter().any(|x| !x.trim().is_empty()))
+        .map(|row| {
+            columns
+                .iter()
+                .map(|column| column.map(|x| row.get(x).cloned().unwrap_or_default()))
+                .collect()
+        })
+        .collect())
+}
+
+/// Reads the value of every field in each object of a JSON array.
+fn read_json_rows(schema: &Schema, text: &str) -> Result<Vec<Vec<Option<String>>>, Box<dyn Error>> {
+    let Value::Array(records) = serde_json::from_str(text)? else {
+        return Err("Table must be a JSON array of records".into());
+    };
+    records
+        .into_iter()
+        .enumerate()
+        .map(|(i, record)| {
+            let Value::Object(object) = record else {
+                return Err(format!("Record {} isn't a JSON object", i).into());
+            };
+            if let Some(name) = object
+                .keys()
+                .find(|x| !schema.fields.iter().any(|f| f.name == **x))
+            {
+                return Err(format!("Unknown field \"{}\" in record {}", name, i).into());
+            }
+            schema
+                .fields
+                .iter()
+                .map(|field| match object.get(&field.name) {
+                    None => Ok(None),
+                    Some(Value::String(x)) => Ok(Some(x.clone())),
+                    Some(Value::Number(x)) => Ok(Some(x.to_string())),
+                    Some(_) => Err(format!(
+                        "Field \"{}\" of record {} must be a number or a string",
+                        field.name, i
+                    )
+                    .into()),
+                })
+                .collect()
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn schema(source: &str) -> Result<Schema, Box<dyn Error>> {
+        let mut schema: Schema = toml::from_str(source)?;
+        schema.resolve()?;
+        Ok(schema)
+    }
+
+    const FIELDS: &str = r#"
+        header = 2
+
+        [[field]]
+        name = "hp"
+        type = "u16"
+        max = 999
+
+        [[field]]
+        name = "attack"
+        type = "i16"
+        endian = "big"
+
+        [[field]]
+        name = "element"
+        type = "u8"
+        offset = 5
+        enum = { none = 0, fire = 1 }
+
+        [[field]]
+        name = "flags"
+        type = "bytes"
+        size = 2
+    "#;
+
+    /// Header, two records with an unused byte at offset 4, and a trailer
+    /// shorter than a record.
+    const TABLE: [u8; 23] = [
+        0xAA, 0xBB, //
+        0xE7, 0x03, 0xFF, 0xFE, 0x77, 0x01, 0x12, 0x34, //
+        0x05, 0x00, 0x00, 0x80, 0x88, 0x02, 0x00, 0xFF, //
+        0xCC, 0xDD, 0xEE, 0x11, 0x22,
+    ];
+
+    #[test]
+    fn csv_round_trips() {
+        let schema = schema(FIELDS).unwrap();
+        let csv = export(&schema, &TABLE).unwrap();
+        assert_eq!(
+            String::from_utf8(csv.clone()).unwrap(),
+            "hp,attack,element,flags\r\n999,-2,fire,1234\r\n5,128,2,00ff\r\n"
+        );
+        assert_eq!(import(&schema, &csv, &TABLE).unwrap(), TABLE);
+    }
+
+    #[test]
+    fn json_round_trips() {
+        let schema = schema(&format!("format = \"json\"\n{}", FIELDS)).unwrap();
+        let json = export(&schema, &TABLE).unwrap();
+        let records: Value = serde_json::from_slice(&json).unwrap();
+        assert_eq!(records[0]["attack"], -2);
+        assert_eq!(records[1]["flags"], "00ff");
+        assert_eq!(import(&schema, &json, &TABLE).unwrap(), TABLE);
+    }
+
+    #[test]
+    fn writes_signed_and_big_endian_values() {
+        let schema = schema(FIELDS).unwrap();
+        let csv = "hp,attack,element\r\n0x10,-32768,none\r\n";
+        let data = import(&schema, csv.as_bytes(), &TABLE).unwrap();
+        assert_eq!(
+            data[2..10],
+            [0x10, 0x00, 0x80, 0x00, 0x77, 0x00, 0x12, 0x34]
+        );
+        // The second record is dropped, the trailer is kept.
+        assert_eq!(data[10..], TABLE[18..]);
+    }
+
+    #[test]
+    fn rejects_values_out_of_range() {
+        let schema = schema(FIELDS).unwrap();
+        let csv = "hp,attack,element\r\n1000,32768,water\r\n";
+        let err = import(&schema, csv.as_bytes(), &TABLE).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "Invalid values in record 0, field \"hp\": 1000 is above the maximum 999; \
+             record 0, field \"attack\": 32768 doesn't fit in i16; \
+             record 0, field \"element\": \"water\" isn't a number or one of the names"
+        );
+    }
+
+    #[test]
+    fn rejects_invalid_layouts() {
+        let error = |source: &str| schema(source).unwrap_err().to_string();
+        assert_eq!(
+            error(
+                r#"
+                [[field]]
+                name = "a"
+                type = "u32"
+                [[field]]
+                name = "b"
+                type = "u8"
+                offset = 3
+                "#
+            ),
+            "Fields \"a\" and \"b\" overlap"
+        );
+        assert_eq!(
+            error(
+                r#"
+                size = 2
+                [[field]]
+                name = "a"
+                type = "u16"
+                [[field]]
+                name = "b"
+                type = "u8"
+                "#
+            ),
+            "Fields end at byte 3, after the record size 2"
+        );
+        assert_eq!(
+            error("[[field]]\nname = \"a\"\ntype = \"bytes\""),
+            "Field \"a\" needs a size"
+        );
+        assert_eq!(error("size = 0"), "Records can't be empty");
+    }
+}
@@ -0,0 +1,740 @@
+//! Sprite cells (NCER) and animations (NANR) as JSON, with PNG renders of
+//! every cell and animation frame. Only the JSON is converted back: objects
+//! and frames can be moved or changed, but not added or removed.
+
+use std::{collections::HashMap, error::Error};
+
+use byteorder::*;
+use serde::{Deserialize, Serialize};
+
+use crate::graphics::{self, CharData, NitroFile};
+
+/// Object sizes by shape and size bits.
+const SIZES: [[(i32, i32); 4]; 3] = [
+    [(8, 8), (16, 16), (32, 32), (64, 64)],
+    [(16, 8), (32, 8), (32, 16), (64, 32)],
+    [(8, 16), (8, 32), (16, 32), (32, 64)],
+];
+
+/// The three OAM attributes of an object.
+type Attributes = [u16; 3];
+
+/// PNG files by name.
+pub type Renders = Vec<(String, Vec<u8>)>;
+
+/// Rendered frames of each row of a sheet.
+const SHEET_COLUMNS: usize = 8;
+
+#[derive(Serialize, Deserialize)]
+struct CellsJson {
+    /// Size of every rendered frame and where the origin is in it.
+    frame: Frame,
+    cells: Vec<CellJson>,
+}
+
+#[derive(Serialize, Deserialize)]
+struct Frame {
+    width: i32,
+    height: i32,
+    origin_x: i32,
+    origin_y: i32,
+}
+
+#[derive(Serialize, Deserialize)]
+struct CellJson {
+    objects: Vec<Object>,
+}
+
+/// An OAM entry of a cell.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+struct Object {
+    x: i32,
+    y: i32,
+    width: i32,
+    height: i32,
+    tile: u16,
+    palette: u8,
+    priority: u8,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    hflip: Option<bool>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    vflip: Option<bool>,
+    /// Rotation and scaling parameter, affine objects can't be flipped.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    affine: Option<u8>,
+}
+
+impl Object {
+    fn decode(attributes: Attributes) -> Result<Self, Box<dyn Error>> {
+        let [a0, a1, a2] = attributes;
+        let shape = (a0 >> 14) as usize;
+        let (width, height) = SIZES.get(shape).ok_or("Invalid object shape")?[(a1 >> 14) as usize];
+        let affine = a0 & 0x100 != 0;
+        Ok(Self {
+            x: ((a1 & 0x1FF) as i32) << 23 >> 23,
+            y: (a0 & 0xFF) as i8 as i32,
+            width,
+            height,
+            tile: a2 & 0x3FF,
+            palette: (a2 >> 12) as u8,
+            priority: ((a2 >> 10) & 3) as u8,
+            hflip: (!affine).then_some(a1 & 0x1000 != 0),
+            vflip: (!affine).then_some(a1 & 0x2000 != 0),
+            affine: affine.then_some(((a1 >> 9) & 0x1F) as u8),
+        })
+    }
+
+    /// Attributes of the object, keeping the bits it doesn't hold from the
+    /// original ones.
+    fn encode(&self, original: Attributes) -> Result<Attributes, Box<dyn Error>> {
+        let (shape, size) = SIZES
+            .iter()
+            .enumerate()
+            .find_map(|(shape, sizes)| {
+                sizes
+                    .iter()
+                    .position(|x| *x == (self.width, self.height))
+                    .map(|size| (shape as u16, size as u16))
+            })
+            .ok_or_else(|| format!("Invalid object size {}x{}", self.width, self.height))?;
+        if !(-256..256).contains(&self.x) || !(-128..128).contains(&self.y) {
+            return Err(format!("Object position {}, {} is out of range", self.x, self.y).into());
+        }
+        if self.tile > 0x3FF || self.palette > 15 || self.priority > 3 {
+            return Err("Object tile, palette or priority is out of range".into());
+        }
+        let [a0, original_a1, _] = original;
+        let affine = match self.affine {
+            Some(param) if param <= 0x1F => true,
+            Some(_) => return Err("Affine parameter is out of range".into()),
+            None => false,
+        };
+        let a0 = (a0 & !0xC1FF) | (self.y as u16 & 0xFF) | (affine as u16) << 8 | shape << 14;
+        let mut a1 = (self.x as u16 & 0x1FF) | size << 14;
+        if let Some(param) = self.affine {
+            a1 |= (param as u16) << 9;
+        } else {
+            // Bits 9 to 11 are unused without the parameter.
+            a1 |= original_a1 & 0xE00;
+            a1 |= (self.hflip == Some(true)) as u16 * 0x1000;
+            a1 |= (self.vflip == Some(true)) as u16 * 0x2000;
+        }
+        let a2 = self.tile | (self.priority as u16) << 10 | (self.palette as u16) << 12;
+        Ok([a0, a1, a2])
+    }
+
+    /// Area drawn by the object, twice its size if it is affine and doubled.
+    fn bounds(&self, attributes: Attributes) -> (i32, i32, i32, i32) {
+        let double = self.affine.is_some() && attributes[0] & 0x200 != 0;
+        let scale = if double { 2 } else { 1 };
+        (self.x, self.y, self.width * scale, self.height * scale)
+    }
+}
+
+/// The cell bank of an NCER file, with where each object is in its section.
+struct CellBank {
+    mapping: u32,
+    /// Cells have bounds after their object count and offset.
+    bounded: bool,
+    /// Offset of each cell, with the offsets and attributes of its objects.
+    cells: Vec<(usize, Vec<(usize, Attributes)>)>,
+}
+
+impl CellBank {
+    fn read(file: &NitroFile) -> Option<Self> {
+        let section = file.section(b"KBEC")?;
+        let count = LE::read_u16(section.get(0..2)?) as usize;
+        let bounded = LE::read_u16(section.get(2..4)?) == 1;
+        let cell_data = LE::read_u32(section.get(4..8)?) as usize;
+        let mapping = LE::read_u32(section.get(8..12)?);
+        let entry_size = if bounded { 16 } else { 8 };
+        let object_data = cell_data + count * entry_size;
+        let mut cells = Vec::with_capacity(count);
+        for i in 0..count {
+            let entry = cell_data + i * entry_size;
+            let objects = LE::read_u16(section.get(entry..entry + 2)?) as usize;
+            let offset = object_data + LE::read_u32(section.get(entry + 4..entry + 8)?) as usize;
+            let objects = (0..objects)
+                .map(|j| {
+                    let offset = offset + j * 6;
+                    let data = section.get(offset..offset + 6)?;
+                    Some((offset, [0, 2, 4].map(|x| LE::read_u16(&data[x..]))))
+                })
+                .collect::<Option<Vec<_>>>()?;
+            cells.push((entry, objects));
+        }
+        Some(Self {
+            mapping,
+            bounded,
+            cells,
+        })
+    }
+
+    fn objects(&self, cell: usize) -> Result<Vec<(Object, Attributes)>, Box<dyn Error>> {
+        self.cells[cell]
+            .1
+            .iter()
+            .map(|(_, x)| Object::decode(*x).map(|object| (object, *x)))
+            .collect()
+    }
+
+    /// Area drawn by all the objects of every cell, at least one tile.
+    fn frame(&self) -> Result<Frame, Box<dyn Error>> {
+        let mut area = (0, 0, 8, 8);
+        let mut first = true;
+        for cell in 0..self.cells.len() {
+            for (object, attributes) in self.objects(cell)? {
+                let (x, y, w, h) = object.bounds(attributes);
+                if first {
+                    area = (x, y, x + w, y + h);
+                    first = false;
+                }
+                area = (
+                    area.0.min(x),
+                    area.1.min(y),
+                    area.2.max(x + w),
+                    area.3.max(y + h),
+                );
+            }
+        }
+        Ok(Frame {
+            width: area.2 - area.0,
+            height: area.3 - area.1,
+            origin_x: -area.0,
+            origin_y: -area.1,
+        })
+    }
+}
+
+pub fn export_cells(file: &NitroFile) -> Result<Vec<u8>, Box<dyn Error>> {
+    let bank = CellBank::read(file).ok_or("Invalid cell file")?;
+    let cells = (0..bank.cells.len())
+        .map(|i| {
+            Ok(CellJson {
+                objects: bank.objects(i)?.into_iter().map(|x| x.0).collect(),
+            })
+        })
+        .collect::<Result<_, Box<dyn Error>>>()?;
+    let json = CellsJson {
+        frame: bank.frame()?,
+        cells,
+    };
+    Ok(serde_json::to_string_pretty(&json)?.into_bytes())
+}
+
+pub fn import_cells(json: &str, file: &mut NitroFile) -> Result<(), Box<dyn Error>> {
+    let bank = CellBank::read(file).ok_or("Invalid cell file")?;
+    let json: CellsJson = serde_json::from_str(json)?;
+    if json.cells.len() != bank.cells.len() {
+        return Err(format!("Expecting {} cells", bank.cells.len()).into());
+    }
+    let section = file.section_mut(b"KBEC").unwrap();
+    for (i, (cell, (entry, objects))) in json.cells.iter().zip(&bank.cells).enumerate() {
+        if cell.objects.len() != objects.len() {
+            return Err(format!("Cell {} must have {} objects", i, objects.len()).into());
+        }
+        let mut changed = false;
+        for (object, (offset, original)) in cell.objects.iter().zip(objects) {
+            let attributes = object
+                .encode(*original)
+                .map_err(|e| format!("Cell {}: {}", i, e))?;
+            if attributes != *original {
+                for (j, value) in attributes.iter().enumerate() {
+                    LE::write_u16(&mut section[offset + j * 2..], *value);
+                }
+                changed = true;
+            }
+        }
+        // Bounds of cells left as they were are kept, whatever they are.
+        if bank.bounded && changed && !cell.objects.is_empty() {
+            let bounds = cell.objects.iter().map(|x| (x.x, x.y, x.width, x.height));
+            let max_x = bounds.clone().map(|x| x.0 + x.2).max().unwrap();
+            let max_y = bounds.clone().map(|x| x.1 + x.3).max().unwrap();
+            let min_x = bounds.clone().map(|x| x.0).min().unwrap();
+            let min_y = bounds.map(|x| x.1).min().unwrap();
+            for (j, value) in [max_x, max_y, min_x, min_y].into_iter().enumerate() {
+                LE::write_i16(&mut section[entry + 8 + j * 2..], value as i16);
+            }
+        }
+    }
+    Ok(())
+}
+
+#[derive(Serialize, Deserialize)]
+struct AnimationsJson {
+    animations: Vec<Sequence>,
+}
+
+#[derive(Serialize, Deserialize)]
+struct Sequence {
+    /// 1 plays forward, 2 loops, 3 plays in reverse, 4 loops in reverse.
+    mode: u32,
+    loop_start: u16,
+    frames: Vec<AnimationFrame>,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+struct AnimationFrame {
+    cell: u16,
+    /// Duration in 60ths of a second.
+    duration: u16,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    x: Option<i16>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    y: Option<i16>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    rotation: Option<u16>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    scale_x: Option<i32>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    scale_y: Option<i32>,
+}
+
+/// Offsets of the animation bank of an NANR file.
+struct AnimationBank {
+    /// Offset of each sequence, with its frame count and element type.
+    sequences: Vec<(usize, usize, u16)>,
+    frames: usize,
+    elements: usize,
+}
+
+impl AnimationBank {
+    fn read(file: &NitroFile) -> Option<Self> {
+        let section = file.section(b"KNBA")?;
+        let count = LE::read_u16(section.get(0..2)?) as usize;
+        let sequence_data = LE::read_u32(section.get(4..8)?) as usize;
+        let frames = LE::read_u32(section.get(8..12)?) as usize;
+        let elements = LE::read_u32(section.get(12..16)?) as usize;
+        let sequences = (0..count)
+            .map(|i| {
+                let offset = sequence_data + i * 16;
+                let data = section.get(offset..offset + 16)?;
+                Some((
+                    offset,
+                    LE::read_u16(data) as usize,
+                    LE::read_u16(&data[4..]),
+                ))
+            })
+            .collect::<Option<_>>()?;
+        Some(Self {
+            sequences,
+            frames,
+            elements,
+        })
+    }
+
+    /// Offsets of the frames of a sequence and of their elements.
+    fn frame_offsets(&self, section: &[u8], sequence: usize) -> Option<Vec<(usize, usize)>> {
+        let (offset, count, _) = self.sequences[sequence];
+        let first = self.frames + LE::read_u32(section.get(offset + 12..offset + 16)?) as usize;
+        (0..count)
+            .map(|i| {
+                let frame = first + i * 8;
+                let element = LE::read_u32(section.get(frame..frame + 4)?) as usize;
+                Some((frame, self.elements + element))
+            })
+            .collect()
+    }
+}
+
+/// Size of the element of each frame, by element type.
+fn element_size(element_type: u16) -> Result<usize, Box<dyn Error>> {
+    match element_type {
+        0 => Ok(2),
+        1 => Ok(16),
+        2 => Ok(8),
+        _ => Err(format!("Unknown animation element type {}", element_type).into()),
+    }
+}
+
+fn read_sequences(file: &NitroFile) -> Result<Vec<Sequence>, Box<dyn Error>> {
+    let bank = AnimationBank::read(file).ok_or("Invalid animation file")?;
+    let section = file.section(b"KNBA").unwrap();
+    let invalid = || "Invalid animation file";
+    let mut sequences = vec![];
+    for (i, (offset, _, element_type)) in bank.sequences.iter().enumerate() {
+        let size = element_size(*element_type)?;
+        let mut frames = vec![];
+        for (frame, element) in bank.frame_offsets(section, i).ok_or_else(invalid)? {
+            let duration = LE::read_u16(section.get(frame + 4..frame + 6).ok_or_else(invalid)?);
+            let data = section.get(element..element + size).ok_or_else(invalid)?;
+            let mut frame = AnimationFrame {
+                cell: LE::read_u16(data),
+                duration,
+                x: None,
+                y: None,
+                rotation: None,
+                scale_x: None,
+                scale_y: None,
+            };
+            match element_type {
+                1 => {
+                    frame.rotation = Some(LE::read_u16(&data[2..]));
+                    frame.scale_x = Some(LE::read_i32(&data[4..]));
+                    frame.scale_y = Some(LE::read_i32(&data[8..]));
+                    frame.x = Some(LE::read_i16(&data[12..]));
+                    frame.y = Some(LE::read_i16(&data[14..]));
+                }
+                2 => {
+                    frame.x = Some(LE::read_i16(&data[4..]));
+                    frame.y = Some(LE::read_i16(&data[6..]));
+                }
+                _ => {}
+            }
+            frames.push(frame);
+        }
+        sequences.push(Sequence {
+            mode: LE::read_u32(&section[offset + 8..]),
+            loop_start: LE::read_u16(&section[offset + 2..]),
+            frames,
+        });
+    }
+    Ok(sequences)
+}
+
+pub fn export_animations(file: &NitroFile) -> Result<Vec<u8>, Box<dyn Error>> {
+    let json = AnimationsJson {
+        animations: read_sequences(file)?,
+    };
+    Ok(serde_json::to_string_pretty(&json)?.into_bytes())
+}
+
+pub fn import_animations(json: &str, file: &mut NitroFile) -> Result<(), Box<dyn Error>> {
+    let bank = AnimationBank::read(file).ok_or("Invalid animation file")?;
+    let originals = read_sequences(file)?;
+    let json: AnimationsJson = serde_json::from_str(json)?;
+    if json.animations.len() != originals.len() {
+        return Err(format!("Expecting {} animations", originals.len()).into());
+    }
+    let offsets = (0..bank.sequences.len())
+        .map(|i| bank.frame_offsets(file.section(b"KNBA").unwrap(), i))
+        .collect::<Option<Vec<_>>>()
+        .ok_or("Invalid animation file")?;
+    let section = file.section_mut(b"KNBA").unwrap();
+    // Frames may share their element, which then can't differ.
+    let mut written: HashMap<usize, (usize, usize, Vec<u8>)> = HashMap::new();
+    for (i, (sequence, original)) in json.animations.iter().zip(&originals).enumerate() {
+        if sequence.frames.len() != original.frames.len() {
+            return Err(
+                format!("Animation {} must have {} frames", i, original.frames.len()).into(),
+            );
+        }
+        let (offset, _, element_type) = bank.sequences[i];
+        LE::write_u16(&mut section[offset + 2..], sequence.loop_start);
+        LE::write_u32(&mut section[offset + 8..], sequence.mode);
+        for (j, (frame, old)) in sequence.frames.iter().zip(&original.frames).enumerate() {
+            let (frame_offset, element) = offsets[i][j];
+            LE::write_u16(&mut section[frame_offset + 4..], frame.duration);
+            let size = element_size(element_type)?;
+            let mut data = section[element..element + size].to_vec();
+            LE::write_u16(&mut data, frame.cell);
+            match element_type {
+                1 => {
+                    LE::write_u16(&mut data[2..], frame.rotation.or(old.rotation).unwrap());
+                    LE::write_i32(&mut data[4..], frame.scale_x.or(old.scale_x).unwrap());
+                    LE::write_i32(&mut data[8..], frame.scale_y.or(old.scale_y).unwrap());
+                    LE::write_i16(&mut data[12..], frame.x.or(old.x).unwrap());
+                    LE::write_i16(&mut data[14..], frame.y.or(old.y).unwrap());
+                }
+                2 => {
+                    LE::write_i16(&mut data[4..], frame.x.or(old.x).unwrap());
+                    LE::write_i16(&mut data[6..], frame.y.or(old.y).unwrap());
+                }
+                _ => {}
+            }
+            if let Some((a, b, other)) = written.get(&element) {
+                if *other != data {
+                    return Err(format!(
+                        "Frame {} of animation {} shares its data with frame {} of animation {}, they must be the same",
+                        j, i, b, a
+                    )
+                    .into());
+                }
+            }
+            section[element..element + data.len()].copy_from_slice(&data);
+            written.insert(element, (i, j, data));
+        }
+    }
+    Ok(())
+}
+
+/// Pixels of character data, to draw objects with.
+struct Sprites<'a> {
+    bank: &'a CellBank,
+    data: Vec<u8>,
+    colors: Vec<[u8; 3]>,
+}
+
+impl<'a> Sprites<'a> {
+    fn new(
+        bank: &'a CellBank,
+        tiles: Option<&[u8]>,
+        palette: Option<&[u8]>,
+    ) -> Result<Self, Box<dyn Error>> {
+        let tiles = tiles.ok_or("No character data for the cells")?;
+        let tiles = NitroFile::parse(tiles).ok_or("Invalid character file")?;
+        let chars = CharData::read(&tiles).ok_or("Invalid character file")?;
+        Ok(Self {
+            bank,
+            data: tiles.section(b"RAHC").unwrap()[chars.range].to_vec(),
+            colors: graphics::palette_rgb(palette, 256)?,
+        })
+    }
+
+    /// Draws a cell with its origin at the given position, leaving color 0
+    /// transparent. The first objects are drawn over the last ones.
+    fn draw(
+        &self,
+        cell: usize,
+        image: &mut [u8],
+        width: i32,
+        origin: (i32, i32),
+    ) -> Result<(), Box<dyn Error>> {
+        let height = image.len() as i32 / width;
+        for (object, attributes) in self.bank.objects(cell)?.into_iter().rev() {
+            let bpp = if attributes[0] & 0x2000 != 0 { 8 } else { 4 };
+            let (x, y, w, h) = object.bounds(attributes);
+            // Affine objects are drawn without their transformation.
+            let (x, y) = (x + (w - object.width) / 2, y + (h - object.height) / 2);
+            let tiles_wide = object.width / 8;
+            for py in 0..object.height {
+                for px in 0..object.width {
+                    let sx = if object.hflip == Some(true) {
+                        object.width - 1 - px
+                    } else {
+                        px
+                    };
+                    let sy = if object.vflip == Some(true) {
+                        object.height - 1 - py
+                    } else {
+                        py
+                    };
+                    let tile = (sy / 8 * tiles_wide + sx / 8) as usize;
+                    let address = if self.bank.mapping >= 4 {
+                        // 2D mapping, in rows of 32 tiles of 32 bytes.
+                        (object.tile as usize
+                            + (sy / 8) as usize * 32
+                            + (sx / 8) as usize * bpp / 4)
+                            * 32
+                    } else {
+                        ((object.tile as usize) << self.bank.mapping) * 32 + tile * bpp * 8
+                    };
+                    let pixel = (sy % 8 * 8 + sx % 8) as usize;
+                    let Some(byte) = self.data.get(address + pixel * bpp / 8) else {
+                        continue;
+                    };
+                    let color = if bpp == 8 {
+                        *byte
+                    } else {
+                        (byte >> (pixel % 2 * 4)) & 0xF
+                    };
+                    let (dx, dy) = (origin.0 + x + px, origin.1 + y + py);
+                    if color == 0 || dx < 0 || dy < 0 || dx >= width || dy >= height {
+                        continue;
+                    }
+                    image[(dy * width + dx) as usize] = if bpp == 8 {
+                        color
+                    } else {
+                        object.palette * 16 + color
+                    };
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Lays out frames drawn by `draw` in rows of a sheet.
+    fn sheet(
+        &self,
+        count: usize,
+        width: i32,
+        height: i32,
+        columns: usize,
+        mut draw: impl FnMut(usize, &mut [u8]) -> Result<(), Box<dyn Error>>,
+    ) -> Result<Vec<u8>, Box<dyn Error>> {
+        let columns = columns.clamp(1, count.max(1));
+        let rows = count.div_ceil(columns).max(1);
+        let (w, h) = (width as usize, height as usize);
+        let mut sheet = vec![0; w * columns * h * rows];
+        let mut frame = vec![0; w * h];
+        for i in 0..count {
+            frame.fill(0);
+            draw(i, &mut frame)?;
+            let (fx, fy) = (i % columns * w, i / columns * h);
+            for y in 0..h {
+                let start = (fy + y) * w * columns + fx;
+                sheet[start..start + w].copy_from_slice(&frame[y * w..y * w + w]);
+            }
+        }
+        graphics::write_png(w * columns, h * rows, &sheet, &self.colors)
+    }
+}
+
+/// Renders every cell of an NCER file as "cell_XXX.png" and all of them in
+/// "sheet.png", aligned on the same origin.
+pub fn render_cells(
+    data: &[u8],
+    tiles: Option<&[u8]>,
+    palette: Option<&[u8]>,
+) -> Result<Renders, Box<dyn Error>> {
+    let file = NitroFile::parse(data).ok_or("Invalid cell file")?;
+    let bank = CellBank::read(&file).ok_or("Invalid cell file")?;
+    let sprites = Sprites::new(&bank, tiles, palette)?;
+    let frame = bank.frame()?;
+    let origin = (frame.origin_x, frame.origin_y);
+    let draw = |i: usize, image: &mut [u8]| sprites.draw(i, image, frame.width, origin);
+    let mut images = vec![];
+    for i in 0..bank.cells.len() {
+        let png = sprites.sheet(1, frame.width, frame.height, 1, |_, x| draw(i, x))?;
+        images.push((format!("cell_{:03}.png", i), png));
+    }
+    let count = bank.cells.len();
+    let sheet = sprites.sheet(count, frame.width, frame.height, SHEET_COLUMNS, draw)?;
+    images.push(("sheet.png".to_owned(), sheet));
+    Ok(images)
+}
+
+/// Renders every animation of an NANR file as "anim_XXX.png", a row of its
+/// frames drawn with the cells.
+pub fn render_animations(
+    data: &[u8],
+    cells: Option<&[u8]>,
+    tiles: Option<&[u8]>,
+    palette: Option<&[u8]>,
+) -> Result<Renders, Box<dyn Error>> {
+    let file = NitroFile::parse(data).ok_or("Invalid animation file")?;
+    let sequences = read_sequences(&file)?;
+    let cells = cells.ok_or("No cells for the animations")?;
+    let cells = NitroFile::parse(cells).ok_or("Invalid cell file")?;
+    let bank = CellBank::read(&cells).ok_or("Invalid cell file")?;
+    let sprites = Sprites::new(&bank, tiles, palette)?;
+    let frame = bank.frame()?;
+    let mut images = vec![];
+    for (i, sequence) in sequences.iter().enumerate() {
+        let offset = |x: &AnimationFrame| (x.x.unwrap_or(0) as i32, x.y.unwrap_or(0) as i32);
+        // Every frame of the animation fits, wherever it is moved to.
+        let (mut min_x, mut min_y) = (0, 0);
+        let (mut max_x, mut max_y) = (frame.width, frame.height);
+        for x in &sequence.frames {
+            let (dx, dy) = offset(x);
+            min_x = min_x.min(dx);
+            min_y = min_y.min(dy);
+            max_x = max_x.max(frame.width + dx);
+            max_y = max_y.max(frame.height + dy);
+        }
+        let png = sprites.sheet(
+            sequence.frames.len(),
+            max_x - min_x,
+            max_y - min_y,
+            sequence.frames.len(),
+            |j, image| {
+                let x = &sequence.frames[j];
+                if x.cell as usize >= bank.cells.len() {
+                    return Err(format!("Animation {} uses a missing cell {}", i, x.cell).into());
+                }
+                let (dx, dy) = offset(x);
+                let origin = (frame.origin_x + dx - min_x, frame.origin_y + dy - min_y);
+                sprites.draw(x.cell as usize, image, max_x - min_x, origin)
+            },
+        )?;
+        images.push((format!("anim_{:03}.png", i), png));
+    }
+    Ok(images)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::graphics::tests::{ncgr, nclr, nitro};
+
+    fn ncer() -> Vec<u8> {
+        let mut section = vec![2, 0, 1, 0, 24, 0, 0, 0];
+        section.resize(24, 0);
+        // Object count, attributes, object offset and bounds of each cell.
+        for (objects, offset, bounds) in [(2u16, 0u32, [8i16, 8, -8, -8]), (1, 12, [1, 2, 3, 4])] {
+            section.extend(objects.to_le_bytes());
+            section.extend([0, 0]);
+            section.extend(offset.to_le_bytes());
+            section.extend(bounds.iter().flat_map(|x| x.to_le_bytes()));
+        }
+        let objects: [[u16; 3]; 3] = [
+            [0x00F8, 0x51F8, 0x1804],
+            [0x4310, 0x8620, 0x0000],
+            [0x8020, 0x2210, 0x03FF],
+        ];
+        section.extend(objects.iter().flatten().flat_map(|x| x.to_le_bytes()));
+        nitro(b"RECN", vec![(*b"KBEC", section)])
+    }
+
+    fn nanr() -> Vec<u8> {
+        let mut section = vec![2, 0, 3, 0, 24, 0, 0, 0, 56, 0, 0, 0, 80, 0, 0, 0];
+        section.resize(24, 0);
+        // Frame count, loop start, element type, mode and first frame.
+        for (frames, loop_start, element_type, mode, first) in
+            [(2u16, 1u16, 0u16, 2u32, 0u32), (1, 0, 2, 1, 16)]
+        {
+            for value in [frames, loop_start, element_type, 0] {
+                section.extend(value.to_le_bytes());
+            }
+            section.extend(mode.to_le_bytes());
+            section.extend(first.to_le_bytes());
+        }
+        // Element offset and duration of each frame, the first two share
+        // their element.
+        for (element, duration) in [(0u32, 4u16), (0, 6), (4, 8)] {
+            section.extend(element.to_le_bytes());
+            section.extend(duration.to_le_bytes());
+            section.extend([0, 0]);
+        }
+        section.extend([1, 0, 0, 0]);
+        section.extend([1, 0, 0, 0, 0xFC, 0xFF, 5, 0]);
+        nitro(b"RNAN", vec![(*b"KNBA", section)])
+    }
+
+    fn round_trip<E, I>(data: &[u8], export: E, import: I) -> String
+    where
+        E: Fn(&NitroFile) -> Result<Vec<u8>, Box<dyn Error>>,
+        I: Fn(&str, &mut NitroFile) -> Result<(), Box<dyn Error>>,
+    {
+        let mut file = NitroFile::parse(data).unwrap();
+        let json = String::from_utf8(export(&file).unwrap()).unwrap();
+        import(&json, &mut file).unwrap();
+        assert_eq!(file.write(), data);
+        json
+    }
+
+    #[test]
+    fn cells_round_trip() {
+        let data = ncer();
+        let json = round_trip(&data, export_cells, import_cells);
+        let parsed: CellsJson = serde_json::from_str(&json).unwrap();
+        assert_eq!(parsed.cells[0].objects[0].x, -8);
+        assert_eq!(parsed.cells[0].objects[1].affine, Some(3));
+        // Moving an object updates the bounds of its cell.
+        let mut file = NitroFile::parse(&data).unwrap();
+        import_cells(&json.replacen("\"x\": -8", "\"x\": -16", 1), &mut file).unwrap();
+        let section = file.section(b"KBEC").unwrap();
+        assert_eq!(LE::read_i16(&section[24 + 12..]), -16);
+        assert_eq!(LE::read_u16(&section[56 + 2..]) & 0x1FF, 0x1F0);
+    }
+
+    #[test]
+    fn animations_round_trip() {
+        let json = round_trip(&nanr(), export_animations, import_animations);
+        let parsed: AnimationsJson = serde_json::from_str(&json).unwrap();
+        assert_eq!(parsed.animations[1].frames[0].x, Some(-4));
+        // Frames sharing their element can't be changed apart.
+        let mut file = NitroFile::parse(&nanr()).unwrap();
+        let edited = json.replacen("\"cell\": 1", "\"cell\": 2", 1);
+        assert!(import_animations(&edited, &mut file).is_err());
+    }
+
+    #[test]
+    fn renders_frames() {
+        let (cells, palette, tiles) = (ncer(), nclr(), ncgr(64));
+        let renders = render_cells(&cells, Some(&tiles), Some(&palette)).unwrap();
+        assert!(!renders.is_empty());
+        let renders = render_animations(&nanr(), Some(&cells), Some(&tiles), Some(&palette));
+        assert!(!renders.unwrap().is_empty());
+    }
+}
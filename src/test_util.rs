@@ -0,0 +1,37 @@
+//! Helpers shared by the tests.
+
+use std::path::{Path, PathBuf};
+
+use crate::temp;
+
+/// A directory in the temporary directory, removed with its content when
+/// dropped.
+pub struct TempDir(PathBuf);
+
+impl TempDir {
+    pub fn new() -> Self {
+        let dir = temp::unique_path("test");
+        std::fs::create_dir_all(&dir).expect("Can't create temporary directory");
+        Self(dir)
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.0
+    }
+
+    /// Writes a file, creating its parent directories.
+    pub fn write(&self, path: &str, data: impl AsRef<[u8]>) -> PathBuf {
+        let path = self.0.join(path);
+        if let Some(parent) = path.parent() {
+            std::fs::create_dir_all(parent).expect("Can't create directory");
+        }
+        std::fs::write(&path, data).expect("Can't write file");
+        path
+    }
+}
+
+impl Drop for TempDir {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_dir_all(&self.0);
+    }
+}
@@ -0,0 +1,644 @@
+//! Star Force message archives: a list of little endian `u16` offsets, one
+//! per script, followed by the scripts. The first offset also tells how many
+//! scripts there are. Scripts are decoded to editable text with a character
+//! table, control codes are written as `[name param ...]` and bytes missing
+//! from the table as `[$XX]`.
+//!
+//! A decoded archive looks like this, `\` escapes `[`, `\` and a `#` at the
+//! start of a line:
+//!
+//! ```text
+//! #script 0
+//! Hello[wait 30]
+//! world![end]
+//! #script 1
+//! ...
+//! ```
+
+use std::{
+    collections::HashMap,
+    error::Error,
+    fmt::Debug,
+    path::{Path, PathBuf},
+};
+
+use byteorder::*;
+
+use crate::{
+    archive,
+    batch::{self, Filters},
+    glob, incremental,
+};
+
+const SCRIPT_HEADER: &str = "#script ";
+
+#[derive(Clone, PartialEq, Eq)]
+struct Command {
+    name: String,
+    params: usize,
+}
+
+/// Character table mapping byte sequences to text and control codes, read
+/// from a `.tbl` file.
+///
+/// Each line is `XX=text`, where `XX` is one or more bytes in hex and `\n`
+/// in the text is a line break, or `XX=[name],N` for a control code followed
+/// by `N` parameter bytes. `/XX` marks an end of string code, decoded as
+/// `[end]` unless it has a text, and `*XX` a line break code. Empty lines,
+/// lines starting with `#` and table ids starting with `@` are ignored.
+#[derive(Clone, Default)]
+pub struct CharTable {
+    chars: HashMap<Vec<u8>, String>,
+    commands: HashMap<Vec<u8>, Command>,
+    /// Longest text first, so encoding picks the longest match.
+    encode: Vec<(String, Vec<u8>)>,
+    /// Length of the longest code.
+    max_code: usize,
+    hash: u64,
+}
+
+impl Debug for CharTable {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        // Only identify the table, the whole map would bloat build caches.
+        write!(f, "CharTable({:016x})", self.hash)
+    }
+}
+
+impl CharTable {
+    pub fn load(path: &Path) -> Result<Self, Box<dyn Error>> {
+        let data = std::fs::read(path)
+            .map_err(|e| format!("Can't read table {}: {}", path.display(), e))?;
+        Self::parse(&String::from_utf8_lossy(&data), incremental::fnv1a(&data))
+    }
+
+    fn parse(source: &str, hash: u64) -> Result<Self, Box<dyn Error>> {
+        let mut table = Self {
+            hash,
+            ..Default::default()
+        };
+        for (number, line) in source.lines().enumerate() {
+            let line = line.trim_start_matches('\u{feff}');
+            if line.is_empty() || line.starts_with('#') || line.starts_with('@') {
+                continue;
+            }
+            let invalid = || format!("Invalid table line {}: {}", number + 1, line);
+            let (code, value) = match line.split_once('=') {
+                Some(pair) => pair,
+                None if line.starts_with(['/', '*']) => (line, ""),
+                None => return Err(invalid().into()),
+            };
+            let (kind, code) = match code.trim() {
+                x if x.starts_with(['/', '*']) => (x.chars().next(), &x[1..]),
+                x => (None, x),
+            };
+            let code = parse_hex(code).ok_or_else(invalid)?;
+            match kind {
+                Some('/') if value.is_empty() => {
+                    table.commands.insert(
+                        code,
+                        Command {
+                            name: "end".to_owned(),
+                            params: 0,
+                        },
+                    );
+                }
+                Some('*') => {
+                    table.chars.insert(code, "\n".to_owned());
+                }
+                _ => match parse_command(value) {
+                    Some(command) => {
+                        table.commands.insert(code, command);
+                    }
+                    None => {
+                        table.chars.insert(code, value.replace("\\n", "\n"));
+                    }
+                },
+            }
+        }
+        let mut encode: Vec<_> = table
+            .chars
+            .iter()
+            .filter(|x| !x.1.is_empty())
+            .map(|(code, text)| (text.to_owned(), code.to_owned()))
+            .collect();
+        // Lowest code first among equal texts, so encoding is deterministic.
+        encode.sort_by(|a, b| {
+            b.0.chars()
+                .count()
+                .cmp(&a.0.chars().count())
+                .then(a.1.len().cmp(&b.1.len()))
+                .then(a.1.cmp(&b.1))
+        });
+        table.encode = encode;
+        table.max_code = table
+            .chars
+            .keys()
+            .chain(table.commands.keys())
+            .map(|x| x.len())
+            .max()
+            .unwrap_or(1);
+        Ok(table)
+    }
+
+    /// Every character the table decodes to.
+    pub fn characters(&self) -> impl Iterator<Item = char> + '_ {
+        self.chars.values().flat_map(|x| x.chars())
+    }
+
+    /// Decodes a script, returning the text and the offsets of the bytes
+    /// missing from the table.
+    pub fn decode(&self, script: &[u8]) -> (String, Vec<usize>) {
+        let mut text = String::new();
+        let mut unmapped = Vec::new();
+        let mut i = 0;
+        'bytes: while i < script.len() {
+            // The longest code matching wins.
+            for len in (1..=self.max_code.min(script.len() - i)).rev() {
+                let code = &script[i..i + len];
+                if let Some(command) = self.commands.get(code) {
+                    let start = i + len;
+                    if start + command.params > script.len() {
+                        continue;
+                    }
+                    text.push('[');
+                    text.push_str(&command.name);
+                    for param in &script[start..start + command.params] {
+                        text.push_str(&format!(" {}", param));
+                    }
+                    text.push(']');
+                    i = start + command.params;
+                    continue 'bytes;
+                } else if let Some(value) = self.chars.get(code).filter(|x| !x.is_empty()) {
+                    for c in value.chars() {
+                        let line_start = text.is_empty() || text.ends_with('\n');
+                        if c == '[' || c == '\\' || (c == '#' && line_start) {
+                            text.push('\\');
+                        }
+                        text.push(c);
+                    }
+                    i += len;
+                    continue 'bytes;
+                }
+            }
+            unmapped.push(i);
+            text.push_str(&format!("[${:02X}]", script[i]));
+            i += 1;
+        }
+        (text, unmapped)
+    }
+
+    /// Encodes a script written by `decode`.
+    pub fn encode(&self, text: &str) -> Result<Vec<u8>, Box<dyn Error>> {
+        let (script, missing) = self.encode_lossy(text)?;
+        match missing.first() {
+            Some(c) => Err(format!("Character {:?} isn't in the table", c).into()),
+            None => Ok(script),
+        }
+    }
+
+    /// Encodes a script, skipping and returning the characters missing from
+    /// the table instead of failing on them.
+    pub fn encode_lossy(&self, text: &str) -> Result<(Vec<u8>, Vec<char>), Box<dyn Error>> {
+        let mut script = Vec::new();
+        let mut missing = Vec::new();
+        let mut rest = text;
+        while let Some(c) = rest.chars().next() {
+            if c == '[' {
+                let end = rest.find(']').ok_or("Unclosed control code")?;
+                script.extend(self.encode_command(&rest[1..end])?);
+                rest = &rest[end + 1..];
+            } else if c == '\\' {
+                let escaped = rest[1..].chars().next().ok_or("Unfinished escape")?;
+                match self.code_of(&rest[1..1 + escaped.len_utf8()]) {
+                    Some(code) => script.extend(code),
+                    None => missing.push(escaped),
+                }
+                rest = &rest[1 + escaped.len_utf8()..];
+            } else {
+                match self.encode.iter().find(|x| rest.starts_with(x.0.as_str())) {
+                    Some((text, code)) => {
+                        script.extend(code);
+                        rest = &rest[text.len()..];
+                    }
+                    None => {
+                        missing.push(c);
+                        rest = &rest[c.len_utf8()..];
+                    }
+                }
+            }
+        }
+        Ok((script, missing))
+    }
+
+    fn code_of(&self, text: &str) -> Option<&[u8]> {
+        self.encode
+            .iter()
+            .find(|x| x.0 == text)
+            .map(|x| x.1.as_slice())
+    }
+
+    fn encode_command(&self, command: &str) -> Result<Vec<u8>, Box<dyn Error>> {
+        if let Some(byte) = command.strip_prefix('$') {
+            return Ok(vec![
+                u8::from_str_radix(byte, 16).map_err(|_| format!("Invalid byte [{}]", command))?
+            ]);
+        }
+        let mut words = command.split_whitespace();
+        let name = words.next().ok_or("Empty control code")?;
+        let (code, definition) = self
+            .commands
+            .iter()
+            .filter(|x| x.1.name == name)
+            .min_by_key(|x| x.0)
+            .ok_or_else(|| format!("Unknown control code [{}]", command))?;
+        let mut bytes = code.to_owned();
+        for param in words {
+            let value = match param.strip_prefix("0x") {
+                Some(hex) => u8::from_str_radix(hex, 16),
+                None => param.parse(),
+            };
+            bytes.push(value.map_err(|_| format!("Invalid parameter in [{}]", command))?);
+        }
+        if bytes.len() != definition.params + code.len() {
+            return Err(format!(
+                "Control code [{}] takes {} parameter{}",
+                command,
+                definition.params,
+                if definition.params == 1 { "" } else { "s" }
+            )
+            .into());
+        }
+        Ok(bytes)
+    }
+}
+
+/// Parses an even number of hex digits.
+fn parse_hex(hex: &str) -> Option<Vec<u8>> {
+    if hex.is_empty() || !hex.len().is_multiple_of(2) || !hex.is_ascii() {
+        return None;
+    }
+    (0..hex.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(&hex[i..i + 2], 16).ok())
+        .collect()
+}
+
+/// Parses a control code definition like `[name]` or `[name],N`.
+fn parse_command(value: &str) -> Option<Command> {
+    let (name, params) = value.strip_prefix('[')?.split_once(']')?;
+    let params = match params.strip_prefix(',') {
+        Some(params) => params.trim().parse().ok()?,
+        None if params.is_empty() => 0,
+        None => return None,
+    };
+    if name.is_empty() || name.starts_with('$') || name.contains(char::is_whitespace) {
+        return None;
+    }
+    Some(Command {
+        name: name.to_owned(),
+        params,
+    })
+}
+
+/// Splits a message archive into its scripts.
+pub fn read_scripts(data: &[u8]) -> Result<Vec<&[u8]>, Box<dyn Error>> {
+    if data.is_empty() {
+        return Ok(vec![]);
+    }
+    let invalid = || "Invalid message archive header";
+    let first = LE::read_u16(data.get(..2).ok_or_else(invalid)?) as usize;
+    if first == 0 || !first.is_multiple_of(2) || first > data.len() {
+        return Err(invalid().into());
+    }
+    let mut offsets: Vec<usize> = data[..first]
+        .chunks_exact(2)
+        .map(|x| LE::read_u16(x) as usize)
+        .collect();
+    offsets.push(data.len());
+    if offsets.windows(2).any(|x| x[0] > x[1]) {
+        return Err(invalid().into());
+    }
+    Ok(offsets.windows(2).map(|x| &data[x[0]..x[1]]).collect())
+}
+
+/// Builds a message archive out of scripts.
+pub fn write_scripts(scripts: &[Vec<u8>]) -> Result<Vec<u8>, Box<dyn Error>> {
+    let mut data = Vec::new();
+    let mut offset = scripts.len() * 2;
+    for script in scripts {
+        data.write_u16::<LE>(
+            offset
+                .try_into()
+                .map_err(|_| "Message archive is larger than 64KB")?,
+        )?;
+        offset += script.len();
+    }
+    for script in scripts {
+        data.extend(script);
+    }
+    Ok(data)
+}
+
+/// Decodes a whole message archive. Fails if it isn't one, or if it wouldn't
+/// encode back to the same bytes.
+pub fn decode_archive(data: &[u8], table: &CharTable) -> Result<String, Box<dyn Error>> {
+    let mut text = String::new();
+    let mut encoded = Vec::new();
+    for (i, script) in read_scripts(data)?.into_iter().enumerate() {
+        let (script, unmapped) = table.decode(script);
+        if !unmapped.is_empty() {
+            return Err(format!("Script {} has bytes missing from the table", i).into());
+        }
+        encoded.push(table.encode(&script)?);
+        text.push_str(&format!("{}{}\n{}\n", SCRIPT_HEADER, i, script));
+    }
+    if write_scripts(&encoded)? != data {
+        return Err("Message archive doesn't encode back to the same bytes".into());
+    }
+    Ok(text)
+}
+
+/// A byte missing from the character table.
+pub struct Unmapped {
+    pub script: usize,
+    /// Offset in the script, unknown for decoded scripts.
+    pub offset: Option<usize>,
+    pub byte: u8,
+}
+
+/// Finds the bytes missing from the table in a message archive, or the
+/// `[$XX]` codes left in text written by `decode_archive`.
+pub fn find_unmapped(
+    data: &[u8],
+    decoded: bool,
+    table: &CharTable,
+) -> Result<Vec<Unmapped>, Box<dyn Error>> {
+    let mut found = Vec::new();
+    if !decoded {
+        for (i, script) in read_scripts(data)?.into_iter().enumerate() {
+            for offset in table.decode(script).1 {
+                found.push(Unmapped {
+                    script: i,
+                    offset: Some(offset),
+                    byte: script[offset],
+                });
+            }
+        }
+        return Ok(found);
+    }
+    let text = std::str::from_utf8(data).map_err(|_| "Script isn't valid UTF-8 text")?;
+    for (i, script) in parse_scripts(text)?.into_iter().enumerate() {
+        let mut rest = script.as_str();
+        while let Some(c) = rest.chars().next() {
+            rest = &rest[c.len_utf8()..];
+            if c == '\\' {
+                rest = rest
+                    .get(rest.chars().next().map_or(0, |x| x.len_utf8())..)
+                    .unwrap_or("");
+            } else if c == '[' {
+                let byte = rest
+                    .strip_prefix('$')
+                    .and_then(|x| x.get(..3))
+                    .filter(|x| x.ends_with(']'))
+                    .and_then(|x| u8::from_str_radix(&x[..2], 16).ok());
+                if let Some(byte) = byte {
+                    found.push(Unmapped {
+                        script: i,
+                        offset: None,
+                        byte,
+                    });
+                }
+            }
+        }
+    }
+    Ok(found)
+}
+
+/// Bytes missing from the table found by `validate`.
+#[derive(Default)]
+pub struct Validation {
+    pub unmapped: Vec<(PathBuf, Unmapped)>,
+    /// Number of message archives and scripts checked.
+    pub checked: usize,
+    /// Files which couldn't be read as message archives or scripts.
+    pub skipped: Vec<(PathBuf, String)>,
+}
+
+/// Finds the bytes missing from the table in a file, or in a directory in
+/// the ".txt" scripts and the message archives matching the include patterns
+/// of the filters. Any binary file could pass for a message archive, so they
+/// are only checked when asked for.
+pub fn validate(
+    path: &Path,
+    filters: &Filters,
+    table: &CharTable,
+) -> Result<Validation, Box<dyn Error>> {
+    let mut files = vec![];
+    if path.is_dir() {
+        batch::walk(path, &mut |x| {
+            let relative = batch::relative_path(path, x);
+            let matches =
+                |patterns: &[String]| patterns.iter().any(|x| glob::matches(x, &relative));
+            let script = archive::content_extension(x).as_deref() == Some("txt");
+            if x.is_file() && (script || matches(&filters.include)) && !matches(&filters.exclude) {
+                files.push(x.to_owned());
+            }
+            true
+        })?;
+    } else {
+        files.push(path.to_owned());
+    }
+    let mut validation = Validation::default();
+    for file in files {
+        let decoded = archive::content_extension(&file).as_deref() == Some("txt");
+        let data = std::fs::read(&file)?;
+        match find_unmapped(&data, decoded, table) {
+            Ok(unmapped) => {
+                validation.checked += 1;
+                validation
+                    .unmapped
+                    .extend(unmapped.into_iter().map(|x| (file.clone(), x)));
+            }
+            Err(err) => validation.skipped.push((file, err.to_string())),
+        }
+    }
+    Ok(validation)
+}
+
+/// Splits text written by `decode_archive` into its scripts.
+pub fn parse_scripts(text: &str) -> Result<Vec<String>, Box<dyn Error>> {
+    let mut scripts: Vec<Vec<&str>> = Vec::new();
+    for (number, line) in text.lines().enumerate() {
+        if let Some(index) = line.strip_prefix(SCRIPT_HEADER) {
+            if index.trim().parse() != Ok(scripts.len()) {
+                return Err(
+                    format!("Line {}: expecting script {}", number + 1, scripts.len()).into(),
+                );
+            }
+            scripts.push(vec![]);
+        } else if let Some(script) = scripts.last_mut() {
+            script.push(line);
+        } else if !line.trim().is_empty() {
+            return Err(format!("Line {}: text before the first script", number + 1).into());
+        }
+    }
+    Ok(scripts.into_iter().map(|x| x.join("\n")).collect())
+}
+
+/// Encodes text written by `decode_archive` back to a message archive.
+pub fn encode_archive(text: &str, table: &CharTable) -> Result<Vec<u8>, Box<dyn Error>> {
+    let scripts = parse_scripts(text)?
+        .iter()
+        .enumerate()
+        .map(|(i, script)| {
+            table
+                .encode(script)
+                .map_err(|e| format!("Script {}: {}", i, e).into())
+        })
+        .collect::<Result<Vec<_>, Box<dyn Error>>>()?;
+    write_scripts(&scripts)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const TABLE: &str = "# comment\n@id\n00= \n0A=A\n0B=B\n5B=[\n8140=あ\n*E8\n/E4\nE5=[wait],1\nF0F1=[portrait],2\n";
+
+    fn table() -> CharTable {
+        CharTable::parse(TABLE, 0).unwrap()
+    }
+
+    #[test]
+    fn decodes_and_encodes_scripts() {
+        let table = table();
+        let script = [
+            0x0A, 0x81, 0x40, 0xE5, 30, 0xE8, 0x0B, 0xF0, 0xF1, 1, 2, 0x00, 0x5B, 0xE4,
+        ];
+        let (text, unmapped) = table.decode(&script);
+        assert_eq!(text, "Aあ[wait 30]\nB[portrait 1 2] \\[[end]");
+        assert!(unmapped.is_empty());
+        assert_eq!(table.encode(&text).unwrap(), script);
+    }
+
+    #[test]
+    fn keeps_unmapped_bytes() {
+        let table = table();
+        // 0xF0 without 0xF1 isn't a control code.
+        let (text, unmapped) = table.decode(&[0x0A, 0x99, 0xF0, 0x0B]);
+        assert_eq!(text, "A[$99][$F0]B");
+        assert_eq!(unmapped, [1, 2]);
+        assert_eq!(table.encode(&text).unwrap(), [0x0A, 0x99, 0xF0, 0x0B]);
+    }
+
+    #[test]
+    fn reports_encoding_errors() {
+        let table = table();
+        let error = |text: &str| table.encode(text).unwrap_err().to_string();
+        assert_eq!(error("AZ"), "Character 'Z' isn't in the table");
+        assert_eq!(error("[wait]"), "Control code [wait] takes 1 parameter");
+        assert_eq!(error("[wait 300]"), "Invalid parameter in [wait 300]");
+        assert_eq!(error("[color 1]"), "Unknown control code [color 1]");
+        assert_eq!(error("[$XY]"), "Invalid byte [$XY]");
+        assert_eq!(error("A[end"), "Unclosed control code");
+        assert_eq!(
+            table.encode_lossy("AZB").unwrap(),
+            (vec![0x0A, 0x0B], vec!['Z'])
+        );
+    }
+
+    #[test]
+    fn rejects_bad_table_lines() {
+        let error = |source: &str| CharTable::parse(source, 0).err().unwrap().to_string();
+        assert_eq!(error("0A=A\nZZ=B"), "Invalid table line 2: ZZ=B");
+        assert_eq!(error("ABC=A"), "Invalid table line 1: ABC=A");
+        assert_eq!(error("0A"), "Invalid table line 1: 0A");
+        // Control codes aren't made up for tables without any.
+        let (text, _) = CharTable::parse("0A=A", 0).unwrap().decode(&[0xE4]);
+        assert_eq!(text, "[$E4]");
+    }
+
+    #[test]
+    fn archives_round_trip() {
+        let table = table();
+        let data = write_scripts(&[vec![0x0A, 0xE4], vec![], vec![0x81, 0x40, 0xE4]]).unwrap();
+        assert_eq!(data[..6], [6, 0, 8, 0, 8, 0]);
+        assert_eq!(read_scripts(&data).unwrap()[2], [0x81, 0x40, 0xE4]);
+        let text = decode_archive(&data, &table).unwrap();
+        assert_eq!(text, "#script 0\nA[end]\n#script 1\n\n#script 2\nあ[end]\n");
+        assert_eq!(encode_archive(&text, &table).unwrap(), data);
+        assert!(read_scripts(&[]).unwrap().is_empty());
+        for header in [&[3, 0, 0][..], &[8, 0, 0, 0], &[4, 0, 2, 0]] {
+            assert!(read_scripts(header).is_err());
+        }
+    }
+
+    #[test]
+    fn parses_script_headers() {
+        let error = |text: &str| parse_scripts(text).unwrap_err().to_string();
+        assert_eq!(
+            error("A\n#script 0\n"),
+            "Line 1: text before the first script"
+        );
+        assert_eq!(
+            error("#script 0\nA\n#script 2\n"),
+            "Line 3: expecting script 1"
+        );
+        assert_eq!(
+            parse_scripts("\n#script 0\nA\nB\n#script 1\n").unwrap(),
+            ["A\nB", ""]
+        );
+    }
+
+    #[test]
+    fn finds_unmapped_bytes() {
+        let table = table();
+        let data = write_scripts(&[vec![0x0A], vec![0x0B, 0x99]]).unwrap();
+        let found = find_unmapped(&data, false, &table).unwrap();
+        assert_eq!(found.len(), 1);
+        assert_eq!(
+            (found[0].script, found[0].offset, found[0].byte),
+            (1, Some(1), 0x99)
+        );
+        let text = "#script 0\n\\[$12]A[$34]\n";
+        let found = find_unmapped(text.as_bytes(), true, &table).unwrap();
+        assert_eq!(found.len(), 1);
+        assert_eq!(
+            (found[0].script, found[0].offset, found[0].byte),
+            (0, None, 0x34)
+        );
+    }
+
+    #[test]
+    fn validates_scripts_and_selected_archives() {
+        let dir = crate::test_util::TempDir::new();
+        let archive = write_scripts(&[vec![0x0A, 0x99]]).unwrap();
+        dir.write("a/a_0.txt", "#script 0\nA[$34]\n");
+        dir.write("a/a_1.bin", &archive);
+        dir.write("msg/m_0.bin", &archive);
+        dir.write("msg/m_1.bin", [3, 0, 0]);
+        let filters = Filters {
+            include: vec!["msg/*.bin".to_owned()],
+            exclude: vec![],
+        };
+        let validation = validate(dir.path(), &filters, &table()).unwrap();
+        let found: Vec<_> = validation
+            .unmapped
+            .iter()
+            .map(|(path, x)| (batch::relative_path(dir.path(), path), x.byte))
+            .collect();
+        assert_eq!(
+            found,
+            [
+                ("a/a_0.txt".to_owned(), 0x34),
+                ("msg/m_0.bin".to_owned(), 0x99)
+            ]
+        );
+        assert_eq!(validation.checked, 2);
+        assert_eq!(validation.skipped.len(), 1);
+        assert!(validation.skipped[0].0.ends_with("msg/m_1.bin"));
+        // Without patterns, only the scripts are checked.
+        let validation = validate(dir.path(), &Filters::default(), &table()).unwrap();
+        assert_eq!((validation.checked, validation.skipped.len()), (1, 0));
+    }
+}
@@ -0,0 +1,583 @@
+//! Translation files holding every script of the unpacked message archives,
+//! as gettext PO or CSV. Each string is identified by "archive:entry:string",
+//! where "archive" is the path of the unpacked archive directory relative to
+//! the exported directory or project, "entry" the subfile index and "string"
+//! the script index. Translated strings replace the scripts when packing.
+
+use std::{
+    collections::{HashMap, HashSet},
+    error::Error,
+    fmt::Debug,
+    path::{Component, Path, PathBuf},
+};
+
+use itertools::Itertools;
+
+use crate::{archive, batch, incremental, text};
+
+/// A script found in an unpacked archive.
+pub struct TextEntry {
+    pub archive: String,
+    pub entry: usize,
+    pub string: usize,
+    /// The ".txt" subfile holding the script.
+    pub source: String,
+    pub text: String,
+    pub translation: String,
+}
+
+impl TextEntry {
+    pub fn key(&self) -> String {
+        key(&self.archive, self.entry, self.string)
+    }
+}
+
+pub fn key(archive: &str, entry: usize, string: usize) -> String {
+    format!("{}:{}:{}", archive, entry, string)
+}
+
+/// The archive part of the keys of an unpacked archive directory: its path
+/// relative to the root, separated by '/', or its name for the root itself.
+pub fn archive_key(root: Option<&Path>, dir: &Path) -> String {
+    let relative = root.and_then(|x| dir.strip_prefix(x).ok()).unwrap_or(dir);
+    let key = relative
+        .components()
+        .filter_map(|x| match x {
+            Component::Normal(name) => Some(name.to_string_lossy()),
+            Component::ParentDir => Some("..".into()),
+            _ => None,
+        })
+        .collect::<Vec<_>>()
+        .join("/");
+    if !key.is_empty() {
+        return key;
+    }
+    std::path::absolute(dir)
+        .ok()
+        .and_then(|x| x.file_name().map(|x| x.to_string_lossy().into_owned()))
+        .unwrap_or_default()
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Format {
+    Po,
+    Csv,
+}
+
+impl Format {
+    pub fn from_path(path: &Path) -> Option<Self> {
+        match path
+            .extension()?
+            .to_string_lossy()
+            .to_ascii_lowercase()
+            .as_str()
+        {
+            "po" | "pot" => Some(Self::Po),
+            "csv" => Some(Self::Csv),
+            _ => None,
+        }
+    }
+}
+
+/// Translated strings by key, untranslated ones are left out.
+#[derive(Clone, Default)]
+pub struct Translations {
+    strings: HashMap<String, String>,
+    /// The archive part of every key.
+    archives: HashSet<String>,
+    hash: u64,
+}
+
+impl Debug for Translations {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "Translations({:016x})", self.hash)
+    }
+}
+
+impl Translations {
+    pub fn load(path: &Path) -> Result<Self, Box<dyn Error>> {
+        let format = Format::from_path(path).ok_or("Translations must be a .po or .csv file")?;
+        let data = std::fs::read(path)
+            .map_err(|e| format!("Can't read translations {}: {}", path.display(), e))?;
+        let source = String::from_utf8(data)
+            .map_err(|_| format!("{} isn't valid UTF-8 text", path.display()))?;
+        let pairs = match format {
+            Format::Po => parse_po(&source)?,
+            Format::Csv => parse_csv(&source)?,
+        };
+        let strings: HashMap<_, _> = pairs.into_iter().filter(|x| !x.1.is_empty()).collect();
+        let archives = strings
+            .keys()
+            .filter_map(|x| Some(x.rsplitn(3, ':').nth(2)?.to_owned()))
+            .collect();
+        Ok(Self {
+            strings,
+            archives,
+            hash: incremental::fnv1a(source.as_bytes()),
+        })
+    }
+
+    /// Finds the archive of the translations for the archive with the given
+    /// key. Keys relative to another directory match when one ends with the
+    /// other, as long as a single archive of the translations does.
+    pub fn archive(&self, key: &str) -> Result<Option<&str>, Box<dyn Error>> {
+        if let Some(archive) = self.archives.get(key) {
+            return Ok(Some(archive));
+        }
+        let ends_with = |a: &str, b: &str| a.ends_with(&format!("/{}", b));
+        let mut matching = self
+            .archives
+            .iter()
+            .filter(|x| ends_with(x, key) || ends_with(key, x))
+            .sorted();
+        match (matching.next(), matching.next()) {
+            (Some(archive), None) => Ok(Some(archive)),
+            (Some(a), Some(b)) => Err(format!(
+                "Translations of \"{}\" and \"{}\" both match archive \"{}\"",
+                a, b, key
+            )
+            .into()),
+            _ => Ok(None),
+        }
+    }
+
+    /// Gets a translated string, `archive` is the archive part of its key in
+    /// the translations.
+    pub fn get(&self, archive: &str, entry: usize, string: usize) -> Option<&str> {
+        self.strings
+            .get(&key(archive, entry, string))
+            .map(|x| x.as_str())
+    }
+}
+
+/// Collects the scripts of every unpacked archive directory under the roots,
+/// with keys relative to `base`.
+pub fn collect(roots: &[PathBuf], base: &Path) -> Result<Vec<TextEntry>, Box<dyn Error>> {
+    let mut dirs = vec![];
+    for root in roots {
+        dirs.push(root.to_owned());
+        batch::walk(root, &mut |path| {
+            if path.is_dir() {
+                dirs.push(path.to_owned());
+            }
+            true
+        })?;
+    }
+    let mut entries = vec![];
+    let mut archives = HashMap::new();
+    for dir in dirs {
+        let archive = archive_key(Some(base), &dir);
+        let mut files = vec![];
+        for file in std::fs::read_dir(&dir)? {
+            let path = file?.path();
+            let name = path.file_name().unwrap_or_default().to_string_lossy();
+            match archive::parse_subfile_index(&name) {
+                Some(index)
+                    if path.is_file()
+                        && archive::content_extension(&path).as_deref() == Some("txt") =>
+                {
+                    files.push((index, path))
+                }
+                _ => {}
+            }
+        }
+        files.sort();
+        if files.is_empty() {
+            continue;
+        }
+        if let Some(other) = archives.insert(archive.clone(), dir.clone()) {
+            return Err(format!(
+                "{} and {} have the same key \"{}\"",
+                other.display(),
+                dir.display(),
+                archive
+            )
+            .into());
+        }
+        for (entry, path) in files {
+            let scripts = text::parse_scripts(&std::fs::read_to_string(&path)?)
+                .map_err(|e| format!("Can't read {}: {}", path.display(), e))?;
+            for (string, text) in scripts.into_iter().enumerate() {
+                entries.push(TextEntry {
+                    archive: archive.clone(),
+                    entry,
+                    string,
+                    source: path.display().to_string(),
+                    text,
+                    translation: String::new(),
+                });
+            }
+        }
+    }
+    Ok(entries)
+}
+
+/// Writes the entries to a PO or CSV file, keeping the translations already
+/// in the file.
+pub fn export(entries: &mut [TextEntry], path: &Path) -> Result<(), Box<dyn Error>> {
+    let format = Format::from_path(path).ok_or("Translations must be a .po or .csv file")?;
+    if path.is_file() {
+        let existing = Translations::load(path)?;
+        for entry in entries.iter_mut() {
+            let Some(archive) = existing.archive(&entry.archive)? else {
+                continue;
+            };
+            if let Some(translation) = existing.get(archive, entry.entry, entry.string) {
+                entry.translation = translation.to_owned();
+            }
+        }
+    }
+    let data = match format {
+        Format::Po => write_po(entries),
+        Format::Csv => write_csv(entries),
+    };
+    Ok(std::fs::write(path, data)?)
+}
+
+fn write_po(entries: &[TextEntry]) -> String {
+    let mut po =
+        String::from("msgid \"\"\nmsgstr \"\"\n\"Content-Type: text/plain; charset=UTF-8\\n\"\n");
+    for entry in entries {
+        po.push_str(&format!("\n#: {}\n", entry.source));
+        po.push_str(&format!("msgctxt {}\n", po_string(&entry.key())));
+        po.push_str(&format!("msgid {}\n", po_string(&entry.text)));
+        po.push_str(&format!("msgstr {}\n", po_string(&entry.translation)));
+    }
+    po
+}
+
+/// Quotes a PO string, splitting it after each line break.
+fn po_string(value: &str) -> String {
+    let quote = |x: &str| {
+        let escaped = x
+            .replace('\\', "\\\\")
+            .replace('"', "\\\"")
+            .replace('\t', "\\t")
+            .replace('\r', "\\r")
+            .replace('\n', "\\n");
+        format!("\"{}\"", escaped)
+    };
+    if value.trim_end_matches('\n').contains('\n') {
+        let lines: Vec<_> = value.split_inclusive('\n').map(quote).collect();
+        format!("\"\"\n{}", lines.join("\n"))
+    } else {
+        quote(value)
+    }
+}
+
+fn parse_po(source: &str) -> Result<Vec<(String, String)>, Box<dyn Error>> {
+    #[derive(PartialEq)]
+    enum Field {
+        None,
+        Context,
+        Id,
+        Str,
+    }
+    let mut pairs = vec![];
+    let mut field = Field::None;
+    let (mut context, mut translation) = (String::new(), String::new());
+    let mut fuzzy = false;
+    let mut finish = |context: &mut String, translation: &mut String, fuzzy: &mut bool| {
+        if !context.is_empty() && !*fuzzy {
+            pairs.push((std::mem::take(context), std::mem::take(translation)));
+        }
+        context.clear();
+        translation.clear();
+        *fuzzy = false;
+    };
+    for (number, line) in source.lines().enumerate() {
+        let line = line.trim();
+        let invalid = || format!("Invalid PO line {}: {}", number + 1, line);
+        let (next, value) = if let Some(value) = line.strip_prefix("msgctxt ") {
+            (Field::Context, value)
+        } else if let Some(value) = line.strip_prefix("msgid ") {
+            (Field::Id, value)
+        } else if let Some(value) = line.strip_prefix("msgstr ") {
+            (Field::Str, value)
+        } else if line.starts_with('"') {
+            if field == Field::None {
+                return Err(invalid().into());
+            }
+            let value = unquote_po(line).ok_or_else(invalid)?;
+            match field {
+                Field::Context => context.push_str(&value),
+                Field::Str => translation.push_str(&value),
+                _ => {}
+            }
+            continue;
+        } else {
+            // Comments and blank lines end the entry before them.
+            if field == Field::Str {
+                finish(&mut context, &mut translation, &mut fuzzy);
+                field = Field::None;
+            }
+            if line.starts_with("#,") && line.contains("fuzzy") {
+                fuzzy = true;
+            }
+            continue;
+        };
+        // A new entry starts with its context or id after a translation.
+        if field == Field::Str && next != Field::Str {
+            finish(&mut context, &mut translation, &mut fuzzy);
+        }
+        let value = unquote_po(value).ok_or_else(invalid)?;
+        match next {
+            Field::Context => context = value,
+            Field::Str => translation = value,
+            _ => {}
+        }
+        field = next;
+    }
+    finish(&mut context, &mut translation, &mut fuzzy);
+    Ok(pairs)
+}
+
+fn unquote_po(value: &str) -> Option<String> {
+    let inner = value.trim().strip_prefix('"')?.strip_suffix('"')?;
+    let mut result = String::new();
+    let mut chars = inner.chars();
+    while let Some(c) = chars.next() {
+        if c != '\\' {
+            result.push(c);
+            continue;
+        }
+        match chars.next()? {
+            'n' => result.push('\n'),
+            't' => result.push('\t'),
+            'r' => result.push('\r'),
+            c => result.push(c),
+        }
+    }
+    Some(result)
+}
+
+const CSV_HEADER: [&str; 5] = ["archive", "entry", "string", "source", "translation"];
+
+fn write_csv(entries: &[TextEntry]) -> String {
+    let mut csv = CSV_HEADER.join(",");
+    csv.push_str("\r\n");
+    for entry in entries {
+        let fields = [
+            entry.archive.clone(),
+            entry.entry.to_string(),
+            entry.string.to_string(),
+            entry.text.clone(),
+            entry.translation.clone(),
+        ];
+        csv.push_str(
+            &fields
+                .iter()
+                .map(|x| csv_field(x))
+                .collect::<Vec<_>>()
+                .join(","),
+        );
+        csv.push_str("\r\n");
+    }
+    csv
+}
+
+pub fn csv_field(value: &str) -> String {
+    if value.contains([',', '"', '\n', '\r']) {
+        format!("\"{}\"", value.replace('"', "\"\""))
+    } else {
+        value.to_owned()
+    }
+}
+
+fn parse_csv(source: &str) -> Result<Vec<(String, String)>, Box<dyn Error>> {
+    let mut rows = read_csv(source.trim_start_matches('\u{feff}')).into_iter();
+    let header = rows.next().unwrap_or_default();
+    let column = |name: &str| {
+        header
+            .iter()
+            .position(|x| x.trim().eq_ignore_ascii_case(name))
+            .ok_or_else(|| format!("Missing CSV column \"{}\"", name))
+    };
+    let [archive, entry, string, _, translation] = CSV_HEADER.map(column);
+    let (archive, entry, string, translation) = (archive?, entry?, string?, translation?);
+    let mut pairs = vec![];
+    for (number, row) in rows.enumerate() {
+        if row.iter().all(|x| x.is_empty()) {
+            continue;
+        }
+        let field = |i: usize| row.get(i).map_or("", |x| x.as_str());
+        let invalid = || format!("Invalid CSV row {}", number + 2);
+        pairs.push((
+            key(
+                field(archive),
+                field(entry).trim().parse().map_err(|_| invalid())?,
+                field(string).trim().parse().map_err(|_| invalid())?,
+            ),
+            field(translation).replace("\r\n", "\n"),
+        ));
+    }
+    Ok(pairs)
+}
+
+/// Splits CSV text into rows of fields, quoted fields may hold line breaks.
+pub fn read_csv(source: &str) -> Vec<Vec<String>> {
+    let mut rows = vec![];
+    let mut row = vec![];
+    let mut field = String::new();
+    let mut quoted = false;
+    let mut chars = source.chars().peekable();
+    while let Some(c) = chars.next() {
+        match c {
+            '"' if quoted && chars.peek() == Some(&'"') => {
+                chars.next();
+                field.push('"');
+            }
+            '"' if quoted => quoted = false,
+            '"' if field.is_empty() => quoted = true,
+            ',' if !quoted => row.push(std::mem::take(&mut field)),
+            '\r' if !quoted && chars.peek() == Some(&'\n') => {}
+            '\n' if !quoted => {
+                row.push(std::mem::take(&mut field));
+                rows.push(std::mem::take(&mut row));
+            }
+            c => field.push(c),
+        }
+    }
+    if !field.is_empty() || !row.is_empty() {
+        row.push(field);
+        rows.push(row);
+    }
+    rows
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::TempDir;
+
+    fn entry(archive: &str, string: usize, text: &str, translation: &str) -> TextEntry {
+        TextEntry {
+            archive: archive.to_owned(),
+            entry: 1,
+            string,
+            source: format!("{}/name_1.txt", archive),
+            text: text.to_owned(),
+            translation: translation.to_owned(),
+        }
+    }
+
+    fn tricky_entries() -> Vec<TextEntry> {
+        vec![
+            entry("msg/a", 0, "Hello", "Bonjour"),
+            entry("msg/a", 1, "Say \"hi\"\\[wait 30]", "Dis \"salut\"\\\t!"),
+            entry("msg/a", 2, "Two\nlines\n", "Deux\nlignes\r\nici\n"),
+            entry("msg/b", 0, "Comma, here", "Virgule, \"ici\"\r\nfin"),
+            entry("msg/b", 1, "Untranslated", ""),
+        ]
+    }
+
+    fn expected(entries: &[TextEntry]) -> Vec<(String, String)> {
+        entries
+            .iter()
+            .map(|x| (x.key(), x.translation.clone()))
+            .collect()
+    }
+
+    #[test]
+    fn po_round_trips() {
+        let entries = tricky_entries();
+        assert_eq!(parse_po(&write_po(&entries)).unwrap(), expected(&entries));
+    }
+
+    #[test]
+    fn csv_round_trips() {
+        let entries = tricky_entries();
+        let pairs = parse_csv(&write_csv(&entries)).unwrap();
+        // Line breaks are read back as "\n", like the ones of the scripts.
+        let expected = expected(&entries)
+            .into_iter()
+            .map(|(key, x)| (key, x.replace("\r\n", "\n")))
+            .collect::<Vec<_>>();
+        assert_eq!(pairs, expected);
+    }
+
+    #[test]
+    fn po_parses_multi_line_and_fuzzy_entries() {
+        let po = r#"msgid ""
+msgstr ""
+"Content-Type: text/plain; charset=UTF-8\n"
+
+#: msg/a/a_1.txt
+msgctxt "msg/a:1:0"
+msgid ""
+"First\n"
+"second"
+msgstr ""
+"Premier\n"
+"second \"deux\""
+
+#, fuzzy
+msgctxt "msg/a:1:1"
+msgid "Guess"
+msgstr "Devine"
+
+msgctxt "msg/a:1:2"
+msgid "Tab"
+msgstr "Tab\tulation\\"
+"#;
+        assert_eq!(
+            parse_po(po).unwrap(),
+            [
+                ("msg/a:1:0".into(), "Premier\nsecond \"deux\"".into()),
+                ("msg/a:1:2".into(), "Tab\tulation\\".into()),
+            ]
+        );
+        assert!(parse_po("msgstr \"unterminated\n").is_err());
+        assert!(unquote_po("\"trailing\\\"").is_none());
+    }
+
+    #[test]
+    fn csv_reads_quoted_fields_with_crlf() {
+        let csv = "archive,entry,string,source,translation\r\n\
+                   \"msg/a\",1,0,\"a, b\",\"Line\r\nbreak \"\"quoted\"\"\"\r\n\
+                   \r\n\
+                   msg/a,1,1,x,plain\r\n";
+        assert_eq!(
+            read_csv(csv)[1],
+            ["msg/a", "1", "0", "a, b", "Line\r\nbreak \"quoted\""]
+        );
+        assert_eq!(
+            parse_csv(csv).unwrap(),
+            [
+                ("msg/a:1:0".into(), "Line\nbreak \"quoted\"".into()),
+                ("msg/a:1:1".into(), "plain".into()),
+            ]
+        );
+        assert!(parse_csv("archive,entry\r\n").is_err());
+    }
+
+    #[test]
+    fn collect_keys_archives_by_relative_path() {
+        let dir = TempDir::new();
+        dir.write("in/a/mess/mess_0.txt", "#script 0\nFirst\n");
+        dir.write("in/b/mess/mess_0.txt", "#script 0\nSecond\n");
+        let root = dir.path().join("in");
+        let entries = collect(std::slice::from_ref(&root), &root).unwrap();
+        let keys = entries.iter().map(|x| x.key()).collect::<Vec<_>>();
+        assert_eq!(keys, ["a/mess:0:0", "b/mess:0:0"]);
+        let archive = root.join("a/mess");
+        assert!(collect(&[archive.clone(), archive], &root).is_err());
+    }
+
+    #[test]
+    fn translations_match_archives_by_path_suffix() {
+        let dir = TempDir::new();
+        let entries = [
+            entry("a/mess", 0, "First", "Premier"),
+            entry("b/mess", 0, "Second", "Second"),
+        ];
+        let path = dir.write("text.po", write_po(&entries));
+        let translations = Translations::load(&path).unwrap();
+        assert_eq!(translations.archive("a/mess").unwrap(), Some("a/mess"));
+        assert_eq!(translations.archive("in/b/mess").unwrap(), Some("b/mess"));
+        assert_eq!(translations.archive("c/mess").unwrap(), None);
+        assert!(translations.archive("mess").is_err());
+        assert_eq!(translations.get("a/mess", 1, 0), Some("Premier"));
+        assert_eq!(translations.get("b/mess", 1, 0), Some("Second"));
+    }
+}
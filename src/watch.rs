@@ -0,0 +1,58 @@
+//! Polls directories for changes, so archives can be repacked as soon as one
+//! of their subfiles is created, modified or removed.
+
+use std::{
+    collections::BTreeMap,
+    path::{Path, PathBuf},
+    thread,
+    time::{Duration, SystemTime},
+};
+
+use crate::batch;
+
+type Snapshot = BTreeMap<PathBuf, (u64, Option<SystemTime>)>;
+
+/// Calls `on_change` every time something under the directories changes,
+/// once the changes settled for an interval. Never returns.
+pub fn watch(dirs: &[PathBuf], interval: Duration, mut on_change: impl FnMut()) -> ! {
+    let mut last = snapshot(dirs);
+    loop {
+        thread::sleep(interval);
+        let mut current = snapshot(dirs);
+        if current == last {
+            continue;
+        }
+        // Editors often write a file in several steps, wait until it's done.
+        loop {
+            thread::sleep(interval);
+            let next = snapshot(dirs);
+            if next == current {
+                break;
+            }
+            current = next;
+        }
+        last = current;
+        on_change();
+    }
+}
+
+fn snapshot(dirs: &[PathBuf]) -> Snapshot {
+    let mut snapshot = Snapshot::new();
+    for dir in dirs {
+        add_dir(dir, &mut snapshot);
+    }
+    snapshot
+}
+
+fn add_dir(dir: &Path, snapshot: &mut Snapshot) {
+    // A directory which is being replaced may vanish for a moment, it just
+    // shows up as a change.
+    let _ = batch::walk(dir, &mut |path| {
+        if let Ok(metadata) = path.metadata() {
+            if metadata.is_file() {
+                snapshot.insert(path.to_owned(), (metadata.len(), metadata.modified().ok()));
+            }
+        }
+        true
+    });
+}